@@ -0,0 +1,33 @@
+// Loads arbitrary bytes as a ROM and runs it for a bounded number of steps,
+// errors are fine but nothing may panic. The first byte picks the machine,
+// so the quirks and the larger XO-CHIP memory are covered too
+#![no_main]
+
+use chip_8::cpu::{Cpu, Machine, MACHINES};
+use chip_8::display::HeadlessScreen;
+use libfuzzer_sys::fuzz_target;
+
+// Instructions run for each input
+const STEPS: u64 = 10_000;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((machine, rom)) = data.split_first() else {
+        return;
+    };
+    let machine = Machine::from_name(MACHINES[*machine as usize % MACHINES.len()]).unwrap();
+
+    let mut cpu = Cpu::new_for(machine);
+    cpu.set_seed(0);
+    if cpu.load_rom(rom).is_err() {
+        return;
+    }
+
+    // Without the display wait nothing stops the run before the step limit
+    // but an error, a halt or a FX0A waiting for a key
+    let mut quirks = cpu.quirks();
+    quirks.display_wait = false;
+    cpu.set_quirks(quirks);
+
+    let mut screen = HeadlessScreen::new();
+    let _ = cpu.run_steps(STEPS, Some(&mut screen));
+});
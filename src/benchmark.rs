@@ -0,0 +1,96 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::cpu::{Cpu, CpuError, Quirks};
+use crate::display::HeadlessScreen;
+
+// Timings of a benchmark run
+#[derive(Debug, Clone, Copy)]
+pub struct BenchmarkReport {
+    pub steps: u64,
+    pub total: Duration,
+    // Fastest and slowest single step
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl BenchmarkReport {
+    // Returns the instructions executed per second
+    pub fn ips(&self) -> f64 {
+        self.steps as f64 / self.total.as_secs_f64()
+    }
+
+    // Returns the average time of a step
+    pub fn average(&self) -> Duration {
+        Duration::from_secs_f64(self.total.as_secs_f64() / self.steps.max(1) as f64)
+    }
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} steps in {:.3?} ({:.0} IPS), step min {:?} max {:?} avg {:?}",
+            self.steps, self.total, self.ips(), self.min, self.max, self.average())
+    }
+}
+
+// Runs steps instructions of the loaded ROM as fast as possible on a
+// HeadlessScreen. The CPU doesn't halt or wait for the display during the
+// run so every step executes an instruction
+pub fn run_benchmark(cpu: &mut Cpu, steps: u64) -> Result<BenchmarkReport, CpuError> {
+    cpu.set_halt_on_loop(false);
+    cpu.set_quirks(Quirks { display_wait: false, ..cpu.quirks() });
+
+    let mut screen = HeadlessScreen::new();
+    let mut min = Duration::MAX;
+    let mut max = Duration::ZERO;
+
+    let start = Instant::now();
+    for _ in 0..steps {
+        let step_start = Instant::now();
+        cpu.step(Some(&mut screen))?;
+        let step_time = step_start.elapsed();
+
+        min = min.min(step_time);
+        max = max.max(step_time);
+    }
+    let total = start.elapsed();
+
+    Ok(BenchmarkReport {
+        steps,
+        total,
+        min: min.min(max),
+        max,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_benchmark, BenchmarkReport};
+    use crate::cpu::Cpu;
+    use std::time::Duration;
+
+    #[test]
+    fn tiny_rom() {
+        // Draw the 0 glyph and jump to itself
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06]).unwrap();
+
+        let report = run_benchmark(&mut cpu, 1000).unwrap();
+        assert_eq!(report.steps, 1000);
+        assert_eq!(cpu.instructions_executed(), 1000);
+        assert!(report.min <= report.average() && report.average() <= report.max);
+        assert!(report.to_string().starts_with("1000 steps in "));
+    }
+
+    #[test]
+    fn average_of_many_steps() {
+        // More steps than fit in a u32
+        let report = BenchmarkReport {
+            steps: 1 << 33,
+            total: Duration::from_secs(1 << 33),
+            min: Duration::ZERO,
+            max: Duration::from_secs(2),
+        };
+        assert_eq!(report.average(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::cpu::{Cpu, CpuError};
+use crate::disasm::disassemble;
+use crate::display::HeadlessScreen;
+
+// First difference between two CPUs run in lockstep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // Number of the step after which the CPUs differ, from 1
+    pub step: u64,
+    // Address and opcode executed by each CPU at that step
+    pub a: (u16, u16),
+    pub b: (u16, u16),
+    // What differs, the registers or the display
+    pub what: &'static str,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The {} diverge after step {}: A ran {:04X} {:04X} ({}), B ran {:04X} {:04X} ({})",
+            self.what, self.step,
+            self.a.0, self.a.1, disassemble(self.a.1),
+            self.b.0, self.b.1, disassemble(self.b.1))
+    }
+}
+
+// Runs up to steps instructions on a and b in lockstep, each on its own
+// HeadlessScreen with the timers ticking at ips instructions per second, and
+// returns the first step after which their registers or displays differ.
+// Stops early once both are halted
+pub fn compare(a: &mut Cpu, b: &mut Cpu, steps: u64, ips: u32) -> Result<Option<Divergence>, CpuError> {
+    let mut screen_a = HeadlessScreen::new();
+    let mut screen_b = HeadlessScreen::new();
+
+    // Each run_for executes a single instruction
+    let instruction = Duration::from_secs(1) / ips.max(1);
+    a.set_ips(ips);
+    b.set_ips(ips);
+
+    for step in 1..=steps {
+        if a.is_halted() && b.is_halted() {
+            break;
+        }
+
+        let (pc_a, pc_b) = (a.pc(), b.pc());
+        let opcode_a = u16::from_be_bytes([a.read_memory(pc_a), a.read_memory(pc_a.wrapping_add(1))]);
+        let opcode_b = u16::from_be_bytes([b.read_memory(pc_b), b.read_memory(pc_b.wrapping_add(1))]);
+
+        a.run_for(instruction, Some(&mut screen_a))?;
+        b.run_for(instruction, Some(&mut screen_b))?;
+
+        let what = if a.registers() != b.registers() {
+            "registers"
+        } else if screen_a.framebuffer() != screen_b.framebuffer() {
+            "displays"
+        } else {
+            continue;
+        };
+
+        return Ok(Some(Divergence {
+            step,
+            a: (pc_a, opcode_a),
+            b: (pc_b, opcode_b),
+            what,
+        }));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::compare;
+    use crate::cpu::Cpu;
+
+    fn cpu(rom: &[u8]) -> Cpu {
+        let mut cpu = Cpu::with_seed(1);
+        cpu.load_rom(rom).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn identical_roms() {
+        let rom = [0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0xC1, 0xFF, 0x12, 0x08];
+        let divergence = compare(&mut cpu(&rom), &mut cpu(&rom), 100, 500).unwrap();
+        assert_eq!(divergence, None);
+    }
+
+    #[test]
+    fn diverging_roms() {
+        // Same registers, different sprites
+        let a = [0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+        let b = [0x60, 0x05, 0xF0, 0x29, 0xD0, 0x04, 0x12, 0x06];
+        let divergence = compare(&mut cpu(&a), &mut cpu(&b), 100, 500).unwrap().unwrap();
+        assert_eq!(divergence.step, 3);
+        assert_eq!(divergence.a, (0x204, 0xD005));
+        assert_eq!(divergence.b, (0x204, 0xD004));
+        assert_eq!(divergence.what, "displays");
+
+        let b = [0x60, 0x06, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+        let divergence = compare(&mut cpu(&a), &mut cpu(&b), 100, 500).unwrap().unwrap();
+        assert_eq!(divergence.step, 1);
+        assert_eq!(divergence.what, "registers");
+    }
+}
@@ -1,18 +1,418 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+use core::fmt;
+use core::ops::Range;
+use core::time::Duration;
+#[cfg(feature = "std")]
 use std::fs::File;
-use std::io::{self, Read};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
 use log::{info, warn, trace};
-use crate::screen::Screen;
-use rand::Rng;
-use std::time::{Duration, Instant};
+use crate::cycles::opcode_cycles;
+#[cfg(feature = "std")]
+use crate::disasm::disassemble;
+use crate::disasm::disassemble_rom;
+use crate::display::{Display, HIRES_HEIGHT, HIRES_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::opcodes::OpcodeStats;
+#[cfg(feature = "std")]
+use crate::gzip;
+use crate::sha1::sha1_hex;
+#[cfg(feature = "std")]
+use crate::timing::SystemClock;
+use crate::timing::{Clock, StepAccumulator};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 // Memory address where CHIP-8 programs usually start
-const START_PGM: u16 = 0x200;
-
-// Memory address where the fontset starts
-const START_FONT: u16 = 0x50;
+pub const START_PGM: u16 = 0x200;
+
+// Memory address where the fontset usually starts
+pub const START_FONT: u16 = 0x50;
+
+// Size in bytes of the glyph of a digit of the fontset, and of the fontset
+pub const GLYPH_SIZE: u16 = 5;
+pub const FONT_SIZE: usize = 16 * GLYPH_SIZE as usize;
+
+// Glyphs of the hexadecimal digits 0 to F drawn by FX29, 5 bytes each
+pub const FONTSET: [u8; FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
 
 // Size of the CHIP-8 RAM in bytes
-const RAM_SIZE: usize = 4096;
+pub const RAM_SIZE: usize = 4096;
+
+// Size of the XO-CHIP RAM in bytes, all that 16 bit addresses reach
+pub const XOCHIP_RAM_SIZE: usize = 0x10000;
+
+// XO-CHIP pitch at which the audio pattern plays at 4000 bits per second
+pub const DEFAULT_PITCH: u8 = 64;
+
+// Instructions executed per second by run_for unless changed with set_ips
+pub const DEFAULT_IPS: u32 = 500;
+
+// Levels of subroutine calls the stack holds unless changed with
+// set_stack_depth, as many as SUPER-CHIP
+pub const DEFAULT_STACK_DEPTH: u16 = 16;
+
+// Frequency of the delay and sound timers in Hz
+const TIMER_HZ: u32 = 60;
+
+// Number of trace lines written between flushes of the trace
+#[cfg(feature = "std")]
+const TRACE_FLUSH_INTERVAL: u64 = 256;
+
+// Number of instructions run_for executes between looks at the clock for
+// the frame budget
+const BUDGET_CHECK_STEPS: u32 = 64;
+
+// Errors returned by the CHIP-8 CPU
+#[derive(Debug)]
+pub enum CpuError {
+    // The ROM does not fit in the memory after the program start address
+    RomTooLarge { size: usize, max: usize },
+    // The ROM can't be a valid CHIP-8 program
+    InvalidRom(&'static str),
+    // An access to an address outside of the RAM
+    MemoryOutOfBounds(usize),
+    // A RAM size not between RAM_SIZE and XOCHIP_RAM_SIZE
+    InvalidRamSize(usize),
+    // 00EE was executed without a subroutine to return from
+    StackUnderflow,
+    // 2NNN was executed with the stack full, its depth
+    StackOverflow(u16),
+    // A stack depth of 0 or not fitting below the fontset
+    InvalidStackDepth(u16),
+    // A start address over the stack or the fontset, or past the RAM
+    InvalidStartAddress(u16),
+    // A fontset not of FONT_SIZE bytes, its length
+    InvalidFont(usize),
+    // The ROM file could not be read
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    // An opcode drawing or reading the keypad was executed without a display
+    DisplayRequired(u16),
+    // A 0NNN call of machine code in strict mode, its opcode
+    UnsupportedSys(u16),
+    // An opcode that is not an instruction in strict mode
+    UnknownOpcode(u16),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::RomTooLarge { size, max } =>
+                write!(f, "ROM is too large ({} bytes, at most {} fit in memory)", size, max),
+            CpuError::InvalidRom(reason) => write!(f, "Invalid ROM: {}", reason),
+            CpuError::MemoryOutOfBounds(addr) =>
+                write!(f, "Memory address 0x{:x} is out of bounds", addr),
+            CpuError::StackUnderflow => write!(f, "Returned from a subroutine with an empty stack"),
+            CpuError::StackOverflow(depth) =>
+                write!(f, "Called a subroutine with the {} levels of the stack full", depth),
+            CpuError::InvalidStackDepth(depth) =>
+                write!(f, "Invalid stack depth of {} levels, the stack must fit below the fontset", depth),
+            CpuError::InvalidStartAddress(addr) =>
+                write!(f, "Invalid start address 0x{:03x}, programs must start after the stack and the fontset, inside the RAM", addr),
+            CpuError::InvalidFont(len) =>
+                write!(f, "Invalid fontset of {} bytes, it must have {}", len, FONT_SIZE),
+            CpuError::InvalidRamSize(size) =>
+                write!(f, "Invalid RAM size of {} bytes, it must be between {} and {}", size, RAM_SIZE, XOCHIP_RAM_SIZE),
+            #[cfg(feature = "std")]
+            CpuError::Io(e) => write!(f, "Could not read the ROM: {}", e),
+            CpuError::DisplayRequired(opcode) =>
+                write!(f, "Operation 0x{:04x} needs a display", opcode),
+            CpuError::UnsupportedSys(opcode) =>
+                write!(f, "Operation 0x{:04x} calls machine code, which is not supported", opcode),
+            CpuError::UnknownOpcode(opcode) =>
+                write!(f, "Operation 0x{:04x} is not implemented", opcode),
+        }
+    }
+}
+
+impl core::error::Error for CpuError {}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for CpuError {
+    fn from(e: io::Error) -> CpuError {
+        CpuError::Io(e)
+    }
+}
+
+// A write by an instruction to a watched address of the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    // Address of the instruction
+    pub pc: u16,
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for MemoryWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:03X} wrote 0x{:02X} at 0x{:03X} (was 0x{:02X})", self.pc, self.new, self.addr, self.old)
+    }
+}
+
+// What happened during a step, or during the steps of run_for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StepOutcome {
+    // The opcode executed (the last one for run_for), None when the CPU is
+    // halted or waits for the display
+    pub opcode: Option<u16>,
+    // Whether the display was changed
+    pub drawn: bool,
+    // Whether the sound started or stopped, i.e. the sound timer went from
+    // or to 0
+    pub sound_changed: bool,
+    // Whether the CPU is halted afterwards
+    pub halted: bool,
+}
+
+// Called by the CPU after the instructions that draw, see Cpu::on_frame
+pub type FrameCallback = Box<dyn FnMut(&Cpu)>;
+
+// Copy of the CHIP-8 registers and timers at a given time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSnapshot {
+    pub pc: u16,
+    pub sp: u16,
+    pub index: u16,
+    pub v_reg: [u8; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+// Behaviours that differ between CHIP-8 interpreters, some ROMs only work
+// correctly with the ones of the platform they were written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY1, 8XY2 and 8XY3 reset VF to 0
+    pub vf_reset: bool,
+    // 8XY6 and 8XYE shift VX in place instead of storing VY shifted in VX
+    pub shift: bool,
+    // FX55 and FX65 leave I pointing after the last register
+    pub memory_increment: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump: bool,
+    // Sprites going past the edge of the screen wrap around instead of being
+    // clipped
+    pub wrap: bool,
+    // DXYN waits for the vertical blank like the COSMAC VIP, so at most one
+    // sprite is drawn every 60Hz tick
+    pub display_wait: bool,
+    // Memory addresses past the end of the RAM wrap around to its start
+    // instead of being out of bounds
+    pub wrap_memory: bool,
+    // Without the wrap quirk, the set pixels of a sprite clipped at the edge
+    // of the screen set VF as if they collided, instead of being ignored
+    pub clip_collision: bool,
+    // FX1E sets VF to 1 when I goes past 0xFFF and to 0 otherwise, like the
+    // Amiga interpreter
+    pub index_overflow_sets_vf: bool,
+}
+
+// Names of the quirks presets accepted by Quirks::preset
+pub const QUIRKS_PRESETS: [&str; 3] = ["chip8", "schip", "xochip"];
+
+impl Default for Quirks {
+    // The original CHIP-8 behaviours, without the display wait
+    fn default() -> Quirks {
+        Quirks {
+            vf_reset: true,
+            shift: false,
+            memory_increment: true,
+            jump: false,
+            wrap: false,
+            display_wait: false,
+            wrap_memory: false,
+            clip_collision: false,
+            index_overflow_sets_vf: false,
+        }
+    }
+}
+
+impl Quirks {
+    // The COSMAC VIP CHIP-8 interpreter
+    pub const CHIP8: Quirks = Quirks {
+        vf_reset: true,
+        shift: false,
+        memory_increment: true,
+        jump: false,
+        wrap: false,
+        display_wait: true,
+        wrap_memory: false,
+        clip_collision: false,
+        index_overflow_sets_vf: false,
+    };
+
+    // The SUPER-CHIP 1.1 interpreter of the HP48 calculators
+    pub const SCHIP: Quirks = Quirks {
+        vf_reset: false,
+        shift: true,
+        memory_increment: false,
+        jump: true,
+        wrap: false,
+        display_wait: false,
+        wrap_memory: false,
+        clip_collision: false,
+        index_overflow_sets_vf: false,
+    };
+
+    // The XO-CHIP extension used by Octo
+    pub const XOCHIP: Quirks = Quirks {
+        vf_reset: false,
+        shift: false,
+        memory_increment: true,
+        jump: false,
+        wrap: true,
+        display_wait: false,
+        wrap_memory: false,
+        clip_collision: false,
+        index_overflow_sets_vf: false,
+    };
+
+    // Returns the quirks of the platform named name, one of QUIRKS_PRESETS
+    pub fn preset(name: &str) -> Option<Quirks> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" => Some(Quirks::CHIP8),
+            "schip" => Some(Quirks::SCHIP),
+            "xochip" => Some(Quirks::XOCHIP),
+            _ => None,
+        }
+    }
+}
+
+// Names of the machines accepted by Machine::from_name
+pub const MACHINES: [&str; 4] = ["vip", "eti660", "schip", "xochip"];
+
+// Computers running CHIP-8, with the memory layout and the quirks of their
+// interpreter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Machine {
+    CosmacVip,
+    // Programs start at 0x600 on the ETI-660
+    Eti660,
+    // SUPER-CHIP on the HP48 calculators
+    SuperChip,
+    // The XO-CHIP of Octo, with 64KB of RAM
+    XoChip,
+}
+
+impl Machine {
+    // Returns the machine named name, one of MACHINES
+    pub fn from_name(name: &str) -> Option<Machine> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" => Some(Machine::CosmacVip),
+            "eti660" => Some(Machine::Eti660),
+            "schip" => Some(Machine::SuperChip),
+            "xochip" => Some(Machine::XoChip),
+            _ => None,
+        }
+    }
+
+    // Address the programs are loaded at and start from
+    pub fn start_address(self) -> u16 {
+        match self {
+            Machine::CosmacVip | Machine::SuperChip | Machine::XoChip => START_PGM,
+            Machine::Eti660 => 0x600,
+        }
+    }
+
+    // Address of the fontset, below the programs and above the stack at
+    // 0x000. Only programs reading the fontset without FX29 depend on it
+    pub fn font_address(self) -> u16 {
+        match self {
+            Machine::CosmacVip | Machine::SuperChip | Machine::XoChip => START_FONT,
+            Machine::Eti660 => 0x100,
+        }
+    }
+
+    // Size of the RAM in bytes
+    pub fn ram_size(self) -> usize {
+        match self {
+            Machine::XoChip => XOCHIP_RAM_SIZE,
+            _ => RAM_SIZE,
+        }
+    }
+
+    // Quirks of the interpreter of the machine
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Machine::CosmacVip | Machine::Eti660 => Quirks::CHIP8,
+            Machine::SuperChip => Quirks::SCHIP,
+            Machine::XoChip => Quirks::XOCHIP,
+        }
+    }
+
+    // Levels of subroutine calls the stack of the interpreter holds
+    pub fn stack_depth(self) -> u16 {
+        match self {
+            Machine::CosmacVip => 12,
+            _ => DEFAULT_STACK_DEPTH,
+        }
+    }
+}
+
+// CHIP-8 variant a ROM was written for, guessed by Cpu::detect_variant from
+// the instructions of the extensions it contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedVariant {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl DetectedVariant {
+    // Returns the quirks preset of the variant, one of QUIRKS_PRESETS
+    pub fn preset(self) -> &'static str {
+        match self {
+            DetectedVariant::Chip8 => "chip8",
+            DetectedVariant::SuperChip => "schip",
+            DetectedVariant::XoChip => "xochip",
+        }
+    }
+}
+
+// Whether the opcode is one of the SUPER-CHIP instructions: the scrolls,
+// exit, the resolution switches, the large font and the flag registers
+fn is_schip_opcode(opcode: u16) -> bool {
+    matches!(opcode, 0x00C1..=0x00CF | 0x00FB..=0x00FF)
+        || opcode & 0xF000 == 0xF000 && matches!(opcode & 0x00FF, 0x30 | 0x75 | 0x85)
+}
+
+// Whether the opcode is one of the XO-CHIP instructions: the long index
+// load, the audio pattern and pitch and the register ranges
+fn is_xochip_opcode(opcode: u16) -> bool {
+    matches!(opcode, 0xF000 | 0xF002)
+        || opcode & 0xF0FF == 0xF03A
+        || opcode & 0xF000 == 0x5000 && matches!(opcode & 0x000F, 2 | 3)
+}
+
+// The registers from x to y of the XO-CHIP register ranges, in descending
+// order when x > y
+fn register_range(x: u8, y: u8) -> impl Iterator<Item = u8> {
+    let len = x.abs_diff(y);
+    (0..=len).map(move |i| if x <= y { x + i } else { x - i })
+}
 
 // Represents the state of the CHIP-8 CPU
 pub struct Cpu {
@@ -25,16 +425,109 @@ pub struct Cpu {
     delay_timer: u8,
     sound_timer: u8,
 
-    ram: [u8; RAM_SIZE],
+    ram: Vec<u8>,
     
-    time: Instant,
+    // Clock of update_timers and the time of its last update, there is no
+    // clock by default without std
+    clock: Option<Box<dyn Clock>>,
+    time: Duration,
 
-    last_key: Option<u8>,
+    // Address of the FX0A being executed and the key it waits to be released
+    last_key: Option<(u16, u8)>,
 
     has_drawn: bool,
+
+    start_pgm: u16,
+    start_font: u16,
+    // Levels of the stack at 0x000, two bytes each
+    stack_depth: u16,
+    // Glyphs loaded at start_font, FONTSET unless changed with set_font
+    font: [u8; FONT_SIZE],
+
+    // The ROM loaded, kept to restore the memory on reset
+    rom: Vec<u8>,
+    rom_sha1: String,
+
+    instructions_executed: u64,
+
+    // Machine cycles of the executed opcodes, counted when cycle_timing is
+    // enabled
+    cycles: u64,
+    cycle_timing: bool,
+
+    // Executions of each opcode, counted when enabled with set_opcode_stats
+    opcode_stats: Option<OpcodeStats>,
+
+    #[cfg(feature = "std")]
+    trace: Option<Box<dyn Write>>,
+    #[cfg(feature = "std")]
+    trace_lines: u64,
+
+    // Terse log of the opcodes and the number of lines left to write to it
+    #[cfg(feature = "std")]
+    opcode_log: Option<Box<dyn Write>>,
+    #[cfg(feature = "std")]
+    opcode_log_left: u64,
+
+    // Seeded generator for CXNN, thread_rng is used when there is no seed
+    // (without std, a generator seeded with 0)
+    seed: Option<u64>,
+    rng: Option<StdRng>,
+
+    // Set when the program jumps to itself, the usual way to end a program
+    halted: bool,
+    halt_on_loop: bool,
+
+    // Fail on the instructions that can't be emulated and the unknown ones
+    // instead of skipping them, and whether a skipped 0NNN call was already
+    // logged
+    strict: bool,
+    sys_logged: bool,
+
+    quirks: Quirks,
+
+    // Set by DXYN with the display wait quirk until the next 60Hz tick
+    waiting_display: bool,
+
+    // Instructions and timer ticks due for the time given to run_for, the
+    // ticks are also used by update_timers
+    steps: StepAccumulator,
+    ticks: StepAccumulator,
+    // Time on the clock after which run_for leaves the rest of its
+    // instructions to the next call, None to always execute them all
+    frame_budget: Option<Duration>,
+
+    // Stop run_for and run_steps from executing instructions, or the timers
+    // from counting down, to debug them apart from each other
+    cpu_paused: bool,
+    timers_paused: bool,
+
+    // Bytes written back to the memory after every step
+    cheats: Vec<(u16, u8)>,
+
+    // Addresses whose writes are logged and passed to watch_callback
+    watches: BTreeSet<u16>,
+    watch_callback: Option<Box<dyn FnMut(MemoryWrite)>>,
+
+    // Called after every instruction that draws
+    frame_callback: Option<FrameCallback>,
+
+    // In the SUPER-CHIP 128x64 mode of 00FF, until 00FE
+    hires: bool,
+
+    // XO-CHIP 1-bit audio samples loaded by F002, played in a loop instead
+    // of the beep while the sound timer is active, at the rate set by FX3A
+    pattern_buffer: Option<[u8; 16]>,
+    pitch: u8,
 }
 
 
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
 impl Cpu {
 
     // Creates and initializes a new CHIP-8 CPU instance with default values
@@ -46,176 +539,1049 @@ impl Cpu {
             v_reg: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
-            ram: [0; 4096],
-            time: Instant::now(),
+            ram: vec![0; RAM_SIZE],
+            #[cfg(feature = "std")]
+            clock: Some(Box::new(SystemClock::new())),
+            #[cfg(not(feature = "std"))]
+            clock: None,
+            time: Duration::ZERO,
             last_key: None,
             has_drawn: false,
+            start_pgm: START_PGM,
+            start_font: START_FONT,
+            stack_depth: DEFAULT_STACK_DEPTH,
+            font: FONTSET,
+            rom: vec![],
+            rom_sha1: String::new(),
+            instructions_executed: 0,
+            cycles: 0,
+            cycle_timing: false,
+            opcode_stats: None,
+            #[cfg(feature = "std")]
+            trace: None,
+            #[cfg(feature = "std")]
+            trace_lines: 0,
+            #[cfg(feature = "std")]
+            opcode_log: None,
+            #[cfg(feature = "std")]
+            opcode_log_left: 0,
+            seed: None,
+            rng: None,
+            halted: false,
+            halt_on_loop: true,
+            strict: false,
+            sys_logged: false,
+            quirks: Quirks::default(),
+            waiting_display: false,
+            steps: StepAccumulator::new(DEFAULT_IPS),
+            ticks: StepAccumulator::new(TIMER_HZ),
+            frame_budget: None,
+            cpu_paused: false,
+            timers_paused: false,
+            cheats: vec![],
+            watches: BTreeSet::new(),
+            watch_callback: None,
+            frame_callback: None,
+            hires: false,
+            pattern_buffer: None,
+            pitch: DEFAULT_PITCH,
+        }
+    }
+
+    // Creates a new CHIP-8 CPU loading and running programs from start_pgm
+    // (e.g. 0x600 for ETI-660 programs)
+    pub fn with_start_address(start_pgm: u16) -> Result<Cpu, CpuError> {
+        let mut cpu = Cpu::new();
+        cpu.set_start_address(start_pgm)?;
+        Ok(cpu)
+    }
+
+    // Creates a new CHIP-8 CPU with the memory layout and quirks of machine
+    pub fn new_for(machine: Machine) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.start_pgm = machine.start_address();
+        cpu.start_font = machine.font_address();
+        cpu.stack_depth = machine.stack_depth();
+        cpu.quirks = machine.quirks();
+        cpu.ram = vec![0; machine.ram_size()];
+        cpu.reset();
+        cpu
+    }
+
+    // Creates a new CHIP-8 CPU whose random numbers are generated from seed,
+    // so that runs of the same ROM with the same input are reproducible
+    pub fn with_seed(seed: u64) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_seed(seed);
+        cpu
+    }
+
+    // Restarts the random numbers from seed
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.rng = Some(StdRng::seed_from_u64(seed));
+    }
+
+    // Changes the size of the RAM, between RAM_SIZE and XOCHIP_RAM_SIZE
+    // bytes, clearing it like reset
+    pub fn set_ram_size(&mut self, size: usize) -> Result<(), CpuError> {
+        if !(RAM_SIZE..=XOCHIP_RAM_SIZE).contains(&size) {
+            return Err(CpuError::InvalidRamSize(size));
+        }
+        if self.start_pgm as usize >= size {
+            return Err(CpuError::InvalidStartAddress(self.start_pgm));
+        }
+        let max = size - self.start_pgm as usize;
+        if self.rom.len() > max {
+            return Err(CpuError::RomTooLarge { size: self.rom.len(), max });
+        }
+        self.ram = vec![0; size];
+        self.cheats.retain(|(addr, _)| (*addr as usize) < size);
+        self.reset();
+        Ok(())
+    }
+
+    // Changes the address programs are loaded and run from, clearing the
+    // memory like reset. The fontset and the stack below it must end before
+    // it, so that neither overwrites the program
+    pub fn set_start_address(&mut self, start_pgm: u16) -> Result<(), CpuError> {
+        let reserved = (self.start_font as usize + FONT_SIZE).max(2 * self.stack_depth as usize);
+        let start = start_pgm as usize;
+        if start < reserved || start >= self.ram.len() {
+            return Err(CpuError::InvalidStartAddress(start_pgm));
+        }
+        let max = self.ram.len() - start;
+        if self.rom.len() > max {
+            return Err(CpuError::RomTooLarge { size: self.rom.len(), max });
+        }
+        self.start_pgm = start_pgm;
+        self.reset();
+        Ok(())
+    }
+
+    // Changes the levels of subroutine calls the stack holds before 2NNN
+    // fails with StackOverflow, at least 1 and all of them below the fontset
+    pub fn set_stack_depth(&mut self, depth: u16) -> Result<(), CpuError> {
+        if depth == 0 || 2 * depth as u32 > self.start_font as u32 {
+            return Err(CpuError::InvalidStackDepth(depth));
+        }
+        self.stack_depth = depth;
+        Ok(())
+    }
+
+    pub fn stack_depth(&self) -> u16 {
+        self.stack_depth
+    }
+
+    // Returns the size of the RAM in bytes
+    pub fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    // Restores the CPU to the state right after the ROM was loaded
+    pub fn reset(&mut self) {
+        self.pc = self.start_pgm;
+        self.sp = 0;
+        self.index = 0;
+        self.v_reg = [0; 16];
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.ram.fill(0);
+        self.time = self.clock.as_ref().map_or(Duration::ZERO, |clock| clock.now());
+        self.last_key = None;
+        self.has_drawn = false;
+        self.instructions_executed = 0;
+        self.cycles = 0;
+        if let Some(stats) = self.opcode_stats.as_mut() {
+            *stats = OpcodeStats::new();
         }
+        self.rng = self.seed.map(StdRng::seed_from_u64);
+        self.halted = false;
+        self.sys_logged = false;
+        self.waiting_display = false;
+        self.hires = false;
+        self.pattern_buffer = None;
+        self.pitch = DEFAULT_PITCH;
+
+        self.load_memory();
     }
 
     // Loads a ROM into the CPU's memory, from the program start address
-    pub fn load_rom(&mut self, rom: &[u8]) {
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), CpuError> {
+
+        if rom.is_empty() {
+            return Err(CpuError::InvalidRom("the ROM is empty"));
+        }
+
+        let max = self.ram.len().saturating_sub(self.start_pgm as usize);
+        if rom.len() > max {
+            return Err(CpuError::RomTooLarge { size: rom.len(), max });
+        }
+
+        // Opcodes are two bytes long, the last byte can't be a full opcode
+        if !rom.len().is_multiple_of(2) {
+            warn!("The ROM has an odd length ({} bytes)", rom.len());
+        }
+
+        self.rom = rom.to_vec();
+        self.rom_sha1 = sha1_hex(rom);
+        self.load_memory();
 
-        let mut startcpy: usize = START_PGM as usize;
-        for byte in rom {
+        info!("Loaded {} bytes from the disk", rom.len());
+
+        Ok(())
+    }
+    
+    // Copies the ROM and the fontset into the memory
+    fn load_memory(&mut self) {
+        let mut startcpy: usize = self.start_pgm as usize;
+        for byte in self.rom.iter() {
             self.ram[startcpy] = *byte;
             startcpy+=1;
         }
 
         // Load the fontset into the memory
-        let fontset: [u8; 80] = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F];
-        ];
-
-        startcpy = START_FONT as usize;
-        for byte in fontset.iter() {
+        startcpy = self.start_font as usize;
+        for byte in self.font.iter() {
             self.ram[startcpy] = *byte;
             startcpy+=1;
         }
+    }
+
+    // Guesses the variant rom was written for from the instructions of the
+    // extensions in it, XO-CHIP being a superset of SUPER-CHIP. Data read as
+    // instructions can be mistaken for them, so it's only a suggestion
+    pub fn detect_variant(rom: &[u8]) -> DetectedVariant {
+        let opcodes: Vec<u16> = disassemble_rom(rom, START_PGM).into_iter().map(|(_, opcode, _)| opcode).collect();
+        if opcodes.iter().any(|opcode| is_xochip_opcode(*opcode)) {
+            DetectedVariant::XoChip
+        } else if opcodes.iter().any(|opcode| is_schip_opcode(*opcode)) {
+            DetectedVariant::SuperChip
+        } else {
+            DetectedVariant::Chip8
+        }
+    }
 
-        info!("Loaded {} bytes from the disk", rom.len());
+    // Returns the SHA-1 of the ROM loaded in lowercase hexadecimal, to look
+    // it up in ROM databases
+    pub fn rom_sha1(&self) -> &str {
+        &self.rom_sha1
     }
-    
+
+    // Returns the ROM loaded, as it was before running it
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // Replaces the glyphs of the digits drawn by FX29 with the FONT_SIZE
+    // bytes of font, 5 per digit like FONTSET. They are written to the
+    // memory at once and kept on reset and when loading ROMs
+    pub fn set_font(&mut self, font: &[u8]) -> Result<(), CpuError> {
+        self.font = font.try_into().map_err(|_| CpuError::InvalidFont(font.len()))?;
+        let start = self.start_font as usize;
+        self.ram[start..start + FONT_SIZE].copy_from_slice(&self.font);
+        Ok(())
+    }
+
+    // Returns the address the ROM is loaded at
+    pub fn start_address(&self) -> u16 {
+        self.start_pgm
+    }
+
+    // Splits the memory in labeled regions, in order and covering all of it:
+    // "program" for the ROM loaded, "font", "reserved" for the rest below
+    // the start address, where the interpreter and the stack were, and
+    // "free" above. A ROM loaded over the fontset hides it. The ranges are
+    // usize as the 64KB of XO-CHIP end past u16
+    pub fn memory_regions(&self) -> Vec<(Range<usize>, &'static str)> {
+        let size = self.ram.len();
+        let start_pgm = self.start_pgm as usize;
+        let font = self.start_font as usize..self.start_font as usize + FONT_SIZE;
+        let program = start_pgm..start_pgm + self.rom.len();
+
+        let label = |addr: usize| match addr {
+            _ if program.contains(&addr) => "program",
+            _ if font.contains(&addr) => "font",
+            _ if addr < start_pgm => "reserved",
+            _ => "free",
+        };
+
+        // The label can only change where one of the ranges starts or ends
+        let bounds: BTreeSet<usize> = [0, font.start, font.end, program.start, program.end, size]
+            .into_iter().map(|addr| addr.min(size)).collect();
+        let mut regions: Vec<(Range<usize>, &'static str)> = Vec::new();
+        for (start, end) in bounds.iter().zip(bounds.iter().skip(1)) {
+            match regions.last_mut() {
+                Some((last, name)) if *name == label(*start) => last.end = *end,
+                _ => regions.push((*start..*end, label(*start))),
+            }
+        }
+        regions
+    }
+
+    // Returns the address of the glyph of the digit (0x0..=0xF) in the
+    // fontset, the one FX29 points index to. Only the low nibble counts
+    pub fn font_address(&self, digit: u8) -> u16 {
+        self.start_font + (digit & 0x0F) as u16 * GLYPH_SIZE
+    }
+
     // Loads a CHIP-8 ROM from a file into the CPU's memory
-    pub fn load_rom_file(&mut self, path: &str) -> io::Result<()> {
+    #[cfg(feature = "std")]
+    pub fn load_rom_file(&mut self, path: &str) -> Result<(), CpuError> {
         let mut file = File::open(path)?;
+        self.load_rom_from_reader(&mut file)
+    }
+
+    // Loads the ROM read from r. At most one byte more than fits in memory is
+    // read, enough for load_rom to reject ROMs that are too large. ROMs
+    // compressed with gzip are read whole and decompressed first, up to the
+    // size that fits in memory
+    #[cfg(feature = "std")]
+    pub fn load_rom_from_reader<R: Read>(&mut self, r: &mut R) -> Result<(), CpuError> {
+        let max = self.ram.len().saturating_sub(self.start_pgm as usize);
 
         let mut buf = vec!();
-        file.read_to_end(&mut buf)?;
+        r.by_ref().take(max as u64 + 1).read_to_end(&mut buf)?;
 
-        self.load_rom(&buf);
+        if gzip::is_gzip(&buf) {
+            r.read_to_end(&mut buf)?;
+            buf = gzip::decompress(&buf, max).map_err(CpuError::InvalidRom)?;
+            info!("Decompressed the ROM ({} bytes)", buf.len());
+        }
 
-        Ok(())
+        self.load_rom(&buf)
+    }
+
+    // Replaces the clock of update_timers, e.g. with a fake one in tests
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.time = clock.now();
+        self.clock = Some(clock);
     }
 
+    // Updates the timers for the time elapsed on the clock since the last
+    // update. Does nothing without a clock, run_for ticks them anyway
     pub fn update_timers(&mut self) {
-        // Update timers
-        let now = Instant::now();
+        if let Some(now) = self.clock.as_ref().map(|clock| clock.now()) {
+            self.update_timers_at(now);
+        }
+    }
+
+    // Updates the timers for the time elapsed until now
+    fn update_timers_at(&mut self, now: Duration) {
+        let elapsed = now.saturating_sub(self.time);
+
+        trace!("{}", elapsed.as_millis());
+
+        // Catch up with all the 60Hz ticks since the last update, the
+        // fraction of tick left over is carried to the next one so the timers
+        // run at exactly 60Hz
+        let ticks = self.ticks.steps(elapsed);
+        if ticks > 0 {
+            self.tick_timers(ticks);
+        }
+        self.time = now;
+    }
+
+    // Decrements the timers by a number of 60Hz ticks, unless they are paused
+    fn tick_timers(&mut self, ticks: u32) {
+        // The tick is the vertical blank DXYN waits for
+        self.waiting_display = false;
+
+        if self.timers_paused {
+            return;
+        }
+        let ticks = ticks.min(u8::MAX as u32) as u8;
+        self.delay_timer = self.delay_timer.saturating_sub(ticks);
+        self.sound_timer = self.sound_timer.saturating_sub(ticks);
+    }
+
+    // Pauses or resumes the instructions run by run_for and run_steps, the
+    // timers keep counting down. step still executes one when called
+    pub fn set_cpu_paused(&mut self, paused: bool) {
+        self.cpu_paused = paused;
+    }
+
+    pub fn cpu_paused(&self) -> bool {
+        self.cpu_paused
+    }
+
+    // Pauses or resumes the countdown of the delay and sound timers, the
+    // ticks elapsed while paused are dropped instead of caught up
+    pub fn set_timers_paused(&mut self, paused: bool) {
+        self.timers_paused = paused;
+    }
+
+    pub fn timers_paused(&self) -> bool {
+        self.timers_paused
+    }
+
+    // Changes the number of instructions executed per second by run_for
+    pub fn set_ips(&mut self, ips: u32) {
+        self.steps.set_ips(ips);
+    }
+
+    // Slows run_for down factor times, both the instructions and the
+    // timers, 1 for the normal speed
+    pub fn set_slowdown(&mut self, factor: u32) {
+        self.steps.set_slowdown(factor);
+        self.ticks.set_slowdown(factor);
+    }
 
-        trace!("{}", now.duration_since(self.time).as_millis());
+    // Limits the time on the clock a call to run_for spends executing
+    // instructions, the ones left are executed by the next call. It keeps a
+    // frame on time when the host can't keep up with the instructions per
+    // second, without a clock there is no limit
+    pub fn set_frame_budget(&mut self, budget: Option<Duration>) {
+        self.frame_budget = budget;
+    }
 
-        // Update timers every 16ms (~ 60Hz)
-        if now.duration_since(self.time) >= Duration::from_millis(16) {
-            if self.delay_timer > 0 {
-                self.delay_timer -= 1;
+    // Runs the instructions and the timer ticks due for the elapsed time,
+    // carrying the fractions over to the next call. The instructions are
+    // executed first and the rest of them is dropped once the CPU halts or
+    // waits for the display, or left to the next call past the frame
+    // budget. Afterwards has_drawn tells whether any of them drew, the
+    // outcome also tells whether the sound started or stopped
+    pub fn run_for(&mut self, elapsed: Duration, mut screen: Option<&mut dyn Display>) -> Result<StepOutcome, CpuError> {
+        let steps = self.steps.steps(elapsed);
+        let ticks = self.ticks.steps(elapsed);
+        let sound = self.sound_timer > 0;
+        let start = self.clock.as_ref().filter(|_| self.frame_budget.is_some()).map(|clock| clock.now());
+
+        let mut outcome = StepOutcome { halted: self.halted, ..StepOutcome::default() };
+        for n in 0..steps {
+            if self.halted || self.waiting_display || self.cpu_paused {
+                break;
             }
-            if self.sound_timer > 0 {
-                self.sound_timer -= 1;
+            if n > 0 && n % BUDGET_CHECK_STEPS == 0 && self.over_budget(start) {
+                self.steps.defer(steps - n);
+                break;
             }
+            let step = self.step(screen.as_mut().map(|screen| &mut **screen as &mut dyn Display))?;
+            outcome.opcode = step.opcode;
+            outcome.drawn = outcome.drawn || step.drawn;
+        }
+        self.has_drawn = outcome.drawn;
+
+        if ticks > 0 {
+            self.tick_timers(ticks);
+        }
 
-            self.time = now;
+        outcome.sound_changed = sound != (self.sound_timer > 0);
+        outcome.halted = self.halted;
+        Ok(outcome)
+    }
+
+    // Whether the time on the clock since start exceeds the frame budget
+    fn over_budget(&self, start: Option<Duration>) -> bool {
+        match (start, self.frame_budget, &self.clock) {
+            (Some(start), Some(budget), Some(clock)) => clock.now().saturating_sub(start) > budget,
+            _ => false,
         }
     }
 
+    // Executes up to n instructions, stopping early when the CPU halts,
+    // waits for the display or is paused, and returns the number executed. It bounds
+    // headless runs, e.g. fuzzing or tests, that could otherwise loop forever
+    pub fn run_steps(&mut self, n: u64, mut screen: Option<&mut dyn Display>) -> Result<u64, CpuError> {
+        let mut executed = 0;
+        while executed < n && !self.halted && !self.waiting_display && !self.cpu_paused {
+            self.step(screen.as_mut().map(|screen| &mut **screen as &mut dyn Display))?;
+            executed += 1;
+        }
+        Ok(executed)
+    }
 
     pub fn get_sound_timer(&self) -> u8 {
         self.sound_timer
     }
 
+    // Returns the XO-CHIP audio pattern loaded by F002, the sound is a plain
+    // beep until a ROM loads one
+    pub fn audio_pattern(&self) -> Option<&[u8; 16]> {
+        self.pattern_buffer.as_ref()
+    }
+
+    // Returns the XO-CHIP pitch of the audio pattern set by FX3A, it plays
+    // at 4000 * 2 ^ ((pitch - 64) / 48) bits per second
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
     pub fn has_drawn(&self) -> bool {
         self.has_drawn
     }
 
-    // Executes one step of the CHIP-8 CPU
-    pub fn step(&mut self, screen: Option<&mut Screen>) {
+    // Returns the number of instructions executed since the CPU was created
+    // or reset
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
 
-        self.has_drawn = false;
-        let opcode = self.fetch();
+    // Enables or disables counting the approximate COSMAC VIP machine cycles
+    // of the executed opcodes, it's disabled by default
+    pub fn set_cycle_timing(&mut self, enabled: bool) {
+        self.cycle_timing = enabled;
+    }
 
-        trace!("Executing 0x{:x}", opcode);
+    // Returns the machine cycles counted since the CPU was created or reset,
+    // always 0 without cycle timing
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
 
-        match opcode & 0xF000 {
-            0x0000 => {
-                match opcode & 0x00FF {
-                    // Clear the screen
-                    0xE0 => {
-                        self.has_drawn = true;
-                        let screen = screen.unwrap();
-                        trace!("Clearing the screen");
-                        screen.clear();
-                    }
+    // Enables or disables counting the executions of each opcode, it's
+    // disabled by default
+    pub fn set_opcode_stats(&mut self, enabled: bool) {
+        self.opcode_stats = enabled.then(OpcodeStats::new);
+    }
 
-                    // Return from subroutine
-                    0xEE => {
-                        trace!("Returning from subroutine");
-                        self.sp-=1;
-                        self.pc = self.ram[self.sp as usize] as u16;
-                        self.sp-=1;
-                        self.pc = self.pc<<8 | (self.ram[self.sp as usize] as u16);
-                    }
+    // Returns the opcodes executed since the counting was enabled or the CPU
+    // reset, None when not counting
+    pub fn opcode_stats(&self) -> Option<&OpcodeStats> {
+        self.opcode_stats.as_ref()
+    }
 
-                    _ => warn!("Operation 0x{:x} is not implemented yet!", opcode),
-                }
-            },
-            // Jump to address NNN
-            0x1000 => {
-                trace!("Jumping to 0x{:x}", opcode & 0x0FFF);
-                self.pc = opcode & 0x0FFF;
-            },
-            // Call subroutine
-            0x2000 => {
-                trace!("Calling subroutine at 0x{:x}", opcode & 0x0FFF);
-                self.ram[self.sp as usize] = (self.pc & 0xff) as u8;
-                self.sp+=1;
-                self.ram[self.sp as usize] = (self.pc>>8) as u8;
-                self.sp+=1;
+    // Returns the program counter, the address of the next opcode
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
 
-                self.pc = opcode & 0x0fff;
-            },
-            // Skip next instruction if VX == NN
-            0x3000 => {
-                let x = (opcode & 0x0F00) >> 8;
-                let nn = opcode & 0x00FF;
-                trace!("Skip if V{} == {}", x, nn);
+    // Returns the stack pointer
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
 
-                if self.v_reg[x as usize] == nn as u8 {
-                    self.pc += 2;
-                }
-            },
-            // Skip next instruction if VX != NN
-            0x4000 => {
-                let x = (opcode & 0x0F00) >> 8;
-                let nn = opcode & 0x00FF;
-                trace!("Skip if V{} != {}", x, nn);
+    // Returns the index register
+    pub fn index(&self) -> u16 {
+        self.index
+    }
 
-                if self.v_reg[x as usize] != nn as u8 {
-                    self.pc += 2;
-                }
-            },
-            // Skip next instruction if VX == VY
-            0x5000 => {
-                let x = (opcode & 0x0F00) >> 8;
-                let y = (opcode & 0x00F0) >> 4;
+    // Returns the value of the register Vi, panics if i is not in 0x0..=0xF
+    pub fn v(&self, i: usize) -> u8 {
+        self.v_reg[i]
+    }
 
-                trace!("Skip if V{} == V{}", x, y);
+    // Returns the value of the delay timer
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
 
-                if self.v_reg[x as usize] == self.v_reg[y as usize] {
-                    self.pc += 2;
-                }
-            },
-            // Set VX to NN
-            0x6000 => {
-                let x = (opcode & 0x0F00) >> 8;
-                let nn = opcode & 0x00FF;
-                trace!("Setting V{} to {}", x, nn);
-                self.v_reg[x as usize] = nn as u8;
+    // Returns a copy of all the registers and timers
+    pub fn registers(&self) -> CpuSnapshot {
+        CpuSnapshot {
+            pc: self.pc,
+            sp: self.sp,
+            index: self.index,
+            v_reg: self.v_reg,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+        }
+    }
+
+    // Reads a byte from the memory, addresses past the end of the RAM read as 0
+    pub fn read_memory(&self, addr: u16) -> u8 {
+        self.ram.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    // Disassembles the instruction at addr in the memory as it is now, so
+    // code the program modified shows as executed. Empty past the end of the
+    // RAM
+    pub fn disassemble_at(&self, addr: u16) -> String {
+        disassemble_rom(&self.read_range(addr, 4), addr).into_iter().next()
+            .map_or_else(String::new, |(_, _, mnemonic)| mnemonic)
+    }
+
+    // Formats up to len bytes of memory from start as a hex dump, 16 bytes
+    // per line after the address of the first one and followed by them as
+    // ASCII, e.g.
+    //
+    //   0200  00 e0 a2 2a 60 0c 61 08  d0 1f 70 09 a2 39 d0 1f  |...*`.a...p..9..|
+    //
+    // The lines start at start, the dump stops at the end of the RAM
+    pub fn hex_dump(&self, start: u16, len: u16) -> String {
+        let mut dump = String::new();
+        for (n, line) in self.read_range(start, len as usize).chunks(16).enumerate() {
+            let addr = start as usize + n * 16;
+            let mut hex = String::new();
+            for (i, byte) in line.iter().enumerate() {
+                hex += &format!("{}{:02x}", if i == 8 { "  " } else if i > 0 { " " } else { "" }, byte);
+            }
+            let ascii: String = line.iter()
+                .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+                .collect();
+            dump += &format!("{:04x}  {:<48}  |{}|\n", addr, hex, ascii);
+        }
+        dump
+    }
+
+    // Reads up to len bytes from the memory starting at addr, stopping at the
+    // end of the RAM
+    pub fn read_range(&self, addr: u16, len: usize) -> Vec<u8> {
+        let start = (addr as usize).min(self.ram.len());
+        let end = start.saturating_add(len).min(self.ram.len());
+        self.ram[start..end].to_vec()
+    }
+
+    // Writes a byte to the memory
+    pub fn write_memory(&mut self, addr: u16, val: u8) -> Result<(), CpuError> {
+        match self.ram.get_mut(addr as usize) {
+            Some(byte) => {
+                *byte = val;
+                Ok(())
             },
-            // Add NN to VX
+            None => Err(CpuError::MemoryOutOfBounds(addr as usize)),
+        }
+    }
+
+    // Pins the byte at addr to value: it is written back after every step,
+    // e.g. to keep the lives of a game from going down
+    pub fn add_cheat(&mut self, addr: u16, value: u8) -> Result<(), CpuError> {
+        if addr as usize >= self.ram.len() {
+            return Err(CpuError::MemoryOutOfBounds(addr as usize));
+        }
+        self.cheats.push((addr, value));
+        Ok(())
+    }
+
+    // Logs the writes of the instructions to addr, with the address of the
+    // instruction and the old and new byte
+    pub fn watch_memory(&mut self, addr: u16) {
+        self.watches.insert(addr);
+    }
+
+    // Calls callback with every write to a watched address, besides logging it
+    pub fn set_watch_callback(&mut self, callback: Box<dyn FnMut(MemoryWrite)>) {
+        self.watch_callback = Some(callback);
+    }
+
+    // Calls callback with the CPU after every instruction that draws (00E0
+    // and DXYN), e.g. to record the frames. It runs in step, on the thread of
+    // the emulation, so it should be quick: a slow callback slows the
+    // emulation down
+    pub fn on_frame(&mut self, callback: FrameCallback) {
+        self.frame_callback = Some(callback);
+    }
+
+    // Returns the index in the RAM of the address, wrapped around with the
+    // memory wrap quirk
+    fn ram_address(&self, addr: u16) -> usize {
+        match self.quirks.wrap_memory {
+            true => addr as usize % self.ram.len(),
+            false => addr as usize,
+        }
+    }
+
+    // Reads a byte of the memory for the instruction being executed
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[self.ram_address(addr)]
+    }
+
+    // Writes a byte of the memory for the instruction being executed, all the
+    // writes of the instructions go through here for the watches
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        let addr = self.ram_address(addr) as u16;
+        let old = core::mem::replace(&mut self.ram[addr as usize], val);
+
+        if self.watches.contains(&addr) {
+            // The writes happen before the instructions change the pc
+            let write = MemoryWrite { pc: self.pc.wrapping_sub(2), addr, old, new: val };
+            info!("Watch: {}", write);
+            if let Some(callback) = self.watch_callback.as_mut() {
+                callback(write);
+            }
+        }
+    }
+
+    // Checks that the len bytes of memory from addr are in the RAM, so that
+    // instructions fail before writing only part of them. With the memory
+    // wrap quirk all the addresses are
+    fn check_range(&self, addr: u16, len: u16) -> Result<(), CpuError> {
+        if !self.quirks.wrap_memory && addr as usize + len as usize > self.ram.len() {
+            return Err(CpuError::MemoryOutOfBounds((addr as usize).max(self.ram.len())));
+        }
+        Ok(())
+    }
+
+    // Stores v_reg[start]..=v_reg[end] in memory starting at index, leaving
+    // index after the last register with the memory increment quirk
+    fn store_registers(&mut self, start: u8, end: u8) -> Result<(), CpuError> {
+        self.check_range(self.index, (end - start) as u16 + 1)?;
+        for (i, reg) in (start..=end).enumerate() {
+            self.write_ram(self.index.wrapping_add(i as u16), self.v_reg[reg as usize]);
+        }
+        if self.quirks.memory_increment {
+            self.index = self.index.wrapping_add((end - start) as u16 + 1);
+        }
+        Ok(())
+    }
+
+    // Loads v_reg[start]..=v_reg[end] from memory starting at index, leaving
+    // index after the last register with the memory increment quirk
+    fn load_registers(&mut self, start: u8, end: u8) -> Result<(), CpuError> {
+        self.check_range(self.index, (end - start) as u16 + 1)?;
+        for (i, reg) in (start..=end).enumerate() {
+            self.v_reg[reg as usize] = self.read_ram(self.index.wrapping_add(i as u16));
+        }
+        if self.quirks.memory_increment {
+            self.index = self.index.wrapping_add((end - start) as u16 + 1);
+        }
+        Ok(())
+    }
+
+    // Stores the registers from VX to VY, descending when X > Y, in memory
+    // starting at index, which is left unchanged
+    fn store_register_range(&mut self, x: u8, y: u8) -> Result<(), CpuError> {
+        self.check_range(self.index, x.abs_diff(y) as u16 + 1)?;
+        for (i, reg) in register_range(x, y).enumerate() {
+            self.write_ram(self.index.wrapping_add(i as u16), self.v_reg[reg as usize]);
+        }
+        Ok(())
+    }
+
+    // Loads the registers from VX to VY, descending when X > Y, from memory
+    // starting at index, which is left unchanged
+    fn load_register_range(&mut self, x: u8, y: u8) -> Result<(), CpuError> {
+        self.check_range(self.index, x.abs_diff(y) as u16 + 1)?;
+        for (i, reg) in register_range(x, y).enumerate() {
+            self.v_reg[reg as usize] = self.read_ram(self.index.wrapping_add(i as u16));
+        }
+        Ok(())
+    }
+
+    // Returns the quirks the CPU is emulating
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // Changes the quirks the CPU is emulating
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Returns true after a DXYN with the display wait quirk until the next
+    // 60Hz tick of update_timers, meanwhile step does nothing. Callers
+    // should stop stepping for the rest of the frame, the skipped
+    // instructions are what makes drawing ROMs run at the original speed
+    pub fn is_waiting_display(&self) -> bool {
+        self.waiting_display
+    }
+
+    // Whether the SUPER-CHIP high resolution mode is on
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Returns the width and height of the display in the current mode
+    pub fn resolution(&self) -> (u32, u32) {
+        match self.hires {
+            true => (HIRES_WIDTH, HIRES_HEIGHT),
+            false => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+
+    // Returns true once the program has ended by jumping to itself, from then
+    // on step does nothing
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // Enables or disables halting on a jump to itself, it's enabled by
+    // default
+    pub fn set_halt_on_loop(&mut self, enabled: bool) {
+        self.halt_on_loop = enabled;
+    }
+
+    // Enables or disables the strict mode, where 0NNN calls of machine code
+    // and unknown opcodes return an error instead of being skipped. It's
+    // disabled by default
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.strict = enabled;
+    }
+
+    // Writes a line to writer for every instruction executed from now on,
+    // with its address, opcode, mnemonic and the registers it changed
+    #[cfg(feature = "std")]
+    pub fn set_trace(&mut self, writer: Box<dyn Write>) {
+        self.trace = Some(writer);
+        self.trace_lines = 0;
+    }
+
+    // Writes a `PC: OPCODE MNEMONIC` line to writer for each of the next
+    // limit instructions fetched, a lighter trace than set_trace
+    #[cfg(feature = "std")]
+    pub fn set_opcode_log(&mut self, writer: Box<dyn Write>, limit: u64) {
+        self.opcode_log = (limit > 0).then_some(writer);
+        self.opcode_log_left = limit;
+    }
+
+    // Executes one step of the CHIP-8 CPU. Opcodes that don't draw or read
+    // the keypad can run without a display, the others return an error and
+    // leave the CPU before the opcode so it can be retried
+    pub fn step(&mut self, screen: Option<&mut dyn Display>) -> Result<StepOutcome, CpuError> {
+
+        if self.halted || self.waiting_display {
+            return Ok(StepOutcome { halted: self.halted, ..StepOutcome::default() });
+        }
+
+        let pc = self.pc;
+        let sound = self.sound_timer > 0;
+        #[cfg(feature = "std")]
+        let before = self.trace.as_ref().map(|_| self.registers());
+
+        self.has_drawn = false;
+        self.check_range(pc, 2)?;
+        let opcode = self.fetch();
+        self.instructions_executed += 1;
+
+        #[cfg(feature = "std")]
+        if self.opcode_log.is_some() {
+            self.log_opcode(pc, opcode);
+        }
+
+        trace!("Executing 0x{:x}", opcode);
+
+        if let Err(e) = self.execute(opcode, screen) {
+            self.pc = pc;
+            self.instructions_executed -= 1;
+            return Err(e);
+        }
+
+        if self.cycle_timing {
+            self.cycles += opcode_cycles(opcode);
+        }
+        if let Some(stats) = self.opcode_stats.as_mut() {
+            stats.record(opcode);
+        }
+
+        // The next opcode is fetched after the cheats are applied, so a
+        // cheat can't change the instruction being executed
+        for (addr, value) in &self.cheats {
+            self.ram[*addr as usize] = *value;
+        }
+
+        #[cfg(feature = "std")]
+        if let Some(before) = before {
+            self.write_trace(pc, opcode, &before);
+        }
+
+        if self.has_drawn {
+            // Taken out for the call, which borrows the whole CPU
+            if let Some(mut callback) = self.frame_callback.take() {
+                callback(self);
+                self.frame_callback = Some(callback);
+            }
+        }
+
+        Ok(StepOutcome {
+            opcode: Some(opcode),
+            drawn: self.has_drawn,
+            sound_changed: sound != (self.sound_timer > 0),
+            halted: self.halted,
+        })
+    }
+
+    // Writes the trace line of the instruction at pc
+    #[cfg(feature = "std")]
+    fn write_trace(&mut self, pc: u16, opcode: u16, before: &CpuSnapshot) {
+        let after = self.registers();
+        let mut changes = vec![];
+        for i in 0..16 {
+            if before.v_reg[i] != after.v_reg[i] {
+                changes.push(format!("V{:X}={:02X}", i, after.v_reg[i]));
+            }
+        }
+        if before.index != after.index {
+            changes.push(format!("I={:04X}", after.index));
+        }
+        if before.sp != after.sp {
+            changes.push(format!("SP={:02X}", after.sp));
+        }
+        if before.delay_timer != after.delay_timer {
+            changes.push(format!("DT={:02X}", after.delay_timer));
+        }
+        if before.sound_timer != after.sound_timer {
+            changes.push(format!("ST={:02X}", after.sound_timer));
+        }
+
+        let line = format!("{:04X} {:04X} {:<16} {}", pc, opcode, disassemble(opcode), changes.join(" "));
+
+        // Flush every now and then so a crash loses at most a few lines
+        self.trace_lines += 1;
+        let trace = self.trace.as_mut().unwrap();
+        let mut result = writeln!(trace, "{}", line.trim_end());
+        if result.is_ok() && self.trace_lines.is_multiple_of(TRACE_FLUSH_INTERVAL) {
+            result = trace.flush();
+        }
+
+        if let Err(e) = result {
+            warn!("Could not write the trace, disabling it: {}", e);
+            self.trace = None;
+        }
+    }
+
+    // Writes the opcode log line of the instruction at pc, the log is closed
+    // after the last one
+    #[cfg(feature = "std")]
+    fn log_opcode(&mut self, pc: u16, opcode: u16) {
+        let log = self.opcode_log.as_mut().unwrap();
+        let result = writeln!(log, "{:04X}: {:04X} {}", pc, opcode, disassemble(opcode));
+
+        self.opcode_log_left -= 1;
+        if let Err(e) = result {
+            warn!("Could not write the opcode log, disabling it: {}", e);
+            self.opcode_log = None;
+        } else if self.opcode_log_left == 0 {
+            let _ = log.flush();
+            self.opcode_log = None;
+        }
+    }
+
+    // Skips an opcode that is not an instruction with a warning, or fails in
+    // strict mode
+    fn unknown_opcode(&self, opcode: u16) -> Result<(), CpuError> {
+        if self.strict {
+            return Err(CpuError::UnknownOpcode(opcode));
+        }
+        warn!("Operation 0x{:x} is not implemented yet!", opcode);
+        Ok(())
+    }
+
+    // Executes a single opcode, the program counter already points to the
+    // next one
+    fn execute(&mut self, opcode: u16, screen: Option<&mut dyn Display>) -> Result<(), CpuError> {
+
+        match opcode & 0xF000 {
+            0x0000 => {
+                match opcode {
+                    // Clear the screen
+                    0x00E0 => {
+                        let screen = screen.ok_or(CpuError::DisplayRequired(opcode))?;
+                        self.has_drawn = true;
+                        trace!("Clearing the screen");
+                        screen.clear();
+                    }
+
+                    // Return from subroutine
+                    0x00EE => {
+                        trace!("Returning from subroutine");
+                        if self.sp < 2 {
+                            return Err(CpuError::StackUnderflow);
+                        }
+                        self.sp-=1;
+                        self.pc = self.ram[self.sp as usize] as u16;
+                        self.sp-=1;
+                        self.pc = self.pc<<8 | (self.ram[self.sp as usize] as u16);
+                    }
+
+                    // Switch to the SUPER-CHIP 64x32 (00FE) or 128x64 (00FF)
+                    // mode. Like Octo the display is cleared, the original
+                    // interpreter left its pixels in place
+                    0x00FE | 0x00FF => {
+                        let screen = screen.ok_or(CpuError::DisplayRequired(opcode))?;
+                        self.has_drawn = true;
+                        self.hires = opcode == 0x00FF;
+                        trace!("Switching to the {} resolution", if self.hires { "high" } else { "low" });
+                        let (width, height) = self.resolution();
+                        screen.resize(width, height);
+                    }
+
+                    // Call the machine code routine at NNN, which only ran on
+                    // the original machines. Like in most interpreters it's
+                    // skipped, logging it once as ROMs may start with one
+                    _ => {
+                        if self.strict {
+                            return Err(CpuError::UnsupportedSys(opcode));
+                        }
+                        if !self.sys_logged {
+                            info!("Skipping the machine code call 0x{:04x}, it's not supported", opcode);
+                            self.sys_logged = true;
+                        }
+                    }
+                }
+            },
+            // Jump to address NNN
+            0x1000 => {
+                trace!("Jumping to 0x{:x}", opcode & 0x0FFF);
+
+                // The jump was fetched from pc - 2
+                if self.halt_on_loop && opcode & 0x0FFF == self.pc.wrapping_sub(2) {
+                    info!("The program has ended, jumping to itself at 0x{:x}", opcode & 0x0FFF);
+                    self.halted = true;
+                }
+
+                self.pc = opcode & 0x0FFF;
+            },
+            // Call subroutine
+            0x2000 => {
+                trace!("Calling subroutine at 0x{:x}", opcode & 0x0FFF);
+                if self.sp >= 2 * self.stack_depth {
+                    return Err(CpuError::StackOverflow(self.stack_depth));
+                }
+                self.check_range(self.sp, 2)?;
+                self.write_ram(self.sp, (self.pc & 0xff) as u8);
+                self.sp+=1;
+                self.write_ram(self.sp, (self.pc>>8) as u8);
+                self.sp+=1;
+
+                self.pc = opcode & 0x0fff;
+            },
+            // Skip next instruction if VX == NN
+            0x3000 => {
+                let x = (opcode & 0x0F00) >> 8;
+                let nn = opcode & 0x00FF;
+                trace!("Skip if V{} == {}", x, nn);
+
+                if self.v_reg[x as usize] == nn as u8 {
+                    self.skip();
+                }
+            },
+            // Skip next instruction if VX != NN
+            0x4000 => {
+                let x = (opcode & 0x0F00) >> 8;
+                let nn = opcode & 0x00FF;
+                trace!("Skip if V{} != {}", x, nn);
+
+                if self.v_reg[x as usize] != nn as u8 {
+                    self.skip();
+                }
+            },
+            // Skip next instruction if VX == VY
+            0x5000 => {
+                let x = ((opcode & 0x0F00) >> 8) as u8;
+                let y = ((opcode & 0x00F0) >> 4) as u8;
+
+                match opcode & 0x000F {
+                    0x0 => {
+                        trace!("Skip if V{} == V{}", x, y);
+
+                        if self.v_reg[x as usize] == self.v_reg[y as usize] {
+                            self.skip();
+                        }
+                    },
+
+                    // XO-CHIP: store VX..VY in memory starting at index
+                    0x2 => {
+                        trace!("Store V{} to V{} at 0x{:X}", x, y, self.index);
+                        self.store_register_range(x, y)?;
+                    },
+
+                    // XO-CHIP: load VX..VY from memory starting at index
+                    0x3 => {
+                        trace!("Load V{} to V{} from 0x{:X}", x, y, self.index);
+                        self.load_register_range(x, y)?;
+                    },
+
+                    _ => self.unknown_opcode(opcode)?,
+                }
+            },
+            // Set VX to NN
+            0x6000 => {
+                let x = (opcode & 0x0F00) >> 8;
+                let nn = opcode & 0x00FF;
+                trace!("Setting V{} to {}", x, nn);
+                self.v_reg[x as usize] = nn as u8;
+            },
+            // Add NN to VX
             0x7000 => {
                 let x = (opcode & 0x0F00) >> 8;
                 let nn = opcode & 0x00FF;
@@ -242,7 +1608,9 @@ impl Cpu {
                         trace!("Setting V{} |= V{}", x, y);
 
                         self.v_reg[x as usize] |= self.v_reg[y as usize];
-                        self.v_reg[0xf] = 0;
+                        if self.quirks.vf_reset {
+                            self.v_reg[0xf] = 0;
+                        }
                     },
                     // Setting VX &= VY
                     0x2 => {
@@ -252,7 +1620,9 @@ impl Cpu {
                         trace!("Setting V{} &= V{}", x, y);
 
                         self.v_reg[x as usize] &= self.v_reg[y as usize];
-                        self.v_reg[0xf] = 0;
+                        if self.quirks.vf_reset {
+                            self.v_reg[0xf] = 0;
+                        }
                     },
                     // Setting VX ^= VY
                     0x3 => {
@@ -260,7 +1630,9 @@ impl Cpu {
                         let y = (opcode & 0x00F0) >> 4;
 
                         self.v_reg[x as usize] ^= self.v_reg[y as usize];
-                        self.v_reg[0xf] = 0;
+                        if self.quirks.vf_reset {
+                            self.v_reg[0xf] = 0;
+                        }
                     },
                     // Add VY to VX (affects the carry flag)
                     0x4 => {
@@ -302,8 +1674,10 @@ impl Cpu {
                         let x = (opcode & 0x0F00) >> 8;
                         let y = (opcode & 0x00F0) >> 4;
 
-                        let flag: u8 = self.v_reg[y as usize] & 0x01; 
-                        self.v_reg[x as usize] = self.v_reg[y as usize] >> 1;
+                        let src = if self.quirks.shift { x } else { y };
+
+                        let flag: u8 = self.v_reg[src as usize] & 0x01; 
+                        self.v_reg[x as usize] = self.v_reg[src as usize] >> 1;
                         self.v_reg[0xF] = flag;
                     },
                     // Subtract VX from VY (affects the carry flag)
@@ -327,12 +1701,14 @@ impl Cpu {
                         let x = (opcode & 0x0F00) >> 8;
                         let y = (opcode & 0x00F0) >> 4;
                         
-                        let flag: u8 = (self.v_reg[y as usize] & 0x80) >> 7;
-                        self.v_reg[x as usize] = self.v_reg[y as usize] << 1;
+                        let src = if self.quirks.shift { x } else { y };
+
+                        let flag: u8 = (self.v_reg[src as usize] & 0x80) >> 7;
+                        self.v_reg[x as usize] = self.v_reg[src as usize] << 1;
                         self.v_reg[0xF] = flag;
                     },
 
-                    _ => warn!("Operation 0x{:x} is not implemented yet!", opcode),
+                    _ => self.unknown_opcode(opcode)?,
                 }
             },
             // Skip next instruction if VX != VY
@@ -343,7 +1719,7 @@ impl Cpu {
                 trace!("Skip if V{} != V{}", x, y);
 
                 if self.v_reg[x as usize] != self.v_reg[y as usize] {
-                    self.pc += 2;
+                    self.skip();
                 }
             },
             // Set index to NNN
@@ -355,45 +1731,60 @@ impl Cpu {
             // Jump to NNN + V0
             0xB000 => {
                 let nnn = opcode & 0x0FFF;
-                trace!("Jumping to 0x{:x} + V0 (0x{:x})", nnn, self.v_reg[0]);
-                self.pc = nnn + self.v_reg[0] as u16;
+                let x = if self.quirks.jump { (opcode & 0x0F00) >> 8 } else { 0 };
+                trace!("Jumping to 0x{:x} + V{} (0x{:x})", nnn, x, self.v_reg[x as usize]);
+                self.pc = nnn + self.v_reg[x as usize] as u16;
             },
             // Set VX to random number & NN
             0xC000 => {
                 let x = (opcode & 0x0F00) >> 8;
                 let nn = (opcode & 0x00FF) as u8;
-                let mut rng = rand::thread_rng();
+                let random = match &mut self.rng {
+                    Some(rng) => rng.gen::<u8>(),
+                    #[cfg(feature = "std")]
+                    None => rand::thread_rng().gen::<u8>(),
+                    // Without std there is no entropy to seed from
+                    #[cfg(not(feature = "std"))]
+                    None => self.rng.insert(StdRng::seed_from_u64(0)).gen::<u8>(),
+                };
 
                 trace!("Setting V{} to random number & {}", x, nn);
 
-                self.v_reg[x as usize] = rng.gen::<u8>() & nn;
+                self.v_reg[x as usize] = random & nn;
 
             }
             // Draw sprite
             0xD000 => { 
-                self.has_drawn = true;
+                let screen = screen.ok_or(CpuError::DisplayRequired(opcode))?;
+                self.check_range(self.index, opcode & 0x000F)?;
 
-                let screen = screen.unwrap();
+                self.has_drawn = true;
+                self.waiting_display = self.quirks.display_wait;
                 
                 let x = (opcode & 0x0F00) >> 8;
                 let y = (opcode & 0x00F0) >> 4;
                 let n = opcode & 0x000F;
 
-                let x = self.v_reg[x as usize] % 64;
-                let y = self.v_reg[y as usize] % 32;
+                // The sprite starts wrapped to the display of the current
+                // resolution and then wraps or clips
+                let (width, height) = self.resolution();
+                let x = self.v_reg[x as usize] as u32 % width;
+                let y = self.v_reg[y as usize] as u32 % height;
 
                 self.v_reg[0xf] = 0;
                 for i in 0..n {
-                    if y+(i as u8) >= 32 {
-                        break;
-                    }
-                    let byte = self.ram[(self.index + i) as usize];
+                    let byte = self.read_ram(self.index.wrapping_add(i));
                     for j in 0..8 {
-                        if x+j >= 64 {
-                            break;
-                        }
                         let bit = (byte >> (7-j)) & 0x01;
-                        let prev = screen.draw_pixel(x+j, y+(i as u8), bit);
+                        let (px, py) = (x + j, y + i as u32);
+                        if (py >= height || px >= width) && !self.quirks.wrap {
+                            // Clipped pixels only collide with the quirk
+                            if bit == 1 && self.quirks.clip_collision {
+                                self.v_reg[0xf] = 1;
+                            }
+                            continue;
+                        }
+                        let prev = screen.draw_pixel((px % width) as u8, (py % height) as u8, bit);
                         if prev == 1 && bit == 1 {
                             self.v_reg[0xf] = 1;
                         }
@@ -402,343 +1793,1762 @@ impl Cpu {
                 }
             }
 
-            0xE000 => {
-                let screen = screen.unwrap();
-                match opcode & 0x00FF {
-                    // Skip next instruction if key VX is pressed
-                    0x9E => {
-                        let x = (opcode & 0x0F00) >> 8;
-                        if screen.is_key_pressed(self.v_reg[x as usize]) {
-                            trace!("Key V{} is pressed", x);
-                            self.pc += 2;
-                        }
-                    },
+            0xE000 => {
+                let screen = screen.ok_or(CpuError::DisplayRequired(opcode))?;
+                match opcode & 0x00FF {
+                    // Skip next instruction if key VX is pressed
+                    0x9E => {
+                        let x = (opcode & 0x0F00) >> 8;
+                        if screen.is_key_pressed(self.v_reg[x as usize] & 0xF) {
+                            trace!("Key V{} is pressed", x);
+                            self.skip();
+                        }
+                    },
+
+                    // Skip next instruction if key VX is not pressed
+                    0xA1 => {
+                        let x = (opcode & 0x0F00) >> 8;
+                        if !screen.is_key_pressed(self.v_reg[x as usize] & 0xF) {
+                            trace!("Key V{} is not pressed", x);
+                            self.skip();
+                        }
+                    },
+
+                    _ => self.unknown_opcode(opcode)?,
+                }
+            }
+
+            0xF000 => {
+                match opcode & 0x00FF {
+                    // Set index to the 16 bit address in the next two bytes
+                    // (XO-CHIP)
+                    0x00 if opcode == 0xF000 => {
+                        self.check_range(self.pc, 2)?;
+                        self.index = (self.read_ram(self.pc) as u16) << 8
+                            | self.read_ram(self.pc.wrapping_add(1)) as u16;
+                        trace!("Setting index to 0x{:x}", self.index);
+                        self.pc = self.pc.wrapping_add(2);
+                    },
+                    // Load the 16 bytes at index into the audio pattern
+                    // (XO-CHIP)
+                    0x02 if opcode == 0xF002 => {
+                        trace!("Loading the audio pattern from 0x{:x}", self.index);
+                        self.check_range(self.index, 16)?;
+                        let mut pattern = [0; 16];
+                        for (i, byte) in pattern.iter_mut().enumerate() {
+                            *byte = self.read_ram(self.index.wrapping_add(i as u16));
+                        }
+                        self.pattern_buffer = Some(pattern);
+                    },
+                    // Set the pitch of the audio pattern to VX (XO-CHIP)
+                    0x3A => {
+                        let x = (opcode & 0x0F00) >> 8;
+                        trace!("Setting the pitch to V{}", x);
+                        self.pitch = self.v_reg[x as usize];
+                    },
+                    // Set VX = delay timer
+                    0x07 => {
+                        trace!("Setting V{} = delay timer", (opcode & 0x0F00) >> 8);
+                        let x = (opcode & 0x0F00) >> 8;
+                        self.v_reg[x as usize] = self.delay_timer;
+                    },
+                    // Set delay timer = VX
+                    0x15 => {
+                        trace!("Setting delay timer = V{}", (opcode & 0x0F00) >> 8);
+                        let x = (opcode & 0x0F00) >> 8;
+                        self.delay_timer = self.v_reg[x as usize];
+                    },
+                    // Set sound timer = VX
+                    0x18 => {
+                        trace!("Setting sound timer = V{}", (opcode & 0x0F00) >> 8);
+                        let x = (opcode & 0x0F00) >> 8;
+                        self.sound_timer = self.v_reg[x as usize];
+                    },
+                    // Set index = index + VX 
+                    0x1E => {
+                        let x = (opcode & 0x0F00) >> 8;
+                        trace!("Setting index = index + V{}", x);
+
+                        self.index = self.index.wrapping_add(self.v_reg[x as usize] as u16);
+                        if self.quirks.index_overflow_sets_vf {
+                            self.v_reg[0xF] = (self.index > 0x0FFF) as u8;
+                        }
+                    },
+                    0x29 => {
+                        let x = (opcode & 0x0F00) >> 8;
+                        trace!("Setting index = sprite address of V{}", x);
+                        // Only the low nibble selects the digit, like the
+                        // original interpreter
+                        self.index = self.font_address(self.v_reg[x as usize]);
+                    },
+                    0x33 => {
+                        let x = (opcode & 0x0F00) >> 8;
+                        trace!("Storing BCD representation of V{} in memory", x);
+                        let vx = self.v_reg[x as usize];
+                        self.check_range(self.index, 3)?;
+                        self.write_ram(self.index, vx / 100);
+                        self.write_ram(self.index.wrapping_add(1), (vx / 10) % 10);
+                        self.write_ram(self.index.wrapping_add(2), vx % 10);
+                    },
+                    // Store v_reg[0]..v_reg[x] in memory starting at index
+                    0x55 => {
+                        let x = ((opcode & 0x0F00) >> 8) as u8;
+                        trace!("Storing v_reg[0]..v_reg[{}] in memory starting at index", x);
+                        self.store_registers(0, x)?;
+                    },
+                    // Read v_reg[0]..v_reg[x] from memory starting at index
+                    0x65 => {
+                        let x = ((opcode & 0x0F00) >> 8) as u8;
+                        trace!("Reading v_reg[0]..v_reg[{}] from memory starting at index", x);
+                        self.load_registers(0, x)?;
+                    },
+
+                    // Wait for a key press and release, then store the key in VX
+                    0x0A => {
+                        let screen = screen.ok_or(CpuError::DisplayRequired(opcode))?;
+                        let x = (opcode & 0x0F00) >> 8;
+                        let addr = self.pc.wrapping_sub(2);
+
+                        // The instruction is repeated until the key is
+                        // released, forget keys tracked by another FX0A
+                        let tracked = match self.last_key {
+                            Some((wait_addr, key)) if wait_addr == addr => Some(key),
+                            _ => None,
+                        };
+
+                        match tracked {
+                            // The tracked key has been released
+                            Some(key) if !screen.is_key_pressed(key) => {
+                                trace!("Key {:X} released, storing it in V{}", key, x);
+                                self.v_reg[x as usize] = key;
+                                self.last_key = None;
+                            },
+                            // The tracked key is still held
+                            Some(_) => self.pc = addr,
+                            // Start tracking the key pressed last, the one
+                            // meant when a key is still held from before
+                            None => {
+                                self.pc = addr;
+                                self.last_key = screen.get_last_key_pressed()
+                                    .map(|key| (addr, key));
+                            }
+                        }
+                    }
+
+                    _ => self.unknown_opcode(opcode)?,
+                }
+            }
+            
+            _ => self.unknown_opcode(opcode)?,
+        }
+
+        Ok(())
+    }
+
+    // Skips the next instruction, the four bytes of F000 NNNN are skipped
+    // whole
+    fn skip(&mut self) {
+        let byte = |addr: u16| self.ram.get(self.ram_address(addr)).copied();
+        let long = byte(self.pc) == Some(0xF0) && byte(self.pc.wrapping_add(1)) == Some(0x00);
+        self.pc = self.pc.wrapping_add(if long { 4 } else { 2 });
+    }
+
+    // Fetches the next opcode from the memory and advances the program counter
+    fn fetch(&mut self) -> u16 {
+        let mut opcode: u16 = self.read_ram(self.pc).into();
+        opcode = opcode << 8 | self.read_ram(self.pc.wrapping_add(1)) as u16;
+        self.pc = self.pc.wrapping_add(2);
+
+        opcode
+    }
+
+    
+}
+
+#[cfg(test)]
+mod test {
+    use crate::display::Display;
+    use crate::timing::Clock;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    // Display keeping only the keypad state, for the key opcodes
+    struct Keypad {
+        keys: [bool; 16],
+    }
+
+    impl Display for Keypad {
+        fn clear(&mut self) {}
+
+        fn draw_pixel(&mut self, _x: u8, _y: u8, _bit: u8) -> u8 {
+            0
+        }
+
+        fn is_key_pressed(&self, key_value: u8) -> bool {
+            self.keys[key_value as usize]
+        }
+
+        fn get_key_pressed(&self) -> Option<u8> {
+            (0..16).find(|key| self.keys[*key as usize])
+        }
+
+        fn resize(&mut self, _width: u32, _height: u32) {}
+    }
+
+    // Clock moving only when advanced, shared with the CPU it is given to
+    #[derive(Clone, Default)]
+    struct FakeClock {
+        now: Rc<Cell<Duration>>,
+    }
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            self.now.set(self.now.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.now.get()
+        }
+    }
+
+    fn cpu_with_fake_clock() -> (super::Cpu, FakeClock) {
+        let clock = FakeClock::default();
+        let mut cpu = super::Cpu::new();
+        cpu.set_clock(Box::new(clock.clone()));
+        (cpu, clock)
+    }
+
+    #[test]
+    fn jump() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x10,0x01]).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x0001);
+    }
+    
+    #[test]
+    fn set_vx() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60,0x01]).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x01);
+    }
+    
+    #[test]
+    fn add_vx() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x02);
+    }
+
+    #[test]
+    fn set_index() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0xA0, 0x01]).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.index, 0x0001);
+    }
+
+    #[test]
+    fn call_sub() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x20, 0x01]).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.sp, 0x0002);
+        assert_eq!(cpu.ram[0x0000], 0x02);
+        assert_eq!(cpu.ram[0x0001], 0x02);
+    }
+
+    #[test]
+    fn ret_sub() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x22, 0x02, 0x00, 0xEE]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x0202);
+        assert_eq!(cpu.sp, 0x0000);
+    }
+
+    #[test]
+    fn skip_vx_eq_nn() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x30, 0x01]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn skip_vx_neq_nn() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x40, 0x02]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn skip_vx_eq_vy() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x01, 0x50, 0x10]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x208);
+    }
+
+    #[test]
+    fn skip_vx_neq_vy() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x90, 0x10]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x208);
+    }
+
+    #[test]
+    fn set_vx_vy() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x10]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x02);
+    }
+
+    #[test]
+    fn set_vx_vx_or_vy() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x11]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x03);
+    }
+
+    #[test]
+    fn set_vx_vx_and_vy() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x12]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x00);
+    }
+
+    #[test]
+    fn set_vx_vx_xor_vy() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x13]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x03);
+    }
+
+    #[test]
+    fn add_vx_vy_carry() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0x61, 0xFF, 0x80, 0x14]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+
+        assert_eq!(cpu.v_reg[0], 0x00);
+        assert_eq!(cpu.v_reg[0xF], 0x01);
+    }
+
+    #[test]
+    fn sub_vx_vy_carry() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x61, 0xFF, 0x60, 0x01, 0x80, 0x15]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+
+        assert_eq!(cpu.v_reg[0], 0x02);
+        assert_eq!(cpu.v_reg[0xF], 0x00);
+    }
+
+    #[test]
+    fn sub_vy_vx_carry() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0xFF, 0x61, 0x01, 0x80, 0x17]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+
+        assert_eq!(cpu.v_reg[0], 0x02);
+        assert_eq!(cpu.v_reg[0xF], 0x00);
+    }
+
+    #[test]
+    fn set_vx_vy_shr() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x61, 0x03, 0x80, 0x16]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+
+        assert_eq!(cpu.v_reg[0], 0x01);
+        assert_eq!(cpu.v_reg[0xF], 0x01);
+    }
+
+    #[test]
+    fn set_vx_vy_shl() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x61, 0x80, 0x80, 0x1E]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+
+        assert_eq!(cpu.v_reg[0], 0x00);
+        assert_eq!(cpu.v_reg[0xF], 0x01);
+    }
+
+    #[test]
+    fn jump_with_offset() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0xB0, 0x01]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+
+        assert_eq!(cpu.pc, 0x0002);
+    }
+    
+    #[test]
+    fn add_idx_vx() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x01, 0xA0, 0x01, 0xF0, 0x1E]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+
+        assert_eq!(cpu.index, 0x0002);
+        assert_eq!(cpu.v_reg[0xF], 0x00);
+    }
+
+    #[test]
+    fn store_load_all_registers() {
+        let mut cpu = super::Cpu::new();
+        // Store V0..VF at 0x300, then load them back at I = 0x310
+        cpu.load_rom(&[0xA3, 0x00, 0xFF, 0x55, 0xFF, 0x65]).unwrap();
+        for i in 0..16 {
+            cpu.v_reg[i] = 0x10 + i as u8;
+        }
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.read_range(0x300, 16), (0x10..0x20).collect::<Vec<u8>>());
+        assert_eq!(cpu.index, 0x310);
+
+        cpu.write_ram(0x310, 0xAA);
+        cpu.write_ram(0x31F, 0xBB);
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0xAA);
+        assert_eq!(cpu.v_reg[0xF], 0xBB);
+        assert_eq!(cpu.index, 0x320);
+
+        // A range not starting at V0, index left in place without the quirk
+        cpu.quirks.memory_increment = false;
+        cpu.index = 0x300;
+        cpu.load_registers(4, 6).unwrap();
+        assert_eq!(&cpu.v_reg[4..7], &[0x10, 0x11, 0x12]);
+        cpu.store_registers(0, 0).unwrap();
+        assert_eq!(cpu.ram[0x300], 0xAA);
+        assert_eq!(cpu.index, 0x300);
+    }
+
+    #[test]
+    fn sys_calls() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x01, 0x23, 0x0F, 0xE0, 0x60, 0x01]).unwrap();
+        let before = cpu.registers();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.registers(), super::CpuSnapshot { pc: 0x202, ..before });
+        assert!(cpu.sys_logged);
+
+        // Only 00E0 clears the screen, no display needed for 0FE0
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+
+        cpu.reset();
+        assert!(!cpu.sys_logged);
+        cpu.set_strict(true);
+        assert!(matches!(cpu.step(None), Err(super::CpuError::UnsupportedSys(0x0123))));
+    }
+
+    #[test]
+    fn unknown_opcodes() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x81, 0x2F, 0x60, 0x01]).unwrap();
+        let before = cpu.registers();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.registers(), super::CpuSnapshot { pc: 0x202, ..before });
+
+        cpu.reset();
+        cpu.set_strict(true);
+        assert!(matches!(cpu.step(None), Err(super::CpuError::UnknownOpcode(0x812F))));
+        cpu.pc = 0x202;
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 1);
+    }
+
+    #[test]
+    fn register_ranges() {
+        let mut cpu = super::Cpu::new();
+        // Store V2..V5 at 0x300, load them back into V5..V2 (reversed)
+        cpu.load_rom(&[0xA3, 0x00, 0x52, 0x52, 0x55, 0x23, 0x56, 0x71]).unwrap();
+        cpu.v_reg[2..6].copy_from_slice(&[0x11, 0x22, 0x33, 0x44]);
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.read_range(0x2FF, 6), vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x00]);
+        assert_eq!(cpu.index, 0x300);
+
+        cpu.step(None).unwrap();
+        assert_eq!(&cpu.v_reg[2..6], &[0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(cpu.index, 0x300);
+
+        // 5XY1 is not an instruction, it doesn't skip though V6 == V7
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc, 0x208);
+
+        // Bounds checked, a single register when X == Y
+        cpu.index = 0xFFF;
+        cpu.store_register_range(7, 7).unwrap();
+        assert!(matches!(cpu.store_register_range(7, 8), Err(super::CpuError::MemoryOutOfBounds(_))));
+        assert!(matches!(cpu.load_register_range(8, 7), Err(super::CpuError::MemoryOutOfBounds(_))));
+    }
+
+    #[test]
+    fn memory_instructions_out_of_bounds() {
+        use super::CpuError;
+
+        // BCD at 0xFFE would write 0x1000
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0xFF, 0xAF, 0xFE, 0xF0, 0x33]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert!(matches!(cpu.step(None), Err(CpuError::MemoryOutOfBounds(0x1000))));
+        assert_eq!(cpu.pc(), 0x204);
+        assert_eq!(cpu.ram[0xFFE], 0);
+
+        // Storing and loading past the end of the RAM, nothing is stored
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0xAF, 0xFC, 0xF7, 0x55, 0xF7, 0x65, 0xF3, 0x55]).unwrap();
+        cpu.step(None).unwrap();
+        assert!(matches!(cpu.step(None), Err(CpuError::MemoryOutOfBounds(0x1000))));
+        assert_eq!(cpu.ram[0xFFC], 0);
+        cpu.pc = 0x204;
+        assert!(matches!(cpu.step(None), Err(CpuError::MemoryOutOfBounds(0x1000))));
+        cpu.pc = 0x206;
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.index, 0x1000);
+
+        // Past the end with I already out of the RAM
+        cpu.pc = 0x206;
+        assert!(matches!(cpu.step(None), Err(CpuError::MemoryOutOfBounds(0x1000))));
+
+        // A sprite of 3 rows at 0xFFF fails before drawing, one ending at the
+        // last byte of the RAM is drawn
+        let mut screen = crate::display::HeadlessScreen::new();
+        let mut cpu = super::Cpu::new();
+        cpu.ram[0xFFD..].fill(0xFF);
+        cpu.load_rom(&[0xAF, 0xFF, 0xD0, 0x03, 0xAF, 0xFD, 0xD0, 0x03]).unwrap();
+        cpu.step(None).unwrap();
+        assert!(matches!(cpu.step(Some(&mut screen)), Err(CpuError::MemoryOutOfBounds(0x1000))));
+        assert_eq!(screen.framebuffer().lit_pixels().count(), 0);
+        cpu.pc = 0x204;
+        cpu.step(None).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(screen.framebuffer().lit_pixels().count(), 24);
+    }
+
+    #[test]
+    fn wrap_memory() {
+        let mut cpu = super::Cpu::new();
+        cpu.quirks.wrap_memory = true;
+        cpu.write_ram(0x1000, 0x42);
+        assert_eq!(cpu.read_ram(0x000), 0x42);
+
+        // V0 and V1 stored at 0xFFF and 0x000, then drawn from there
+        let mut screen = crate::display::HeadlessScreen::new();
+        cpu.load_rom(&[0x60, 0xF0, 0x61, 0xF0, 0xAF, 0xFF, 0xF1, 0x55, 0xAF, 0xFF, 0xD2, 0x22]).unwrap();
+        for _ in 0..6 {
+            cpu.step(Some(&mut screen)).unwrap();
+        }
+        assert_eq!(cpu.read_range(0xFFF, 1), vec![0xF0]);
+        assert_eq!(cpu.read_range(0x000, 1), vec![0xF0]);
+        assert_eq!(screen.row_bits(1), 0x0F);
+        assert_eq!(cpu.v_reg[0xF], 0);
+    }
+
+    #[test]
+    fn wrap_memory_index_increment() {
+        // The registers stored from I = 0xFFF8 wrap around the 16 bits of I
+        let mut cpu = super::Cpu::new();
+        cpu.quirks.wrap_memory = true;
+        cpu.quirks.memory_increment = true;
+        cpu.load_rom(&[0xFF, 0x55]).unwrap();
+        cpu.v_reg[0xF] = 0x42;
+        cpu.index = 0xFFF8;
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.index(), 0x0008);
+        assert_eq!(cpu.read_range(0x007, 1), vec![0x42]);
+    }
+
+    #[test]
+    fn long_index() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0xF0, 0x00, 0x0A, 0xBC, 0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x60, 0x01]).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.index(), 0x0ABC);
+        assert_eq!(cpu.pc(), 0x204);
+
+        // Skipped whole
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc(), 0x20A);
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x01);
+        assert_eq!(cpu.index(), 0x0ABC);
+    }
+
+    #[test]
+    fn audio_pattern() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0xA2, 0x0A, 0xF0, 0x02, 0x60, 0x70, 0xF0, 0x3A, 0x12, 0x08,
+            0xFF, 0x00, 0xAA, 0x55, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0xF0]).unwrap();
+        assert_eq!(cpu.audio_pattern(), None);
+        assert_eq!(cpu.pitch(), super::DEFAULT_PITCH);
+
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.audio_pattern().unwrap(), &cpu.read_range(0x20A, 16)[..]);
+        assert_eq!(cpu.audio_pattern().unwrap()[15], 0xF0);
+
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pitch(), 0x70);
+
+        cpu.reset();
+        assert_eq!(cpu.audio_pattern(), None);
+        assert_eq!(cpu.pitch(), super::DEFAULT_PITCH);
+
+        // The pattern must fit in the memory
+        cpu.load_rom(&[0xAF, 0xF8, 0xF0, 0x02]).unwrap();
+        cpu.step(None).unwrap();
+        assert!(matches!(cpu.step(None), Err(super::CpuError::MemoryOutOfBounds(0x1000))));
+    }
+
+    #[test]
+    fn stack_and_pc_out_of_bounds() {
+        use super::CpuError;
+        let mut screen = crate::display::HeadlessScreen::new();
+
+        // Returning without a call
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x00, 0xEE]).unwrap();
+        assert!(matches!(cpu.step(None), Err(CpuError::StackUnderflow)));
+        assert_eq!(cpu.pc(), 0x200);
+
+        // Calling itself fills the stack and fails one level past its depth
+        for depth in [super::DEFAULT_STACK_DEPTH, 12, 2] {
+            let mut cpu = super::Cpu::new();
+            cpu.set_stack_depth(depth).unwrap();
+            cpu.load_rom(&[0x22, 0x00]).unwrap();
+            assert_eq!(cpu.run_steps(depth as u64, None).unwrap(), depth as u64);
+            assert!(matches!(cpu.step(None), Err(CpuError::StackOverflow(d)) if d == depth));
+            assert_eq!(cpu.sp, 2 * depth);
+        }
+        assert_eq!(super::Cpu::new_for(super::Machine::CosmacVip).stack_depth(), 12);
+        assert!(matches!(super::Cpu::new().set_stack_depth(0), Err(CpuError::InvalidStackDepth(0))));
+        assert!(matches!(super::Cpu::new().set_stack_depth(41), Err(CpuError::InvalidStackDepth(41))));
+        assert!(super::Cpu::new().set_stack_depth(40).is_ok());
+
+        // Running off the end of the memory, the last opcode is at 0xFFE
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x1F, 0xFF]).unwrap();
+        cpu.step(None).unwrap();
+        assert!(matches!(cpu.step(None), Err(CpuError::MemoryOutOfBounds(0x1000))));
+        assert_eq!(cpu.pc(), 0xFFF);
+
+        // Keys above 0xF use the low nibble
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x15, 0xE0, 0x9E, 0xE0, 0xA1]).unwrap();
+        screen.set_key(0x5, true);
+        for _ in 0..2 {
+            cpu.step(Some(&mut screen)).unwrap();
+        }
+        assert_eq!(cpu.pc(), 0x206);
+    }
+
+    #[test]
+    fn font_addresses() {
+        use super::{Cpu, Machine, START_FONT};
+
+        let cpu = Cpu::new();
+        assert_eq!(cpu.font_address(0x0), START_FONT);
+        assert_eq!(cpu.font_address(0xF), START_FONT + 75);
+        assert_eq!(cpu.font_address(0x1A), START_FONT + 50);
+        assert_eq!(Cpu::new_for(Machine::Eti660).font_address(0x1), 0x105);
+    }
+
+    #[test]
+    fn memory_regions() {
+        use super::{Cpu, Machine, START_FONT, START_PGM};
+
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x00, 0xE0, 0x12, 0x02]).unwrap();
+        let start = START_PGM as usize;
+        let font = START_FONT as usize;
+        assert_eq!(cpu.memory_regions(), vec![
+            (0..font, "reserved"),
+            (font..font + 80, "font"),
+            (font + 80..start, "reserved"),
+            (start..start + 4, "program"),
+            (start + 4..4096, "free"),
+        ]);
+
+        // The regions follow the machine, without a ROM
+        let cpu = Cpu::new_for(Machine::Eti660);
+        let regions = cpu.memory_regions();
+        assert_eq!(regions[1], (0x100..0x150, "font"));
+        assert_eq!(regions[2], (0x150..0x600, "reserved"));
+        assert_eq!(regions[3], (0x600..4096, "free"));
+
+        // A program can't be loaded over the fontset
+        assert!(matches!(Cpu::with_start_address(0x60), Err(super::CpuError::InvalidStartAddress(0x60))));
+    }
+
+    #[test]
+    fn hex_dump() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(b"\x00\xe0Hello, CHIP-8!\x12\x02").unwrap();
+        assert_eq!(cpu.hex_dump(0x200, 20),
+            "0200  00 e0 48 65 6c 6c 6f 2c  20 43 48 49 50 2d 38 21  |..Hello, CHIP-8!|\n\
+             0210  12 02 00 00                                       |....|\n");
+
+        // Lines start at start, the RAM ends the dump
+        assert_eq!(cpu.hex_dump(0x203, 2), "0203  65 6c                                             |el|\n");
+        assert_eq!(cpu.hex_dump(0xFFE, 16), "0ffe  00 00                                             |..|\n");
+        assert_eq!(cpu.hex_dump(0x1000, 16), "");
+    }
+
+    #[test]
+    fn custom_font() {
+        use super::{CpuError, FONTSET, FONT_SIZE};
+
+        let mut cpu = super::Cpu::new();
+        let mut font = FONTSET;
+        font[5..10].copy_from_slice(&[0x40, 0xC0, 0x40, 0x40, 0xE0]);
+        cpu.set_font(&font).unwrap();
+        assert_eq!(cpu.read_range(0x55, 5), vec![0x40, 0xC0, 0x40, 0x40, 0xE0]);
+
+        // Kept when loading a ROM, at the address of the machine
+        cpu.load_rom(&[0x60, 0x01, 0xF0, 0x29]).unwrap();
+        assert_eq!(cpu.read_range(0x50, FONT_SIZE), font.to_vec());
+        let mut cpu = super::Cpu::new_for(super::Machine::Eti660);
+        cpu.set_font(&font).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.read_range(0x105, 1), vec![0x40]);
+
+        assert!(matches!(cpu.set_font(&font[..79]), Err(CpuError::InvalidFont(79))));
+        assert_eq!(cpu.read_range(0x105, 1), vec![0x40]);
+    }
+
+    #[test]
+    fn load_rom_too_large() {
+        let mut cpu = super::Cpu::new();
+        let max = super::RAM_SIZE - super::START_PGM as usize;
+
+        assert!(cpu.load_rom(&vec![0xFF; max]).is_ok());
+        assert!(matches!(
+            cpu.load_rom(&vec![0xFF; max + 1]),
+            Err(super::CpuError::RomTooLarge { size, .. }) if size == max + 1
+        ));
+    }
+
+    #[test]
+    fn ram_size() {
+        use super::{CpuError, XOCHIP_RAM_SIZE};
+
+        let mut cpu = super::Cpu::new();
+        assert!(matches!(cpu.set_ram_size(1024), Err(CpuError::InvalidRamSize(1024))));
+        assert!(matches!(cpu.set_ram_size(XOCHIP_RAM_SIZE + 1), Err(CpuError::InvalidRamSize(_))));
+        cpu.set_ram_size(XOCHIP_RAM_SIZE).unwrap();
+
+        // A ROM not fitting in 4KB, storing V0 at 0x1000 from the code past
+        // it and jumping back
+        let mut rom = vec![0x00; 0xE0A];
+        rom[..2].copy_from_slice(&[0x1F, 0xFE]);
+        rom[0xDFE..].copy_from_slice(&[0x60, 0x42, 0xAF, 0xFF, 0x61, 0x01, 0xF1, 0x1E, 0xF0, 0x55, 0x12, 0x00]);
+        cpu.load_rom(&rom).unwrap();
+        for _ in 0..7 {
+            cpu.step(None).unwrap();
+        }
+        assert_eq!(cpu.pc(), 0x200);
+        assert_eq!(cpu.index(), 0x1001);
+        assert_eq!(cpu.read_memory(0x1000), 0x42);
+
+        // The ROM doesn't fit in 4KB any more
+        assert!(matches!(cpu.set_ram_size(super::RAM_SIZE), Err(CpuError::RomTooLarge { .. })));
+
+        // A program starting past the end of the new size doesn't fit at all
+        let mut cpu = super::Cpu::new();
+        cpu.set_ram_size(XOCHIP_RAM_SIZE).unwrap();
+        cpu.set_start_address(0x2000).unwrap();
+        cpu.load_rom(&[0x12, 0x00]).unwrap();
+        assert!(matches!(cpu.set_ram_size(super::RAM_SIZE), Err(CpuError::InvalidStartAddress(0x2000))));
+    }
+
+    #[test]
+    fn store_registers_top_of_ram() {
+        // The 16 registers fill the last bytes of the 64KB and I wraps to 0
+        let mut cpu = super::Cpu::new_for(super::Machine::XoChip);
+        cpu.load_rom(&[0xF0, 0x00, 0xFF, 0xF0, 0xFF, 0x55, 0xF0, 0x00, 0xFF, 0xF0, 0xFF, 0x65]).unwrap();
+        cpu.v_reg[0xF] = 0x42;
+        cpu.run_steps(2, None).unwrap();
+        assert_eq!(cpu.index(), 0x0000);
+        assert_eq!(cpu.read_memory(0xFFFF), 0x42);
+
+        cpu.v_reg[0xF] = 0;
+        cpu.run_steps(2, None).unwrap();
+        assert_eq!(cpu.index(), 0x0000);
+        assert_eq!(cpu.v_reg[0xF], 0x42);
+    }
+
+    #[test]
+    fn wait_key_top_of_ram() {
+        // The FX0A in the last two bytes of the 64KB waits in place
+        let mut cpu = super::Cpu::new();
+        cpu.set_ram_size(super::XOCHIP_RAM_SIZE).unwrap();
+        let mut rom = vec![0x00; 0xFE00];
+        rom[0xFDFE..].copy_from_slice(&[0xF0, 0x0A]);
+        cpu.load_rom(&rom).unwrap();
+        cpu.pc = 0xFFFE;
+
+        let mut keypad = Keypad { keys: [false; 16] };
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.pc(), 0xFFFE);
+        keypad.keys[0x4] = true;
+        cpu.step(Some(&mut keypad)).unwrap();
+        keypad.keys[0x4] = false;
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!((cpu.pc(), cpu.v_reg[0]), (0x0000, 0x4));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn load_rom_from_reader() {
+        let mut cpu = super::Cpu::new();
+        let mut rom = std::io::Cursor::new(vec![0x60, 0x05, 0x12, 0x02]);
+        cpu.load_rom_from_reader(&mut rom).unwrap();
+        assert_eq!(cpu.read_range(0x200, 4), vec![0x60, 0x05, 0x12, 0x02]);
+
+        let max = super::RAM_SIZE - super::START_PGM as usize;
+        let mut rom = std::io::Cursor::new(vec![0xFF; max * 2]);
+        assert!(matches!(
+            cpu.load_rom_from_reader(&mut rom),
+            Err(super::CpuError::RomTooLarge { .. })
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn load_rom_gzip() {
+        // printf '\x60\x05\x12\x02' | gzip -n
+        let gz = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x4b, 0x60,
+            0x15, 0x62, 0x02, 0x00, 0x0b, 0xfb, 0x53, 0x8a, 0x04, 0x00, 0x00, 0x00,
+        ];
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom_from_reader(&mut std::io::Cursor::new(gz)).unwrap();
+        assert_eq!(cpu.ram[0x200..0x204], [0x60, 0x05, 0x12, 0x02]);
+        assert_eq!(cpu.ram[0x204], 0);
+
+        let mut corrupted = gz;
+        corrupted[16] ^= 1;
+        assert!(matches!(
+            cpu.load_rom_from_reader(&mut std::io::Cursor::new(corrupted)),
+            Err(super::CpuError::InvalidRom(_))
+        ));
+    }
+
+    #[test]
+    fn cheat_pins_memory() {
+        let mut cpu = super::Cpu::new();
+        // Decrement the lives at 0x300 in a loop
+        cpu.load_rom(&[0xA3, 0x00, 0xF0, 0x65, 0x70, 0xFF, 0xF0, 0x55, 0x12, 0x00]).unwrap();
+        cpu.write_memory(0x300, 3).unwrap();
+        cpu.add_cheat(0x300, 9).unwrap();
+
+        for _ in 0..20 {
+            cpu.step(None).unwrap();
+            assert_eq!(cpu.read_memory(0x300), 9);
+        }
+        assert_eq!(cpu.v(0), 8);
 
-                    // Skip next instruction if key VX is not pressed
-                    0xA1 => {
-                        let x = (opcode & 0x0F00) >> 8;
-                        if !screen.is_key_pressed(self.v_reg[x as usize]) {
-                            trace!("Key V{} is not pressed", x);
-                            self.pc += 2;
-                        }
-                    },
+        assert!(cpu.add_cheat(0x1000, 1).is_err());
+    }
 
-                    _ => warn!("Operation 0x{:x} is not implemented yet!", opcode),
-                }
-            }
+    #[test]
+    fn watch_memory_write() {
+        use std::cell::RefCell;
 
-            0xF000 => {
-                match opcode & 0x00FF {
-                    // Set VX = delay timer
-                    0x07 => {
-                        trace!("Setting V{} = delay timer", (opcode & 0x0F00) >> 8);
-                        let x = (opcode & 0x0F00) >> 8;
-                        self.v_reg[x as usize] = self.delay_timer;
-                    },
-                    // Set delay timer = VX
-                    0x15 => {
-                        trace!("Setting delay timer = V{}", (opcode & 0x0F00) >> 8);
-                        let x = (opcode & 0x0F00) >> 8;
-                        self.delay_timer = self.v_reg[x as usize];
-                    },
-                    // Set sound timer = VX
-                    0x18 => {
-                        trace!("Setting sound timer = V{}", (opcode & 0x0F00) >> 8);
-                        let x = (opcode & 0x0F00) >> 8;
-                        self.sound_timer = self.v_reg[x as usize];
-                    },
-                    // Set index = index + VX 
-                    0x1E => {
-                        let x = (opcode & 0x0F00) >> 8;
-                        trace!("Setting index = index + V{}", x);
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0xA3, 0x00, 0x60, 0x07, 0x61, 0x08, 0xF1, 0x55]).unwrap();
+        cpu.write_memory(0x301, 0x42).unwrap();
+        cpu.watch_memory(0x301);
 
-                        self.index = self.index.wrapping_add(self.v_reg[x as usize] as u16);
-                    },
-                    0x29 => {
-                        let x = (opcode & 0x0F00) >> 8;
-                        trace!("Setting index = sprite address of V{}", x);
-                        self.index = START_FONT+(self.v_reg[x as usize]*5) as u16;
-                    },
-                    0x33 => {
-                        let x = (opcode & 0x0F00) >> 8;
-                        trace!("Storing BCD representation of V{} in memory", x);
-                        self.ram[self.index as usize] = self.v_reg[x as usize] / 100;
-                        self.ram[(self.index+1) as usize] = (self.v_reg[x as usize] / 10) % 10;
-                        self.ram[(self.index+2) as usize] = self.v_reg[x as usize] % 10;
-                    },
-                    // Store v_reg[0]..v_reg[x] in memory starting at index
-                    0x55 => {
-                        let x = (opcode & 0x0F00) >> 8;
-                        trace!("Storing v_reg[0]..v_reg[{}] in memory starting at index", x);
-                        for i in 0..x+1 {
-                            self.ram[(self.index) as usize] = self.v_reg[i as usize];
-                            self.index += 1;
-                        }
-                    },
-                    // Read v_reg[0]..v_reg[x] from memory starting at index
-                    0x65 => {
-                        let x = (opcode & 0x0F00) >> 8;
-                        trace!("Reading v_reg[0]..v_reg[{}] from memory starting at index", x);
-                        for i in 0..x+1 {
-                            self.v_reg[i as usize] = self.ram[(self.index) as usize];
-                            self.index += 1;
-                        }
-                    },
+        let writes = Rc::new(RefCell::new(vec![]));
+        let seen = writes.clone();
+        cpu.set_watch_callback(Box::new(move |write| seen.borrow_mut().push(write)));
 
-                    0x0A => {
-                        let screen = screen.unwrap();
-                        let x = (opcode & 0x0F00) >> 8;
+        for _ in 0..4 {
+            cpu.step(None).unwrap();
+        }
+        assert_eq!(cpu.read_memory(0x300), 0x07);
+        assert_eq!(*writes.borrow(), vec![super::MemoryWrite { pc: 0x206, addr: 0x301, old: 0x42, new: 0x08 }]);
+    }
 
-                        match self.last_key {
-                            Some(key) => {
-                                if !screen.is_key_pressed(key) {
-                                    self.v_reg[x as usize] = key;
-                                    self.last_key = None;
-                                } else {
-                                    self.pc -= 2;
-                                }
-                            },
-                            None => {
-                                self.pc -= 2;
-                                self.last_key = screen.get_key_pressed();
-                            }
-                        }
+    #[test]
+    fn frame_callback() {
+        let mut cpu = super::Cpu::new();
+        let mut screen = crate::display::HeadlessScreen::new();
+        // Clear the screen and draw a digit, then halt
+        cpu.load_rom(&[0x00, 0xE0, 0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x08]).unwrap();
+
+        let frames = Rc::new(Cell::new(0));
+        let counted = frames.clone();
+        cpu.on_frame(Box::new(move |cpu| {
+            assert!(cpu.has_drawn());
+            counted.set(counted.get() + 1);
+        }));
+
+        for _ in 0..10 {
+            cpu.step(Some(&mut screen)).unwrap();
+        }
+        assert!(cpu.is_halted());
+        assert_eq!(frames.get(), 2);
+    }
 
-                    }
+    #[test]
+    fn step_outcome() {
+        let mut cpu = super::Cpu::new();
+        let mut screen = crate::display::HeadlessScreen::new();
+        cpu.load_rom(&[0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0xF0, 0x18, 0xF0, 0x18, 0x12, 0x0A]).unwrap();
+
+        let outcome = cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(outcome, super::StepOutcome { opcode: Some(0x6005), ..Default::default() });
+        cpu.step(Some(&mut screen)).unwrap();
+
+        // Draw
+        let outcome = cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(outcome.opcode, Some(0xD005));
+        assert!(outcome.drawn && !outcome.sound_changed);
+
+        // Sound, started once
+        let outcome = cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(outcome.opcode, Some(0xF018));
+        assert!(!outcome.drawn && outcome.sound_changed);
+        assert!(!cpu.step(Some(&mut screen)).unwrap().sound_changed);
+
+        // Halt
+        assert!(cpu.step(Some(&mut screen)).unwrap().halted);
+        let outcome = cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(outcome, super::StepOutcome { halted: true, ..Default::default() });
+    }
 
-                    _ => warn!("Operation 0x{:x} is not implemented yet!", opcode),
-                }
-            }
-            
-            _ => warn!("Operation {opcode} is not implemented yet!"),
+    #[test]
+    fn load_rom_empty() {
+        let mut cpu = super::Cpu::new();
+        assert!(matches!(cpu.load_rom(&[]), Err(super::CpuError::InvalidRom(_))));
+    }
+
+    #[test]
+    fn load_rom_odd_length() {
+        let mut cpu = super::Cpu::new();
+        assert!(cpu.load_rom(&[0x60]).is_ok());
+        assert_eq!(cpu.ram[0x200], 0x60);
+    }
+
+    #[test]
+    fn load_rom_start_address() {
+        let mut cpu = super::Cpu::with_start_address(0x600).unwrap();
+        cpu.load_rom(&[0x60, 0x01]).unwrap();
+        assert_eq!(cpu.pc, 0x600);
+        assert_eq!(cpu.ram[0x600], 0x60);
+        assert_eq!(cpu.ram[0x601], 0x01);
+        assert_eq!(cpu.ram[0x200], 0x00);
+
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.v_reg[0], 0x01);
+
+        // Only RAM_SIZE - 0x600 bytes fit after the start address
+        let max = super::RAM_SIZE - 0x600;
+        assert!(matches!(
+            cpu.load_rom(&vec![0xFF; max + 1]),
+            Err(super::CpuError::RomTooLarge { max: m, .. }) if m == max
+        ));
+
+        // The start address must leave the stack and the fontset alone and
+        // be inside the RAM
+        let font_end = super::START_FONT + super::FONT_SIZE as u16;
+        for addr in [0x10, font_end - 1, super::RAM_SIZE as u16] {
+            assert!(matches!(
+                super::Cpu::with_start_address(addr),
+                Err(super::CpuError::InvalidStartAddress(a)) if a == addr
+            ));
         }
+        assert_eq!(super::Cpu::with_start_address(font_end).unwrap().pc(), font_end);
 
+        // A loaded ROM that doesn't fit after the new address is kept
+        assert!(matches!(cpu.set_start_address(0xFFF), Err(super::CpuError::RomTooLarge { size: 2, max: 1 })));
+        assert_eq!(cpu.pc(), 0x602);
     }
 
-    // Fetches the next opcode from the memory and advances the program counter
-    fn fetch(&mut self) -> u16 {
-        let mut opcode: u16 = self.ram[self.pc as usize].into();
-        opcode = opcode << 8 | self.ram[(self.pc+1) as usize] as u16;
-        self.pc += 2;
+    #[test]
+    fn registers_snapshot() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x60, 0x05, 0x6F, 0x07, 0xA1, 0x23, 0xF0, 0x15]).unwrap();
+        for _ in 0..4 {
+            cpu.step(None).unwrap();
+        }
 
-        opcode
+        assert_eq!(cpu.pc(), 0x208);
+        assert_eq!(cpu.sp(), 0);
+        assert_eq!(cpu.index(), 0x123);
+        assert_eq!(cpu.v(0x0), 0x05);
+        assert_eq!(cpu.v(0xF), 0x07);
+        assert_eq!(cpu.delay_timer(), 0x05);
+
+        let snapshot = cpu.registers();
+        assert_eq!(snapshot.pc, cpu.pc());
+        assert_eq!(snapshot.index, cpu.index());
+        assert_eq!(snapshot.v_reg, cpu.v_reg);
+        assert_eq!(snapshot.delay_timer, 0x05);
+        assert_eq!(snapshot.sound_timer, 0x00);
     }
 
-    
-}
+    #[test]
+    fn memory_access() {
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x12, 0x34]).unwrap();
+
+        assert_eq!(cpu.read_memory(0x200), 0x12);
+        assert_eq!(cpu.read_range(0x200, 3), vec![0x12, 0x34, 0x00]);
+
+        cpu.write_memory(0xFFF, 0xAB).unwrap();
+        assert_eq!(cpu.read_memory(0xFFF), 0xAB);
+        assert_eq!(cpu.read_range(0xFFE, 4), vec![0x00, 0xAB]);
+    }
 
-#[cfg(test)]
-mod test {
     #[test]
-    fn jump() {
+    fn memory_access_out_of_bounds() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x10,0x01]);
-        cpu.step(None);
-        assert_eq!(cpu.pc, 0x0001);
+
+        assert_eq!(cpu.read_memory(0x1000), 0x00);
+        assert!(cpu.read_range(0x1000, 16).is_empty());
+        assert!(matches!(
+            cpu.write_memory(0x1000, 0xAB),
+            Err(super::CpuError::MemoryOutOfBounds(0x1000))
+        ));
     }
-    
+
     #[test]
-    fn set_vx() {
+    fn wait_key_release() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60,0x01]);
-        cpu.step(None);
-        assert_eq!(cpu.v_reg[0], 0x01);
+        let mut keypad = Keypad { keys: [false; 16] };
+        cpu.load_rom(&[0xF3, 0x0A]).unwrap();
+
+        // No key pressed
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+
+        // Pressed and held
+        keypad.keys[0x5] = true;
+        cpu.step(Some(&mut keypad)).unwrap();
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+
+        // Released
+        keypad.keys[0x5] = false;
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.v_reg[3], 0x5);
+        assert_eq!(cpu.pc, 0x202);
     }
-    
+
     #[test]
-    fn add_vx() {
+    fn wait_key_release_tracked_key() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01]);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.v_reg[0], 0x02);
+        let mut keypad = Keypad { keys: [false; 16] };
+        cpu.load_rom(&[0xF3, 0x0A]).unwrap();
+
+        // Two keys held, the first one is tracked
+        keypad.keys[0x3] = true;
+        keypad.keys[0x7] = true;
+        cpu.step(Some(&mut keypad)).unwrap();
+
+        // Releasing the other key doesn't end the wait
+        keypad.keys[0x7] = false;
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+
+        keypad.keys[0x3] = false;
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.v_reg[3], 0x3);
+        assert_eq!(cpu.pc, 0x202);
     }
 
     #[test]
-    fn set_index() {
+    fn wait_key_release_held_key_repeats() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0xA0, 0x01]);
-        cpu.step(None);
-        assert_eq!(cpu.index, 0x0001);
+        let mut screen = crate::display::HeadlessScreen::new();
+        cpu.load_rom(&[0xF3, 0x0A, 0xF4, 0x0A]).unwrap();
+
+        // Pressed again while held, as by a key repeat, and then released
+        screen.press_key(0x9).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        screen.press_key(0x9).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+        screen.release_key(0x9).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(cpu.v_reg[3], 0x9);
+
+        // A single key press ends a single wait
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(cpu.pc, 0x202);
     }
 
     #[test]
-    fn call_sub() {
+    fn count_instructions() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x20, 0x01]);
-        cpu.step(None);
-        assert_eq!(cpu.pc, 0x0001);
-        assert_eq!(cpu.sp, 0x0002);
-        assert_eq!(cpu.ram[0x0000], 0x02);
-        assert_eq!(cpu.ram[0x0001], 0x02);
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]).unwrap();
+        for _ in 0..10 {
+            cpu.step(None).unwrap();
+        }
+        assert_eq!(cpu.instructions_executed(), 10);
     }
 
     #[test]
-    fn ret_sub() {
+    fn reset() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x22, 0x02, 0x00, 0xEE]);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.pc, 0x0202);
-        assert_eq!(cpu.sp, 0x0000);
+        cpu.load_rom(&[0x60, 0x01, 0xA3, 0x00, 0xF0, 0x55]).unwrap();
+        for _ in 0..3 {
+            cpu.step(None).unwrap();
+        }
+        assert_eq!(cpu.ram[0x300], 0x01);
+
+        cpu.reset();
+        assert_eq!(cpu.instructions_executed(), 0);
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.index, 0);
+        assert_eq!(cpu.v_reg[0], 0);
+        assert_eq!(cpu.ram[0x300], 0x00);
+        assert_eq!(cpu.read_range(0x200, 2), vec![0x60, 0x01]);
+        assert_eq!(cpu.ram[0x50], 0xF0);
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn skip_vx_eq_nn() {
+    fn trace_file() {
+        let path = std::env::temp_dir().join("chip-8-trace-file.txt");
+        let file = std::fs::File::create(&path).unwrap();
+
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x30, 0x01]);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.pc, 0x206);
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01, 0xA3, 0x00, 0x12, 0x00]).unwrap();
+        cpu.set_trace(Box::new(std::io::BufWriter::new(file)));
+        for _ in 0..6 {
+            cpu.step(None).unwrap();
+        }
+        // Dropping the CPU flushes the trace
+        drop(cpu);
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "0200 6001 LD V0, 0x01      V0=01");
+        assert_eq!(lines[2], "0204 A300 LD I, 0x300      I=0300");
+        assert_eq!(lines[3], "0206 1200 JP 0x200");
+        assert_eq!(lines[5], "0202 7001 ADD V0, 0x01     V0=02");
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn skip_vx_neq_nn() {
+    fn opcode_log() {
+        // Writer appending to a buffer the test keeps
+        struct Shared(Rc<std::cell::RefCell<Vec<u8>>>);
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let log = Rc::new(std::cell::RefCell::new(vec![]));
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x40, 0x02]);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.pc, 0x206);
+        cpu.set_halt_on_loop(false);
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]).unwrap();
+        cpu.set_opcode_log(Box::new(Shared(log.clone())), 4);
+        cpu.run_steps(10, None).unwrap();
+
+        // Only the first 4 are logged
+        assert_eq!(String::from_utf8(log.borrow().clone()).unwrap(), concat!(
+            "0200: 6001 LD V0, 0x01\n",
+            "0202: 7001 ADD V0, 0x01\n",
+            "0204: 1202 JP 0x202\n",
+            "0202: 7001 ADD V0, 0x01\n",
+        ));
     }
 
     #[test]
-    fn skip_vx_eq_vy() {
+    fn seeded_random() {
+        // Ten CXFF with different X
+        let rom: Vec<u8> = (0..10).flat_map(|x| [0xC0 | x, 0xFF]).collect();
+
+        let mut first = super::Cpu::with_seed(42);
+        let mut second = super::Cpu::with_seed(42);
+        first.load_rom(&rom).unwrap();
+        second.load_rom(&rom).unwrap();
+        for _ in 0..10 {
+            first.step(None).unwrap();
+            second.step(None).unwrap();
+        }
+        assert_eq!(first.registers().v_reg, second.registers().v_reg);
+
+        // Resetting restarts the sequence
+        let v_reg = first.registers().v_reg;
+        first.reset();
+        for _ in 0..10 {
+            first.step(None).unwrap();
+        }
+        assert_eq!(first.registers().v_reg, v_reg);
+    }
+
+    #[test]
+    fn font_address() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x61, 0x01, 0x50, 0x10]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.pc, 0x208);
+        cpu.load_rom(&[0x60, 0x0F, 0xF0, 0x29, 0x61, 0xFF, 0xF1, 0x29]).unwrap();
+
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.index(), super::START_FONT + 75);
+
+        // Values above 0xF use their low nibble instead of overflowing
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.index(), super::START_FONT + 75);
     }
 
     #[test]
-    fn skip_vx_neq_vy() {
+    fn machines() {
+        use super::{Cpu, Machine, Quirks, MACHINES};
+
+        let cpu = Cpu::new_for(Machine::CosmacVip);
+        assert_eq!((cpu.pc(), cpu.start_address()), (0x200, 0x200));
+        assert_eq!(cpu.quirks(), Quirks::CHIP8);
+
+        let cpu = Cpu::new_for(Machine::Eti660);
+        assert_eq!((cpu.pc(), cpu.start_address()), (0x600, 0x600));
+        assert_eq!(cpu.quirks(), Quirks::CHIP8);
+
+        let cpu = Cpu::new_for(Machine::SuperChip);
+        assert_eq!((cpu.pc(), cpu.start_address()), (0x200, 0x200));
+        assert_eq!(cpu.quirks(), Quirks::SCHIP);
+        assert_eq!(cpu.ram_size(), 4096);
+
+        let cpu = Cpu::new_for(Machine::XoChip);
+        assert_eq!(cpu.quirks(), Quirks::XOCHIP);
+        assert_eq!(cpu.ram_size(), 0x10000);
+        assert_eq!(cpu.read_range(0x50, 1), vec![0xF0]);
+
+        // The fontset follows the machine
+        let mut cpu = Cpu::new_for(Machine::Eti660);
+        cpu.load_rom(&[0x60, 0x01, 0xF0, 0x29]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.index(), 0x105);
+        assert_eq!(cpu.read_range(0x105, 5), vec![0x20, 0x60, 0x20, 0x20, 0x70]);
+
+        assert!(MACHINES.iter().all(|name| Machine::from_name(name).is_some()));
+        assert_eq!(Machine::from_name("ETI660"), Some(Machine::Eti660));
+        assert_eq!(Machine::from_name("dream6800"), None);
+    }
+
+    #[test]
+    fn halt_on_self_jump() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x90, 0x10]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.pc, 0x208);
+        cpu.load_rom(&[0x60, 0x01, 0x12, 0x02]).unwrap();
+
+        cpu.step(None).unwrap();
+        assert!(!cpu.is_halted());
+        cpu.step(None).unwrap();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc(), 0x202);
+
+        // Halted CPUs don't execute anything
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.instructions_executed(), 2);
+
+        cpu.reset();
+        assert!(!cpu.is_halted());
+
+        // Unless halting is disabled
+        cpu.set_halt_on_loop(false);
+        for _ in 0..3 {
+            cpu.step(None).unwrap();
+        }
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.instructions_executed(), 3);
     }
 
     #[test]
-    fn set_vx_vy() {
+    fn display_wait() {
+        let mut keypad = Keypad { keys: [false; 16] };
+        let (mut cpu, clock) = cpu_with_fake_clock();
+        cpu.set_quirks(super::Quirks { display_wait: true, ..Default::default() });
+        cpu.load_rom(&[0xD0, 0x01, 0x60, 0x01]).unwrap();
+
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert!(cpu.is_waiting_display());
+
+        // Nothing runs until the next tick
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.pc(), 0x202);
+
+        clock.advance(Duration::from_millis(17));
+        cpu.update_timers();
+        assert!(!cpu.is_waiting_display());
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert_eq!(cpu.v(0), 1);
+    }
+
+    #[test]
+    fn display_required() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x10]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.v_reg[0], 0x02);
+        cpu.load_rom(&[0xD0, 0x15, 0xE0, 0x9E]).unwrap();
+
+        assert!(matches!(cpu.step(None), Err(super::CpuError::DisplayRequired(0xD015))));
+        assert_eq!(cpu.pc(), 0x200);
+        assert!(!cpu.has_drawn());
+        assert_eq!(cpu.instructions_executed(), 0);
+
+        // The opcode runs once a display is given
+        let mut keypad = Keypad { keys: [false; 16] };
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert!(matches!(cpu.step(None), Err(super::CpuError::DisplayRequired(0xE09E))));
+        assert_eq!(cpu.pc(), 0x202);
     }
 
     #[test]
-    fn set_vx_vx_or_vy() {
+    fn clear_screen_without_display() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x11]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.v_reg[0], 0x03);
+        cpu.load_rom(&[0x00, 0xE0, 0xF0, 0x0A]).unwrap();
+
+        assert!(matches!(cpu.step(None), Err(super::CpuError::DisplayRequired(0x00E0))));
+        assert!(!cpu.has_drawn());
+
+        // FX0A needs the keypad
+        let mut keypad = Keypad { keys: [false; 16] };
+        cpu.step(Some(&mut keypad)).unwrap();
+        assert!(matches!(cpu.step(None), Err(super::CpuError::DisplayRequired(0xF00A))));
+        assert_eq!(cpu.pc(), 0x202);
     }
 
     #[test]
-    fn set_vx_vx_and_vy() {
+    fn quirks_presets() {
+        use super::Quirks;
+
+        let chip8 = Quirks::preset("chip8").unwrap();
+        assert!(chip8.vf_reset && chip8.memory_increment && chip8.display_wait);
+        assert!(!chip8.shift && !chip8.jump && !chip8.wrap);
+
+        let schip = Quirks::preset("schip").unwrap();
+        assert!(schip.shift && schip.jump);
+        assert!(!schip.vf_reset && !schip.memory_increment && !schip.wrap && !schip.display_wait);
+
+        let xochip = Quirks::preset("XOCHIP").unwrap();
+        assert!(xochip.memory_increment && xochip.wrap);
+        assert!(!xochip.vf_reset && !xochip.shift && !xochip.jump && !xochip.display_wait);
+
+        assert!(Quirks::preset("chip48").is_none());
+        assert!(super::QUIRKS_PRESETS.iter().all(|name| Quirks::preset(name).is_some()));
+
+        // The default is the original behaviour without the display wait
+        assert_eq!(Quirks::default(), Quirks { display_wait: false, ..chip8 });
+    }
+
+    #[test]
+    fn schip_quirks() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x12]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.v_reg[0], 0x00);
+        cpu.set_quirks(super::Quirks::SCHIP);
+        // V0 = 0x03, V1 = 0x10, V0 >>= 1, VF |= V1, I = 0x300, store V0..V1,
+        // jump to 0x100 + V1
+        cpu.load_rom(&[0x60, 0x03, 0x61, 0x10, 0x80, 0x16, 0x8F, 0x11,
+            0xA3, 0x00, 0xF1, 0x55, 0xB1, 0x00]).unwrap();
+        for _ in 0..6 {
+            cpu.step(None).unwrap();
+        }
+        assert_eq!(cpu.v(0), 0x01);
+        assert_eq!(cpu.v(0xF), 0x11);
+        assert_eq!(cpu.index(), 0x300);
+        assert_eq!(cpu.read_range(0x300, 2), vec![0x01, 0x10]);
+
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.pc(), 0x110);
     }
 
     #[test]
-    fn set_vx_vx_xor_vy() {
+    fn run_for() {
+        // DT = 0xFF, then increment V1 forever
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x13]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
-        assert_eq!(cpu.v_reg[0], 0x03);
+        cpu.load_rom(&[0x60, 0xFF, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04]).unwrap();
+
+        cpu.run_for(Duration::from_secs(1), None).unwrap();
+        assert_eq!(cpu.instructions_executed(), super::DEFAULT_IPS as u64);
+        assert_eq!(cpu.delay_timer(), 0xFF - 60);
+
+        // Fractions of instructions and ticks are carried over
+        cpu.set_ips(100);
+        for _ in 0..4 {
+            cpu.run_for(Duration::from_millis(25), None).unwrap();
+        }
+        assert_eq!(cpu.instructions_executed(), super::DEFAULT_IPS as u64 + 10);
+        assert_eq!(cpu.delay_timer(), 0xFF - 66);
     }
 
     #[test]
-    fn add_vx_vy_carry() {
+    fn run_steps() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0x61, 0xFF, 0x80, 0x14]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x04]).unwrap();
+
+        // Stopped by the cap without the halt on loop
+        cpu.set_halt_on_loop(false);
+        assert_eq!(cpu.run_steps(2, None).unwrap(), 2);
+        assert_eq!(cpu.run_steps(100, None).unwrap(), 100);
+        assert_eq!(cpu.instructions_executed(), 102);
+
+        // Stopped early by the halt
+        cpu.reset();
+        cpu.set_halt_on_loop(true);
+        assert_eq!(cpu.run_steps(100, None).unwrap(), 3);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.run_steps(100, None).unwrap(), 0);
+
+        // Errors are returned
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&[0x00, 0xE0]).unwrap();
+        assert!(cpu.run_steps(1, None).is_err());
+    }
 
-        assert_eq!(cpu.v_reg[0], 0x00);
-        assert_eq!(cpu.v_reg[0xF], 0x01);
+    #[test]
+    fn paused_timers_and_cpu() {
+        // Paused timers stay put however long update_timers waits
+        let (mut cpu, clock) = cpu_with_fake_clock();
+        cpu.load_rom(&[0x60, 0x20, 0xF0, 0x15, 0x12, 0x04]).unwrap();
+        cpu.step(None).unwrap();
+        cpu.step(None).unwrap();
+        cpu.set_timers_paused(true);
+        clock.advance(Duration::from_secs(1));
+        cpu.update_timers();
+        assert_eq!(cpu.delay_timer(), 0x20);
+
+        // The ticks while paused are not caught up on resuming
+        cpu.set_timers_paused(false);
+        cpu.update_timers();
+        assert_eq!(cpu.delay_timer(), 0x20);
+        clock.advance(Duration::from_millis(50));
+        cpu.update_timers();
+        assert_eq!(cpu.delay_timer(), 0x20 - 3);
+
+        // A paused CPU runs no instructions but its timers count down
+        cpu.set_cpu_paused(true);
+        let executed = cpu.instructions_executed();
+        cpu.run_for(Duration::from_millis(50), None).unwrap();
+        assert_eq!(cpu.run_steps(10, None).unwrap(), 0);
+        assert_eq!(cpu.instructions_executed(), executed);
+        assert_eq!(cpu.delay_timer(), 0x20 - 6);
     }
 
     #[test]
-    fn sub_vx_vy_carry() {
+    fn disassemble_at() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x61, 0xFF, 0x60, 0x01, 0x80, 0x15]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
+        cpu.load_rom(&[
+            0x60, 0x00, 0x61, 0xE0, 0xA2, 0x0C, 0xF1, 0x55, 0x12, 0x08, 0xF0, 0x00, 0x12, 0x34,
+        ]).unwrap();
+        assert_eq!(cpu.disassemble_at(0x204), "LD I, 0x20C");
+        assert_eq!(cpu.disassemble_at(0x20A), "LD I, 0x1234");
+        assert_eq!(cpu.disassemble_at(0x20C), "JP 0x234");
+
+        // FX55 overwrites the jump with a CLS
+        for _ in 0..4 {
+            cpu.step(None).unwrap();
+        }
+        assert_eq!(cpu.disassemble_at(0x20C), "CLS");
+        assert_eq!(cpu.disassemble_at(0x20A), "LD I, 0x00E0");
 
-        assert_eq!(cpu.v_reg[0], 0x02);
-        assert_eq!(cpu.v_reg[0xF], 0x00);
+        assert_eq!(cpu.disassemble_at(0xFFF), "DB 0x00");
+        assert_eq!(cpu.disassemble_at(0x1000), "");
     }
 
     #[test]
-    fn sub_vy_vx_carry() {
+    fn fake_clock_ticks() {
+        let (mut cpu, clock) = cpu_with_fake_clock();
+        cpu.delay_timer = 10;
+
+        clock.advance(Duration::from_secs(3) / 60);
+        cpu.update_timers();
+        assert_eq!(cpu.delay_timer(), 7);
+
+        // Nothing happens while the clock stands still
+        cpu.update_timers();
+        assert_eq!(cpu.delay_timer(), 7);
+    }
+
+    // Clock moving a millisecond forward each time it is read, like a host
+    // too slow for the instructions it runs
+    struct SlowClock(Cell<Duration>);
+
+    impl Clock for SlowClock {
+        fn now(&self) -> Duration {
+            let now = self.0.get();
+            self.0.set(now + Duration::from_millis(1));
+            now
+        }
+    }
+
+    #[test]
+    fn frame_budget() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0xFF, 0x61, 0x01, 0x80, 0x17]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
+        cpu.load_rom(&[0x12, 0x00]).unwrap();
+        cpu.set_halt_on_loop(false);
+        cpu.set_ips(1000);
+        cpu.set_clock(Box::new(SlowClock(Cell::new(Duration::ZERO))));
+        cpu.set_frame_budget(Some(Duration::from_millis(5)));
+
+        // The clock is read every BUDGET_CHECK_STEPS instructions and 6ms
+        // have passed at the sixth reading
+        cpu.run_for(Duration::from_secs(1), None).unwrap();
+        let executed = 6 * super::BUDGET_CHECK_STEPS as u64;
+        assert_eq!(cpu.instructions_executed(), executed);
+
+        // The rest runs with the next frame
+        cpu.set_frame_budget(None);
+        cpu.run_for(Duration::ZERO, None).unwrap();
+        assert_eq!(cpu.instructions_executed(), 1000);
+    }
 
-        assert_eq!(cpu.v_reg[0], 0x02);
-        assert_eq!(cpu.v_reg[0xF], 0x00);
+    #[test]
+    fn timers_catch_up() {
+        let (mut cpu, clock) = cpu_with_fake_clock();
+        cpu.delay_timer = 10;
+        cpu.sound_timer = 2;
+
+        // 50ms are 3 ticks and 0.33 of the next one
+        clock.advance(Duration::from_millis(50));
+        cpu.update_timers();
+        assert_eq!(cpu.delay_timer(), 7);
+        assert_eq!(cpu.get_sound_timer(), 0);
+
+        // The fractions add up
+        clock.advance(Duration::from_millis(50));
+        cpu.update_timers();
+        assert_eq!(cpu.delay_timer(), 4);
     }
 
     #[test]
-    fn set_vx_vy_shr() {
+    fn timers_at_60hz() {
+        // A second in 10ms updates, which are 0.6 ticks each
+        let (mut cpu, clock) = cpu_with_fake_clock();
+        cpu.delay_timer = 100;
+        for _ in 0..100 {
+            clock.advance(Duration::from_millis(10));
+            cpu.update_timers();
+        }
+        assert_eq!(cpu.delay_timer(), 40);
+    }
+
+    #[test]
+    fn cycle_timing() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x61, 0x03, 0x80, 0x16]);
-        cpu.step(None);
-        cpu.step(None);
+        cpu.load_rom(&[0x60, 0x01, 0x81, 0x04, 0xF1, 0x55]).unwrap();
 
-        assert_eq!(cpu.v_reg[0], 0x01);
-        assert_eq!(cpu.v_reg[0xF], 0x01);
+        cpu.step(None).unwrap();
+        assert_eq!(cpu.cycles(), 0);
+
+        cpu.reset();
+        cpu.set_cycle_timing(true);
+        for _ in 0..3 {
+            cpu.step(None).unwrap();
+        }
+        assert_eq!(cpu.cycles(), 6 + 44 + 14 + 14 * 2);
     }
 
     #[test]
-    fn set_vx_vy_shl() {
+    fn opcode_stats() {
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x61, 0x80, 0x80, 0x1E]);
-        cpu.step(None);
-        cpu.step(None);
+        cpu.load_rom(&[0x60, 0x01, 0x70, 0x01, 0x70, 0x01, 0x81, 0x04, 0xF1, 0x55, 0x12, 0x0A]).unwrap();
+        assert!(cpu.opcode_stats().is_none());
+
+        cpu.set_opcode_stats(true);
+        assert_eq!(cpu.run_steps(10, None).unwrap(), 6);
+        let stats = cpu.opcode_stats().unwrap();
+        assert_eq!(stats.count("6XNN"), Some(1));
+        assert_eq!(stats.count("7XNN"), Some(2));
+        assert_eq!(stats.count("8XY4"), Some(1));
+        assert_eq!(stats.count("FX55"), Some(1));
+        assert_eq!(stats.count("1NNN"), Some(1));
+        assert_eq!(stats.count("DXYN"), Some(0));
+
+        cpu.reset();
+        assert_eq!(cpu.opcode_stats().unwrap().count("7XNN"), Some(0));
+    }
 
-        assert_eq!(cpu.v_reg[0], 0x00);
-        assert_eq!(cpu.v_reg[0xF], 0x01);
+    #[test]
+    fn draw_collision() {
+        let mut screen = crate::display::HeadlessScreen::new();
+        let mut cpu = super::Cpu::new();
+        // Draw the 0 glyph at (2, 1) twice
+        cpu.load_rom(&[0x60, 0x00, 0xF0, 0x29, 0x61, 0x02, 0x62, 0x01,
+            0xD1, 0x25, 0xD1, 0x25]).unwrap();
+        for _ in 0..5 {
+            cpu.step(Some(&mut screen)).unwrap();
+        }
+        assert_eq!(cpu.v(0xF), 0);
+        assert_eq!(screen.framebuffer().lit_pixels().count(), 14);
+        assert_eq!(screen.pixel(2, 1), 1);
+
+        // Drawing it again erases it and reports the collision
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(cpu.v(0xF), 1);
+        assert_eq!(screen.framebuffer().lit_pixels().count(), 0);
     }
 
     #[test]
-    fn jump_with_offset() {
+    fn resolution_modes() {
+        let mut screen = crate::display::HeadlessScreen::new();
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0xB0, 0x01]);
-        cpu.step(None);
-        cpu.step(None);
+        cpu.load_rom(&[0x00, 0xFF, 0x00, 0xFE, 0x00, 0xFF]).unwrap();
+        assert_eq!(cpu.resolution(), (64, 32));
+
+        cpu.step(Some(&mut screen)).unwrap();
+        assert!(cpu.is_hires());
+        assert_eq!(cpu.resolution(), (128, 64));
+        assert_eq!((screen.framebuffer().width(), screen.framebuffer().height()), (128, 64));
+
+        cpu.step(Some(&mut screen)).unwrap();
+        assert!(!cpu.is_hires());
+        assert_eq!((screen.framebuffer().width(), screen.framebuffer().height()), (64, 32));
+
+        // Reset goes back to the low resolution
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(screen.framebuffer().width(), 128);
+        cpu.reset();
+        assert_eq!(cpu.resolution(), (64, 32));
+        assert!(matches!(cpu.step(None), Err(super::CpuError::DisplayRequired(0x00FF))));
+    }
 
-        assert_eq!(cpu.pc, 0x0002);
+    #[test]
+    fn hires_sprite_edges() {
+        // An 8 pixel row of 0xFF drawn at x = 124, y = 63 in the high
+        // resolution, then at x = 130 which starts wrapped at x = 2
+        let rom = [0x00, 0xFF, 0xA2, 0x10, 0x60, 0x7C, 0x61, 0x3F, 0xD0, 0x11, 0x60, 0x82, 0xD0, 0x11,
+            0x00, 0x00, 0xFF];
+        let mut screen = crate::display::HeadlessScreen::new();
+        let mut cpu = super::Cpu::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.run_steps(5, Some(&mut screen)).unwrap();
+
+        // Clipped at the right edge of the 128 pixels, on the last row
+        let lit: Vec<(u32, u32)> = screen.framebuffer().lit_pixels().collect();
+        assert_eq!(lit, vec![(124, 63), (125, 63), (126, 63), (127, 63)]);
+
+        cpu.run_steps(2, Some(&mut screen)).unwrap();
+        assert_eq!(screen.framebuffer().lit_pixels().count(), 12);
+        assert_eq!(screen.pixel(2, 63), 1);
+        assert_eq!(screen.pixel(9, 63), 1);
+        assert_eq!(screen.pixel(10, 63), 0);
+
+        // With the wrap quirk the rest of the row wraps to the left edge
+        let mut screen = crate::display::HeadlessScreen::new();
+        let mut cpu = super::Cpu::new();
+        cpu.set_quirks(super::Quirks { wrap: true, ..Default::default() });
+        cpu.load_rom(&rom).unwrap();
+        cpu.run_steps(5, Some(&mut screen)).unwrap();
+        let lit: Vec<(u32, u32)> = screen.framebuffer().lit_pixels().collect();
+        assert_eq!(lit, vec![(0, 63), (1, 63), (2, 63), (3, 63), (124, 63), (125, 63), (126, 63), (127, 63)]);
     }
-    
+
     #[test]
-    fn add_idx_vx() {
+    fn index_overflow() {
+        // I = 0xFFE + 0x02 and then + 0x01 with VF set beforehand
+        let rom = [0xAF, 0xFE, 0x60, 0x02, 0x6F, 0x07, 0xF0, 0x1E, 0x60, 0x01, 0xF0, 0x1E];
+        let run = |quirks| {
+            let mut cpu = super::Cpu::new();
+            cpu.set_quirks(quirks);
+            cpu.load_rom(&rom).unwrap();
+            cpu.run_steps(4, None).unwrap();
+            let first = (cpu.index, cpu.v(0xF));
+            cpu.run_steps(2, None).unwrap();
+            (first, (cpu.index, cpu.v(0xF)))
+        };
+
+        let quirks = super::Quirks::default();
+        assert_eq!(run(quirks), ((0x1000, 0x07), (0x1001, 0x07)));
+        let quirks = super::Quirks { index_overflow_sets_vf: true, ..quirks };
+        assert_eq!(run(quirks), ((0x1000, 1), (0x1001, 1)));
+
+        // Without the overflow VF is cleared
         let mut cpu = super::Cpu::new();
-        cpu.load_rom(&[0x60, 0x01, 0xA0, 0x01, 0xF0, 0x1E]);
-        cpu.step(None);
-        cpu.step(None);
-        cpu.step(None);
+        cpu.set_quirks(quirks);
+        cpu.load_rom(&[0x6F, 0x07, 0x60, 0x02, 0xF0, 0x1E]).unwrap();
+        cpu.run_steps(3, None).unwrap();
+        assert_eq!((cpu.index, cpu.v(0xF)), (0x002, 0));
+    }
 
-        assert_eq!(cpu.index, 0x0002);
-        assert_eq!(cpu.v_reg[0xF], 0x00);
+    #[test]
+    fn detect_variant() {
+        use super::{Cpu, DetectedVariant};
+
+        assert_eq!(Cpu::detect_variant(&[0x00, 0xE0, 0x60, 0x05, 0x12, 0x02]), DetectedVariant::Chip8);
+        assert_eq!(Cpu::detect_variant(&[0x00, 0xFF, 0xD0, 0x10, 0x12, 0x02]), DetectedVariant::SuperChip);
+        assert_eq!(Cpu::detect_variant(&[0x00, 0xE0, 0xF1, 0x75, 0x12, 0x02]), DetectedVariant::SuperChip);
+        assert_eq!(Cpu::detect_variant(&[0x00, 0xFF, 0xF0, 0x00, 0x12, 0x34]), DetectedVariant::XoChip);
+        assert_eq!(Cpu::detect_variant(&[0xA2, 0x10, 0xF0, 0x02, 0x12, 0x02]), DetectedVariant::XoChip);
+        assert_eq!(Cpu::detect_variant(&[0x51, 0x23, 0x12, 0x02]), DetectedVariant::XoChip);
+
+        assert_eq!(DetectedVariant::SuperChip.preset(), "schip");
+    }
+
+    #[test]
+    fn clip_collision() {
+        // A row of 8 pixels drawn at x 60 on a blank screen, half of it past
+        // the right edge
+        let rom = [0xA2, 0x08, 0x60, 0x3C, 0xD0, 0x01, 0x12, 0x06, 0xFF];
+        let draw = |quirks| {
+            let mut screen = crate::display::HeadlessScreen::new();
+            let mut cpu = super::Cpu::new();
+            cpu.set_quirks(quirks);
+            cpu.load_rom(&rom).unwrap();
+            cpu.run_steps(3, Some(&mut screen)).unwrap();
+            (cpu.v(0xF), screen.framebuffer().lit_pixels().count())
+        };
+
+        let quirks = super::Quirks::default();
+        assert_eq!(draw(quirks), (0, 4));
+        assert_eq!(draw(super::Quirks { clip_collision: true, ..quirks }), (1, 4));
+
+        // Nothing is clipped with the wrap quirk
+        assert_eq!(draw(super::Quirks { wrap: true, clip_collision: true, ..quirks }), (0, 8));
     }
 
 }
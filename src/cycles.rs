@@ -0,0 +1,60 @@
+// Approximate cost in machine cycles of an opcode on the COSMAC VIP
+// interpreter. Opcodes whose time depends on the operands include them, e.g.
+// DXYN grows with the sprite height and FX55 with the registers stored.
+// Opcodes that are not instructions cost nothing
+pub fn opcode_cycles(opcode: u16) -> u64 {
+    let x = ((opcode & 0x0F00) >> 8) as u64;
+    let n = (opcode & 0x000F) as u64;
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => 24,
+            0x00EE => 10,
+            _ => 0,
+        },
+        0x1000 => 12,
+        0x2000 => 26,
+        0x3000 | 0x4000 => 10,
+        0x5000 | 0x9000 => 14,
+        0x6000 => 6,
+        0x7000 => 10,
+        0x8000 => 44,
+        0xA000 => 12,
+        0xB000 => 22,
+        0xC000 => 36,
+        0xD000 => 22 + 34 * n,
+        0xE000 => 14,
+        0xF000 => match opcode & 0x00FF {
+            0x07 | 0x0A | 0x15 | 0x18 => 10,
+            0x1E => 16,
+            0x29 => 20,
+            0x33 => 84,
+            0x55 | 0x65 => 14 + 14 * (x + 1),
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::opcode_cycles;
+
+    #[test]
+    fn fixed_costs() {
+        assert_eq!(opcode_cycles(0x00E0), 24);
+        assert_eq!(opcode_cycles(0x1234), 12);
+        assert_eq!(opcode_cycles(0x6A05), 6);
+        assert_eq!(opcode_cycles(0x8124), 44);
+        assert_eq!(opcode_cycles(0xF029), 20);
+        assert_eq!(opcode_cycles(0x0123), 0);
+    }
+
+    #[test]
+    fn operand_costs() {
+        assert_eq!(opcode_cycles(0xD011), 56);
+        assert_eq!(opcode_cycles(0xD01F), 22 + 34 * 15);
+        assert_eq!(opcode_cycles(0xF055), 28);
+        assert_eq!(opcode_cycles(0xF265), 56);
+    }
+}
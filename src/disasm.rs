@@ -0,0 +1,203 @@
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// Decodes a CHIP-8 opcode into its assembly mnemonic. Opcodes that are not
+// instructions are shown as a data word (DW)
+pub fn disassemble(opcode: u16) -> String {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            0x00FE => "LOW".to_string(),
+            0x00FF => "HIGH".to_string(),
+            _ => format!("SYS 0x{:03X}", nnn),
+        },
+        0x1000 => format!("JP 0x{:03X}", nnn),
+        0x2000 => format!("CALL 0x{:03X}", nnn),
+        0x3000 => format!("SE V{:X}, 0x{:02X}", x, nn),
+        0x4000 => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        0x5000 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x5000 if n == 2 => format!("SAVE V{:X} - V{:X}", x, y),
+        0x5000 if n == 3 => format!("LOAD V{:X} - V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, 0x{:02X}", x, nn),
+        0x7000 => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        0x8000 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("DW 0x{:04X}", opcode),
+        },
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, 0x{:03X}", nnn),
+        0xB000 => format!("JP V0, 0x{:03X}", nnn),
+        0xC000 => format!("RND V{:X}, 0x{:02X}", x, nn),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("DW 0x{:04X}", opcode),
+        },
+        0xF000 => match nn {
+            0x00 if x == 0 => "LD I, long".to_string(),
+            0x02 if x == 0 => "AUDIO".to_string(),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x3A => format!("PITCH V{:X}", x),
+            _ => format!("DW 0x{:04X}", opcode),
+        },
+        _ => format!("DW 0x{:04X}", opcode),
+    }
+}
+
+// Disassembles a ROM loaded at start, two bytes at a time except for the
+// four of the XO-CHIP F000 NNNN. Returns the address, opcode and mnemonic of
+// each instruction, a last odd byte is shown as a data byte (DB)
+pub fn disassemble_rom(rom: &[u8], start: u16) -> Vec<(u16, u16, String)> {
+    let mut instructions = Vec::new();
+    let mut i = 0;
+    while i < rom.len() {
+        let addr = start.wrapping_add(i as u16);
+        match rom[i..] {
+            [0xF0, 0x00, hi, lo, ..] => {
+                instructions.push((addr, 0xF000, format!("LD I, 0x{:04X}", u16::from_be_bytes([hi, lo]))));
+                i += 4;
+            }
+            [hi, lo, ..] => {
+                let opcode = u16::from_be_bytes([hi, lo]);
+                instructions.push((addr, opcode, disassemble(opcode)));
+                i += 2;
+            }
+            _ => {
+                instructions.push((addr, (rom[i] as u16) << 8, format!("DB 0x{:02X}", rom[i])));
+                i += 1;
+            }
+        }
+    }
+    instructions
+}
+
+// Address jumped to or called by the opcode
+fn target(opcode: u16) -> Option<u16> {
+    match opcode & 0xF000 {
+        0x1000 | 0x2000 | 0xB000 => Some(opcode & 0x0FFF),
+        _ => None,
+    }
+}
+
+// Name of the label of an address
+fn label(addr: u16) -> String {
+    format!("L_0x{:03X}", addr)
+}
+
+// Disassembles a ROM loaded at start into a listing closer to source that can
+// be assembled again: the targets of jumps and calls get a label, used by the
+// instructions that jump to them. Targets in the middle of an instruction,
+// usually data read as code, keep their address and are noted in a comment
+pub fn listing(rom: &[u8], start: u16) -> String {
+    let instructions = disassemble_rom(rom, start);
+
+    // Find the targets in a first pass, then write them in a second one
+    let targets: BTreeSet<u16> = instructions.iter().filter_map(|(_, opcode, _)| target(*opcode)).collect();
+    let labels: BTreeSet<u16> = targets.iter().copied()
+        .filter(|t| instructions.iter().any(|(addr, _, _)| addr == t))
+        .collect();
+
+    let mut out = String::new();
+    for (addr, opcode, mnemonic) in instructions {
+        if labels.contains(&addr) {
+            out += &format!("{}:\n", label(addr));
+        }
+        if targets.contains(&(addr.wrapping_add(1))) {
+            out += &format!("; {} is a data reference in the middle of the next instruction\n", label(addr + 1));
+        }
+
+        let mnemonic = match target(opcode) {
+            Some(t) if labels.contains(&t) => mnemonic.replace(&format!("0x{:03X}", t), &label(t)),
+            _ => mnemonic,
+        };
+        out += &format!("    {:<16} ; {:03X}: {:04X}\n", mnemonic, addr, opcode);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{disassemble, disassemble_rom, listing};
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(disassemble(0x00E0), "CLS");
+        assert_eq!(disassemble(0x00EE), "RET");
+        assert_eq!(disassemble(0x00FF), "HIGH");
+        assert_eq!(disassemble(0x0123), "SYS 0x123");
+        assert_eq!(disassemble(0x1ABC), "JP 0xABC");
+        assert_eq!(disassemble(0x3A05), "SE VA, 0x05");
+        assert_eq!(disassemble(0x8124), "ADD V1, V2");
+        assert_eq!(disassemble(0xA2F0), "LD I, 0x2F0");
+        assert_eq!(disassemble(0xD015), "DRW V0, V1, 5");
+        assert_eq!(disassemble(0xE39E), "SKP V3");
+        assert_eq!(disassemble(0xF265), "LD V2, [I]");
+        assert_eq!(disassemble(0xF000), "LD I, long");
+        assert_eq!(disassemble(0xF002), "AUDIO");
+        assert_eq!(disassemble(0xF53A), "PITCH V5");
+        assert_eq!(disassemble(0x5252), "SAVE V2 - V5");
+        assert_eq!(disassemble(0x5523), "LOAD V5 - V2");
+    }
+
+    #[test]
+    fn data_words() {
+        assert_eq!(disassemble(0x5121), "DW 0x5121");
+        assert_eq!(disassemble(0x812F), "DW 0x812F");
+        assert_eq!(disassemble(0xFFFF), "DW 0xFFFF");
+    }
+
+    #[test]
+    fn rom_instructions() {
+        let rom = [0x60, 0x05, 0xF0, 0x00, 0x1A, 0xBC, 0x12, 0x00, 0xFF];
+        assert_eq!(disassemble_rom(&rom, 0x200), vec![
+            (0x200, 0x6005, "LD V0, 0x05".to_string()),
+            (0x202, 0xF000, "LD I, 0x1ABC".to_string()),
+            (0x206, 0x1200, "JP 0x200".to_string()),
+            (0x208, 0xFF00, "DB 0xFF".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn labels() {
+        // A loop jumping back to its start, a call into the middle of an
+        // instruction and a jump out of the ROM
+        let rom = [0x00, 0xE0, 0x60, 0x05, 0x70, 0xFF, 0x30, 0x00, 0x12, 0x04, 0x22, 0x03, 0x13, 0x00];
+        assert_eq!(listing(&rom, 0x200), concat!(
+            "    CLS              ; 200: 00E0\n",
+            "; L_0x203 is a data reference in the middle of the next instruction\n",
+            "    LD V0, 0x05      ; 202: 6005\n",
+            "L_0x204:\n",
+            "    ADD V0, 0xFF     ; 204: 70FF\n",
+            "    SE V0, 0x00      ; 206: 3000\n",
+            "    JP L_0x204       ; 208: 1204\n",
+            "    CALL 0x203       ; 20A: 2203\n",
+            "    JP 0x300         ; 20C: 1300\n",
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+use alloc::format;
+use alloc::string::String;
+
+use crate::framebuffer::Framebuffer;
+
+// Interface between the CHIP-8 CPU and the front-end showing the display and
+// reading the keypad, implemented by each backend
+pub trait Display {
+    // Clears the display
+    fn clear(&mut self);
+
+    // XORs the pixel at (x, y) with bit and returns its previous value
+    fn draw_pixel(&mut self, x: u8, y: u8, bit: u8) -> u8;
+
+    // Whether the key key_value (0x0..=0xF) is currently pressed
+    fn is_key_pressed(&self, key_value: u8) -> bool;
+
+    // Returns the lowest of the keys currently pressed, if any
+    fn get_key_pressed(&self) -> Option<u8>;
+
+    // Returns the key pressed most recently among the ones currently
+    // pressed, if any. Backends that don't know the order of the presses
+    // return the lowest one
+    fn get_last_key_pressed(&self) -> Option<u8> {
+        self.get_key_pressed()
+    }
+
+    // Changes the resolution of the display to width x height pixels, for
+    // the SUPER-CHIP modes. The pixels are cleared
+    fn resize(&mut self, width: u32, height: u32);
+}
+
+// Checks that key is a key of the keypad, for the keys pressed by scripts
+pub fn check_key(key: u8) -> Result<(), String> {
+    if key > 0xF {
+        return Err(format!("Invalid key 0x{:X}, the keys go from 0x0 to 0xF", key));
+    }
+    Ok(())
+}
+
+// Resolution of the CHIP-8 display
+pub const SCREEN_WIDTH: u32 = 64;
+pub const SCREEN_HEIGHT: u32 = 32;
+
+// Resolution of the SUPER-CHIP high resolution mode
+pub const HIRES_WIDTH: u32 = 128;
+pub const HIRES_HEIGHT: u32 = 64;
+
+// Display keeping the pixels in memory without showing them, for tests and
+// runs without a window. Keys are pressed with set_key
+pub struct HeadlessScreen {
+    pixels: Framebuffer,
+    keypad: [bool; 16],
+    // The key pressed most recently
+    last_pressed: Option<u8>,
+}
+
+impl Default for HeadlessScreen {
+    fn default() -> HeadlessScreen {
+        HeadlessScreen::new()
+    }
+}
+
+impl HeadlessScreen {
+    // Creates a new display with all the pixels off and no key pressed
+    pub fn new() -> HeadlessScreen {
+        HeadlessScreen {
+            pixels: Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            keypad: [false; 16],
+            last_pressed: None,
+        }
+    }
+
+    // Returns the pixel at (x, y), 1 when lit
+    pub fn pixel(&self, x: u8, y: u8) -> u8 {
+        self.pixels.get(x as u32, y as u32)
+    }
+
+    // Returns the pixel at (x, y), 1 when lit and 0 when off or outside of
+    // the display. Unlike pixel, it doesn't panic outside of the display
+    pub fn pixel_at(&self, x: u8, y: u8) -> u8 {
+        self.pixels.pixel_at(x as u32, y as u32)
+    }
+
+    // Returns the row y of the display, the pixel x in the bit x, and 0
+    // outside of the display
+    pub fn row_bits(&self, y: u8) -> u64 {
+        self.pixels.row_bits(y as u32)
+    }
+
+    // Returns the pixels of the display
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.pixels
+    }
+
+    // Presses or releases the key key_value (0x0..=0xF)
+    pub fn set_key(&mut self, key_value: u8, pressed: bool) {
+        self.keypad[key_value as usize] = pressed;
+        if pressed {
+            self.last_pressed = Some(key_value);
+        }
+    }
+
+    // Presses the key, for scripted input
+    pub fn press_key(&mut self, key: u8) -> Result<(), String> {
+        check_key(key)?;
+        self.set_key(key, true);
+        Ok(())
+    }
+
+    // Releases the key pressed with press_key
+    pub fn release_key(&mut self, key: u8) -> Result<(), String> {
+        check_key(key)?;
+        self.set_key(key, false);
+        Ok(())
+    }
+}
+
+impl Display for HeadlessScreen {
+    fn clear(&mut self) {
+        self.pixels.clear();
+    }
+
+    fn draw_pixel(&mut self, x: u8, y: u8, bit: u8) -> u8 {
+        self.pixels.xor(x as u32, y as u32, bit)
+    }
+
+    fn is_key_pressed(&self, key_value: u8) -> bool {
+        self.keypad[key_value as usize]
+    }
+
+    fn get_key_pressed(&self) -> Option<u8> {
+        (0..16).find(|key| self.keypad[*key as usize])
+    }
+
+    fn get_last_key_pressed(&self) -> Option<u8> {
+        self.last_pressed.filter(|key| self.keypad[*key as usize])
+            .or_else(|| self.get_key_pressed())
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.pixels = Framebuffer::new(width, height);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Display, HeadlessScreen};
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn headless_pixels() {
+        let mut screen = HeadlessScreen::new();
+        assert_eq!(screen.draw_pixel(63, 31, 1), 0);
+        assert_eq!(screen.pixel(63, 31), 1);
+        assert_eq!(screen.draw_pixel(63, 31, 1), 1);
+        assert_eq!(screen.pixel(63, 31), 0);
+
+        assert_eq!(screen.pixel_at(63, 31), 0);
+        screen.draw_pixel(2, 31, 1);
+        assert_eq!(screen.pixel_at(2, 31), 1);
+        assert_eq!(screen.row_bits(31), 1 << 2);
+        assert_eq!(screen.pixel_at(64, 0), 0);
+        assert_eq!(screen.row_bits(32), 0);
+
+        screen.draw_pixel(0, 0, 1);
+        screen.clear();
+        assert_eq!(screen.pixel(0, 0), 0);
+
+        screen.set_key(0xA, true);
+        assert!(screen.is_key_pressed(0xA));
+        assert_eq!(screen.get_key_pressed(), Some(0xA));
+    }
+
+    #[test]
+    fn injected_keys() {
+        let mut screen = HeadlessScreen::new();
+        screen.press_key(0xF).unwrap();
+        assert!(screen.is_key_pressed(0xF));
+        screen.release_key(0xF).unwrap();
+        assert!(!screen.is_key_pressed(0xF));
+        assert!(screen.press_key(0x10).is_err());
+        assert!(screen.release_key(0x10).is_err());
+
+        // Skip the next instruction when 0x5 is pressed
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x60, 0x05, 0xE0, 0x9E, 0x00, 0x00, 0xE0, 0x9E]).unwrap();
+        screen.press_key(0x5).unwrap();
+        for _ in 0..2 {
+            cpu.step(Some(&mut screen)).unwrap();
+        }
+        assert_eq!(cpu.pc(), 0x206);
+
+        screen.release_key(0x5).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(cpu.pc(), 0x208);
+    }
+
+    #[test]
+    fn last_key_pressed() {
+        let mut screen = HeadlessScreen::new();
+        assert_eq!(screen.get_last_key_pressed(), None);
+
+        screen.press_key(0x9).unwrap();
+        screen.press_key(0x2).unwrap();
+        screen.press_key(0x6).unwrap();
+        assert_eq!(screen.get_key_pressed(), Some(0x2));
+        assert_eq!(screen.get_last_key_pressed(), Some(0x6));
+
+        // Once released the lowest of the keys still held is returned
+        screen.release_key(0x6).unwrap();
+        assert_eq!(screen.get_last_key_pressed(), Some(0x2));
+
+        // FX0A waits for the release of the key pressed last
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0xF3, 0x0A]).unwrap();
+        screen.press_key(0xC).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        screen.release_key(0x2).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!(cpu.pc(), 0x200);
+        screen.release_key(0xC).unwrap();
+        cpu.step(Some(&mut screen)).unwrap();
+        assert_eq!((cpu.pc(), cpu.v(3)), (0x202, 0xC));
+    }
+}
@@ -0,0 +1,167 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Monochrome pixels packed one bit per pixel in 64 bit words, each row
+// starting on a new word. Empty words are skipped when looking for the lit
+// pixels, which makes sparse screens cheap to draw
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    words: Vec<u64>,
+}
+
+impl Framebuffer {
+    // Creates a framebuffer of width x height pixels, all off
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            words: vec![0; (Framebuffer::row_words(width) * height) as usize],
+        }
+    }
+
+    // Number of words of a row of width pixels
+    fn row_words(width: u32) -> u32 {
+        width.div_ceil(64)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Returns the words of the pixels, row by row, the pixel x of a row in
+    // the bit x % 64 of its word x / 64
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    // Returns the word holding the pixel at (x, y) and the bit of the pixel
+    fn position(&self, x: u32, y: u32) -> (usize, u64) {
+        let word = y * Framebuffer::row_words(self.width) + x / 64;
+        (word as usize, 1 << (x % 64))
+    }
+
+    // Returns the pixel at (x, y), 1 when lit
+    pub fn get(&self, x: u32, y: u32) -> u8 {
+        let (word, mask) = self.position(x, y);
+        (self.words[word] & mask != 0) as u8
+    }
+
+    // Returns the pixel at (x, y), 1 when lit and 0 when off or outside of
+    // the framebuffer
+    pub fn pixel_at(&self, x: u32, y: u32) -> u8 {
+        if x >= self.width || y >= self.height {
+            return 0;
+        }
+        self.get(x, y)
+    }
+
+    // Returns the first 64 pixels of the row y, the pixel x in the bit x,
+    // and 0 outside of the framebuffer
+    pub fn row_bits(&self, y: u32) -> u64 {
+        if y >= self.height {
+            return 0;
+        }
+        self.words[self.position(0, y).0]
+    }
+
+    // XORs the pixel at (x, y) with bit and returns its previous value
+    pub fn xor(&mut self, x: u32, y: u32, bit: u8) -> u8 {
+        let prev = self.get(x, y);
+        if bit == 1 {
+            let (word, mask) = self.position(x, y);
+            self.words[word] ^= mask;
+        }
+        prev
+    }
+
+    // Turns all the pixels off
+    pub fn clear(&mut self) {
+        self.words.iter_mut().for_each(|word| *word = 0);
+    }
+
+    // Returns the coordinates of the lit pixels, row by row
+    pub fn lit_pixels(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.set_bits(self.words.iter().copied())
+    }
+
+    // Returns the coordinates of the pixels that differ from the ones of
+    // other, a framebuffer of the same size, row by row
+    pub fn changed_pixels<'a>(&'a self, other: &'a Framebuffer) -> impl Iterator<Item = (u32, u32)> + 'a {
+        self.set_bits(self.words.iter().zip(&other.words).map(|(a, b)| a ^ b))
+    }
+
+    // Returns the coordinates of the bits set in words, laid out like the
+    // words of the pixels
+    fn set_bits(&self, words: impl Iterator<Item = u64>) -> impl Iterator<Item = (u32, u32)> {
+        let row_words = Framebuffer::row_words(self.width);
+        words.enumerate()
+            .filter(|(_, word)| *word != 0)
+            .flat_map(move |(i, word)| {
+                let y = i as u32 / row_words;
+                let x = (i as u32 % row_words) * 64;
+                (0..64).filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| (x + bit, y))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Framebuffer;
+
+    #[test]
+    fn xor_pixels() {
+        let mut fb = Framebuffer::new(64, 32);
+        assert_eq!(fb.xor(10, 5, 1), 0);
+        assert_eq!(fb.get(10, 5), 1);
+        assert_eq!(fb.xor(10, 5, 0), 1);
+        assert_eq!(fb.get(10, 5), 1);
+        assert_eq!(fb.xor(10, 5, 1), 1);
+        assert_eq!(fb.get(10, 5), 0);
+
+        fb.xor(63, 31, 1);
+        fb.clear();
+        assert_eq!(fb.get(63, 31), 0);
+    }
+
+    #[test]
+    fn read_pixels() {
+        let mut fb = Framebuffer::new(64, 32);
+        for (x, y) in [(0, 3), (5, 3), (63, 3), (10, 31)] {
+            fb.xor(x, y, 1);
+        }
+        assert_eq!(fb.row_bits(3), 1 | 1 << 5 | 1 << 63);
+        assert_eq!(fb.row_bits(31), 1 << 10);
+        assert_eq!(fb.row_bits(0), 0);
+        assert_eq!(fb.pixel_at(5, 3), 1);
+        assert_eq!(fb.pixel_at(6, 3), 0);
+
+        // Outside of the framebuffer
+        assert_eq!(fb.pixel_at(64, 3), 0);
+        assert_eq!(fb.pixel_at(0, 32), 0);
+        assert_eq!(fb.row_bits(32), 0);
+    }
+
+    #[test]
+    fn lit_pixels() {
+        // Rows wider than a word
+        let mut fb = Framebuffer::new(128, 64);
+        for (x, y) in [(0, 0), (63, 0), (64, 0), (127, 63), (5, 10)] {
+            fb.xor(x, y, 1);
+        }
+        let lit: Vec<(u32, u32)> = fb.lit_pixels().collect();
+        assert_eq!(lit, vec![(0, 0), (63, 0), (64, 0), (5, 10), (127, 63)]);
+
+        let mut other = fb.clone();
+        other.xor(64, 0, 1);
+        other.xor(100, 20, 1);
+        let changed: Vec<(u32, u32)> = fb.changed_pixels(&other).collect();
+        assert_eq!(changed, vec![(64, 0), (100, 20)]);
+    }
+}
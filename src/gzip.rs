@@ -0,0 +1,361 @@
+// Decompression of gzip files (RFC 1952) holding a DEFLATE stream (RFC 1951),
+// enough to load ROMs distributed as .ch8.gz
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+// First bytes of a gzip file
+pub const MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+// Header flags
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+// Base lengths and extra bits of the length symbols 257 to 285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Base distances and extra bits of the distance symbols 0 to 29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// Order in which the code lengths of the code length alphabet are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// Whether data starts like a gzip file
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+// Error of the data decompressing to more than the limit
+const TOO_LARGE: &str = "gzip file decompressing to more than the memory";
+
+// Decompresses the first member of a gzip file, checking its CRC and size.
+// Decompressing more than limit bytes is an error, so that a small file
+// can't fill the memory
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, &'static str> {
+    if !is_gzip(data) {
+        return Err("not a gzip file");
+    }
+    if data.len() < 18 {
+        return Err("truncated gzip file");
+    }
+    if data[2] != 8 {
+        return Err("unsupported gzip compression method");
+    }
+
+    // Skip the optional fields of the header
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        pos += 2 + len;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.get(pos..).and_then(|rest| rest.iter().position(|b| *b == 0))
+                .ok_or("truncated gzip file")?;
+            pos += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err("truncated gzip file");
+    }
+
+    let mut bits = Bits::new(&data[pos..]);
+    let out = inflate(&mut bits, limit)?;
+
+    // The CRC and the size follow the compressed data, from the next byte
+    let trailer = pos + bits.pos;
+    let trailer = data.get(trailer..trailer + 8).ok_or("truncated gzip file")?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&out) || size != out.len() as u32 {
+        return Err("corrupted gzip file");
+    }
+
+    Ok(out)
+}
+
+// CRC-32 of IEEE 802.3 used by gzip, and the chunks of PNG
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Reads the bits of the data from the least significant of each byte
+struct Bits<'a> {
+    data: &'a [u8],
+    // Next byte to read from
+    pos: usize,
+    // Bits left from the last byte read and their number
+    buf: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Bits<'a> {
+        Bits { data, pos: 0, buf: 0, count: 0 }
+    }
+
+    // Reads n bits, n at most 16
+    fn bits(&mut self, n: u32) -> Result<u32, &'static str> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or("truncated gzip file")?;
+            self.buf |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // Drops the bits left from the last byte read
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+// Canonical Huffman code, as the number of codes of each length and the
+// symbols sorted by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    // Builds the code of the symbols with the given code lengths, 0 for the
+    // symbols not used
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+
+        Huffman { counts, symbols }
+    }
+
+    // Reads the next symbol, one bit at a time
+    fn decode(&self, bits: &mut Bits) -> Result<u16, &'static str> {
+        // First code of the current length and index of its symbol
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid gzip data")
+    }
+}
+
+// Decompresses the DEFLATE blocks until the last one
+fn inflate(bits: &mut Bits, limit: usize) -> Result<Vec<u8>, &'static str> {
+    let mut out = vec![];
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => stored(bits, &mut out, limit)?,
+            1 => {
+                let (lit, dist) = fixed_codes();
+                codes(bits, &mut out, limit, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_codes(bits)?;
+                codes(bits, &mut out, limit, &lit, &dist)?;
+            }
+            _ => return Err("invalid gzip data"),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+// Copies a block stored without compression
+fn stored(bits: &mut Bits, out: &mut Vec<u8>, limit: usize) -> Result<(), &'static str> {
+    bits.align();
+    let len = bits.bits(16)?;
+    if bits.bits(16)? != !len & 0xFFFF {
+        return Err("invalid gzip data");
+    }
+    if out.len() + len as usize > limit {
+        return Err(TOO_LARGE);
+    }
+    let data = bits.data.get(bits.pos..bits.pos + len as usize).ok_or("truncated gzip file")?;
+    out.extend_from_slice(data);
+    bits.pos += len as usize;
+    Ok(())
+}
+
+// Codes of the blocks compressed with the fixed codes
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+// Reads the codes of a block compressed with its own codes
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), &'static str> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+
+    // The code lengths are compressed with a code of their own
+    let mut lengths = [0u8; 19];
+    for i in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+        lengths[*i] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths);
+
+    let mut lengths = vec![];
+    while lengths.len() < literals + distances {
+        let (len, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("invalid gzip data")?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.extend(core::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths.len() > literals + distances {
+        return Err("invalid gzip data");
+    }
+
+    Ok((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])))
+}
+
+// Decompresses a block of literals and copies of earlier data
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, limit: usize, lit: &Huffman, dist: &Huffman) -> Result<(), &'static str> {
+    loop {
+        let symbol = lit.decode(bits)? as usize;
+        if symbol < 256 {
+            if out.len() >= limit {
+                return Err(TOO_LARGE);
+            }
+            out.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let symbol = symbol - 257;
+            if symbol >= LENGTH_BASE.len() {
+                return Err("invalid gzip data");
+            }
+            let len = LENGTH_BASE[symbol] as usize + bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+            let symbol = dist.decode(bits)? as usize;
+            if symbol >= DIST_BASE.len() {
+                return Err("invalid gzip data");
+            }
+            let distance = DIST_BASE[symbol] as usize + bits.bits(DIST_EXTRA[symbol] as u32)? as usize;
+            if distance > out.len() {
+                return Err("invalid gzip data");
+            }
+            if out.len() + len > limit {
+                return Err(TOO_LARGE);
+            }
+
+            // The copy can overlap the bytes it writes
+            let start = out.len() - distance;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, decompress, is_gzip};
+
+    // printf '\x60\x05\x12\x02' | gzip -n
+    const SMALL: [u8; 24] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x4b, 0x60,
+        0x15, 0x62, 0x02, 0x00, 0x0b, 0xfb, 0x53, 0x8a, 0x04, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn known_crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn decompress_small() {
+        assert!(is_gzip(&SMALL));
+        assert_eq!(decompress(&SMALL, 4).unwrap(), vec![0x60, 0x05, 0x12, 0x02]);
+
+        let mut corrupted = SMALL;
+        corrupted[16] ^= 1;
+        assert!(decompress(&corrupted, 4096).is_err());
+        assert!(decompress(&SMALL[..12], 4096).is_err());
+        assert!(decompress(&[0x60, 0x05], 4096).is_err());
+        assert!(decompress(&SMALL, 3).is_err());
+    }
+
+    #[test]
+    fn decompress_rom() {
+        // Dynamic codes and copies, made with gzip -9n
+        let rom = std::fs::read("roms/test/opcodes.ch8").unwrap();
+        let gz = std::fs::read("tests/data/opcodes.ch8.gz").unwrap();
+        assert_eq!(decompress(&gz, rom.len()).unwrap(), rom);
+        assert!(decompress(&gz, rom.len() - 1).is_err());
+    }
+
+    #[test]
+    fn decompress_bomb() {
+        // A megabyte of zeros in 1 KB, made with head -c 1M /dev/zero | gzip -9n
+        let gz = std::fs::read("tests/data/zeros.gz").unwrap();
+        assert_eq!(decompress(&gz, 1 << 20).unwrap().len(), 1 << 20);
+        assert_eq!(decompress(&gz, 4096), Err(super::TOO_LARGE));
+    }
+}
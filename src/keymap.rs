@@ -0,0 +1,117 @@
+use log::warn;
+
+// Parses a keymap file mapping keyboard key names to CHIP-8 keys, one
+// mapping per line in the form `name = value`, e.g.
+//
+//   # Top row
+//   "1" = 0x1
+//   Q = 0x4
+//
+// Names may be quoted and are returned as written, values are hexadecimal
+// with a 0x prefix or decimal. Every CHIP-8 key must be mapped at least once
+pub fn parse_keymap(contents: &str) -> Result<Vec<(String, u8)>, String> {
+    let mut entries: Vec<(String, u8)> = vec![];
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = line.split_once('=')
+            .ok_or(format!("line {}: expected `name = value`", n + 1))?;
+
+        let name = name.trim().trim_matches('"').to_string();
+        if name.is_empty() {
+            return Err(format!("line {}: missing key name", n + 1));
+        }
+
+        let value = value.trim();
+        let key = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => value.parse(),
+        }.map_err(|_| format!("line {}: invalid CHIP-8 key {}", n + 1, value))?;
+
+        if key > 0xF {
+            return Err(format!("line {}: CHIP-8 key {} is not in 0x0..=0xF", n + 1, value));
+        }
+
+        if entries.iter().any(|(other, _)| other.eq_ignore_ascii_case(&name)) {
+            warn!("Key {} is mapped more than once, using the last mapping", name);
+            entries.retain(|(other, _)| !other.eq_ignore_ascii_case(&name));
+        }
+
+        entries.push((name, key));
+    }
+
+    let missing: Vec<String> = (0..16u8)
+        .filter(|key| !entries.iter().any(|(_, k)| k == key))
+        .map(|key| format!("{:X}", key))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("CHIP-8 keys {} are not mapped", missing.join(", ")));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_keymap;
+
+    const SAMPLE: &str = r#"
+        # Same layout as the default one
+        "1" = 0x1
+        "2" = 0x2
+        "3" = 0x3
+        "4" = 0xC
+        Q = 0x4
+        W = 0x5
+        E = 0x6
+        R = 0xD
+        A = 0x7
+        S = 0x8
+        D = 0x9
+        F = 0xE
+        Z = 0xA
+        X = 0x0
+        C = 0xB
+        V = 15
+    "#;
+
+    #[test]
+    fn parse_sample() {
+        let entries = parse_keymap(SAMPLE).unwrap();
+        assert_eq!(entries.len(), 16);
+        assert_eq!(entries[0], ("1".to_string(), 0x1));
+        assert_eq!(entries[4], ("Q".to_string(), 0x4));
+        assert_eq!(entries[15], ("V".to_string(), 0xF));
+    }
+
+    #[test]
+    fn parse_duplicate() {
+        let keymap = format!("{}\nv = 0xF", SAMPLE);
+        let entries = parse_keymap(&keymap).unwrap();
+        assert_eq!(entries.len(), 16);
+        assert!(entries.contains(&("v".to_string(), 0xF)));
+        assert!(!entries.iter().any(|(name, _)| name == "V"));
+
+        // Remapping the only key of 0x4 leaves it unmapped
+        let keymap = format!("{}\nq = 0x5", SAMPLE);
+        assert!(parse_keymap(&keymap).is_err());
+    }
+
+    #[test]
+    fn parse_missing_key() {
+        let keymap = SAMPLE.replace("V = 15", "");
+        assert!(parse_keymap(&keymap).unwrap_err().contains("F"));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse_keymap("Q 0x4").is_err());
+        assert!(parse_keymap("Q = 0x10").is_err());
+        assert!(parse_keymap("Q = G").is_err());
+        assert!(parse_keymap("= 0x1").is_err());
+    }
+}
@@ -0,0 +1,37 @@
+// Simple rust CHIP-8 interpreter, the CPU is independent of the front-end
+// so it can be reused by other programs. Without the std feature only the
+// core is built, the CPU and what it needs, with alloc instead of std
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod benchmark;
+#[cfg(feature = "std")]
+pub mod compare;
+pub mod cpu;
+pub mod cycles;
+pub mod disasm;
+pub mod display;
+pub mod framebuffer;
+pub mod gzip;
+#[cfg(feature = "std")]
+pub mod keymap;
+pub mod opcodes;
+#[cfg(feature = "std")]
+pub mod overlay;
+pub mod png;
+#[cfg(feature = "std")]
+pub mod quirks;
+#[cfg(feature = "std")]
+pub mod recording;
+#[cfg(feature = "std")]
+pub mod roms;
+#[cfg(feature = "sdl")]
+pub mod screen;
+pub mod sha1;
+pub mod sha256;
+pub mod state_hash;
+#[cfg(feature = "tui")]
+pub mod term_screen;
+pub mod timing;
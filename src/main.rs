@@ -1,69 +1,1052 @@
-mod cpu;
-mod screen;
-
-use crate::cpu::Cpu;
-use crate::screen::Screen;
-use log::error;
+use chip_8::benchmark::run_benchmark;
+use chip_8::compare::compare;
+use chip_8::disasm::listing;
+use chip_8::cpu::{Cpu, CpuError, DetectedVariant, Machine, Quirks, StepOutcome, DEFAULT_PITCH, MACHINES, QUIRKS_PRESETS, RAM_SIZE, XOCHIP_RAM_SIZE};
+use chip_8::display::{Display, HeadlessScreen};
+use chip_8::framebuffer::Framebuffer;
+use chip_8::keymap::parse_keymap;
+use chip_8::png;
+use chip_8::quirks::parse_quirks;
+use chip_8::recording::{Playback, Recorder};
+use chip_8::state_hash::run_hash;
+use chip_8::roms::{has_rom_extension, list_roms, Menu, ROM_EXTENSIONS};
+use chip_8::screen::{default_keymap, keymap_from_names, FadeCurve, Screen, ScreenEvent, ScreenOptions, FADE_CURVES};
+#[cfg(feature = "tui")]
+use chip_8::term_screen::{Terminal, TermScreen};
+use chip_8::timing::FrameLimiter;
+use log::{error, info, warn};
 use clap::Parser;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::{Duration, Instant};
 
-const FRAME_RATE: u16 = 40;
+// Exit codes besides 0, clap exits with 2 on invalid arguments. A ROM or
+// another file that can't be loaded fails with EXIT_FAILURE, a ROM stopping
+// with an error with EXIT_CPU_ERROR
+const EXIT_FAILURE: u8 = 1;
+const EXIT_CPU_ERROR: u8 = 3;
+
+// Bounds and increment of the instructions per second set with the hotkeys
+const MIN_IPS: u32 = 1;
+const MAX_IPS: u32 = 5000;
+const IPS_STEP: u32 = 50;
+
+// Most frames run per frame while holding the turbo key, and how often the
+// display is redrawn meanwhile
+const MAX_TURBO: u32 = 64;
+const TURBO_REDRAW_FRAMES: u64 = 4;
+
+// Largest slowdown of the slow motion
+const MAX_SLOWDOWN: u32 = 100;
+
+// Quirks file read when --quirks-file is not given
+const DEFAULT_QUIRKS_FILE: &str = "quirks.toml";
 
 // Simple rust CHIP-8 interpreter
 #[derive(Parser)]
 struct Opts {
-    // The path to the ROM file to load into memory
+    // The path to the ROM file to load into memory, - to read it from stdin
     rom: String,
 
-    // The number of instructions to execute per second
-    #[clap(short, long, default_value = "500")]
+    // The number of instructions to execute per second, in the range the
+    // speed hotkeys change it in
+    #[clap(short, long, default_value = "500",
+        value_parser = clap::value_parser!(u16).range(MIN_IPS as i64..=MAX_IPS as i64))]
     ips: u16,
 
     // Whether to mute the sound
     #[clap(short, long, default_value = "false")]
     muted: bool,
+
+    // The size in window pixels of a single CHIP-8 pixel
+    #[clap(short, long, default_value = "12", value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+
+    // Whether to start in fullscreen mode (toggle with F11)
+    #[clap(short, long, default_value = "false")]
+    fullscreen: bool,
+
+    // The number of frames to draw per second
+    #[clap(long, default_value = "40", value_parser = clap::value_parser!(u16).range(1..))]
+    fps: u16,
+
+    // Whether to synchronize the frames with the display refresh rate. The
+    // frame pacing is then left to the vsync, which makes the effective frame
+    // rate the refresh rate of the display instead of --fps
+    #[clap(long, default_value = "false")]
+    vsync: bool,
+
+    // How the pixels turned off fade out: linear, exponential (like the
+    // phosphor of a CRT) or instant
+    #[clap(long, default_value = "linear", value_parser = parse_fade_curve)]
+    fade_curve: FadeCurve,
+
+    // Darken every row of pixels at the bottom and leave gaps between the
+    // pixels, like on a CRT
+    #[clap(long, default_value = "false")]
+    crt: bool,
+
+    // Width of the border around the display in CHIP-8 pixels, 0 to draw
+    // the display edge to edge
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u32).range(0..=16))]
+    border: u32,
+
+    // Color of the border, as RRGGBB in hexadecimal
+    #[clap(long, default_value = "000000", value_parser = parse_color)]
+    border_color: Color,
+
+    // Start with the grid between the pixels shown, toggled with F4
+    #[clap(long, default_value = "false")]
+    grid: bool,
+
+    // Color of the grid, as RRGGBB in hexadecimal
+    #[clap(long, default_value = "404040", value_parser = parse_color)]
+    grid_color: Color,
+
+    // How many frames are run in the time of one while Tab is held, to fast
+    // forward through slow parts of a program
+    #[clap(long, default_value = "8", value_parser = clap::value_parser!(u32).range(2..=MAX_TURBO as i64))]
+    turbo: u32,
+
+    // How many times slower the program runs in slow motion, toggled with F5
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u32).range(2..=MAX_SLOWDOWN as i64))]
+    slow_motion: u32,
+
+    // Stop executing the instructions of a frame after this many
+    // milliseconds and leave the rest to the next frame, so that a high
+    // --ips the computer can't keep up with doesn't make the frames late
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    frame_budget: Option<u64>,
+
+    // The memory address where the program is loaded and started from
+    // (0x200 by default, 0x600 for ETI-660 programs)
+    #[clap(long, value_parser = parse_address, conflicts_with = "machine")]
+    start_addr: Option<u16>,
+
+    // The machine to emulate: vip, eti660, schip or xochip. It sets the
+    // start address, the fontset address, the size of the RAM and the quirks
+    // used without a preset
+    #[clap(long, value_parser = parse_machine)]
+    machine: Option<Machine>,
+
+    // The size of the RAM in bytes, from 4096 (the default) to 65536 for
+    // XO-CHIP programs
+    #[clap(long, value_parser = clap::value_parser!(u32).range(RAM_SIZE as i64..=XOCHIP_RAM_SIZE as i64))]
+    ram_size: Option<u32>,
+
+    // The levels of subroutine calls the stack holds before the ROM fails,
+    // 16 by default or the ones of the machine
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    stack_depth: Option<u16>,
+
+    // A file with the 80 bytes of a custom fontset, the 5 rows of each of
+    // the digits 0 to F drawn by FX29
+    #[clap(long)]
+    font: Option<String>,
+
+    // Pin a byte of memory, written back after every instruction, e.g.
+    // `--cheat 0x2F0=9` to keep the lives of a game at 9. Can be repeated
+    #[clap(long, value_parser = parse_cheat)]
+    cheat: Vec<(u16, u8)>,
+
+    // Print every write of the program to this address of memory, with the
+    // address of the instruction and the old and new byte. Can be repeated
+    #[clap(long, value_parser = parse_address)]
+    watch: Vec<u16>,
+
+    // A file mapping keyboard keys to the CHIP-8 keys, one `name = value`
+    // per line (e.g. `Q = 0x4`), the QWERTY layout is used by default
+    #[clap(long)]
+    keymap: Option<String>,
+
+    // Treat the ROM path as a directory and pick the ROM to run from a menu
+    // (2/8 to move, 5 to select)
+    #[clap(long, default_value = "false")]
+    launcher: bool,
+
+    // A file to write a line to for every instruction executed, with its
+    // address, opcode, mnemonic and the registers it changed
+    #[clap(long)]
+    trace_file: Option<String>,
+
+    // Print a `PC: OPCODE MNEMONIC` line to stderr for each of the first
+    // instructions executed, 1000 unless a number is given
+    #[clap(long, num_args = 0..=1, default_missing_value = "1000")]
+    log_opcodes: Option<u64>,
+
+    // Print to stderr the pixels turned on and off by each frame drawn
+    #[clap(long)]
+    draw_diff: bool,
+
+    // Seed of the random numbers, to make runs reproducible
+    #[clap(long)]
+    seed: Option<u64>,
+
+    // Stop with an error on the unknown opcodes and the instructions that
+    // can't be emulated, like the 0NNN calls of machine code, instead of
+    // skipping them
+    #[clap(long)]
+    strict: bool,
+
+    // Keep running programs that end by jumping to themselves instead of
+    // halting the CPU
+    #[clap(long)]
+    no_halt: bool,
+
+    // The quirks of the platform the ROM was written for (chip8, schip or
+    // xochip), the original CHIP-8 ones without the display wait by default
+    #[clap(long, value_parser = parse_quirks_preset)]
+    quirks_preset: Option<Quirks>,
+
+    // A file with the quirks of known ROMs by SHA-1, used when no preset is
+    // given (quirks.toml in the current directory by default)
+    #[clap(long)]
+    quirks_file: Option<String>,
+
+    // Override single quirks of the preset, e.g. `--shift-quirk` or
+    // `--shift-quirk false`
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    vf_reset_quirk: Option<bool>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    shift_quirk: Option<bool>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    memory_quirk: Option<bool>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    jump_quirk: Option<bool>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    wrap_quirk: Option<bool>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    wrap_memory_quirk: Option<bool>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    clip_collision_quirk: Option<bool>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    index_overflow_quirk: Option<bool>,
+
+    // Make sprite drawing wait for the vertical blank like the COSMAC VIP,
+    // some games are tuned to it and run too fast without it
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    display_wait: Option<bool>,
+
+    // Run this many instructions (a million by default) as fast as possible
+    // without a window and print the timings
+    #[clap(long, num_args = 0..=1, default_missing_value = "1000000", conflicts_with = "launcher",
+        value_parser = clap::value_parser!(u64).range(1..))]
+    benchmark: Option<u64>,
+
+    // Print a listing of the ROM with labels for the jump and call targets
+    // instead of running it
+    #[clap(long, conflicts_with_all = ["launcher", "benchmark"])]
+    disassemble: bool,
+
+    // Run this other ROM in lockstep with the ROM without a window and print
+    // the first step at which their registers or displays differ
+    #[clap(long, conflicts_with_all = ["launcher", "benchmark", "disassemble"])]
+    compare: Option<String>,
+
+    // Run this many instructions without a window and print the SHA-256 of
+    // the registers, memory and display afterwards, with the seed 0 unless
+    // --seed is given. Runs of the same ROM and options print the same hash
+    #[clap(long, conflicts_with_all = ["launcher", "benchmark", "disassemble", "compare"])]
+    run_hash: Option<u64>,
+
+    // The number of steps compared by --compare
+    #[clap(long, default_value = "100000")]
+    compare_steps: u64,
+
+    // Count the executions of each opcode and print a histogram of them on
+    // exit
+    #[clap(long)]
+    opcode_stats: bool,
+
+    // Record the keys pressed on every frame and the seed to this file, to
+    // play the run back with --playback
+    #[clap(long)]
+    record: Option<String>,
+
+    // Play back the keys of a file written by --record, with its seed. The
+    // input is live again once it ends
+    #[clap(long)]
+    playback: Option<String>,
+
+    // A region of memory to print as a hex dump when quitting and when F7 is
+    // pressed, as START:LEN (e.g. 0x200:64). F7 prints the 64 bytes at I
+    // without it
+    #[clap(long, value_parser = parse_region)]
+    hex_dump: Option<(u16, u16)>,
+
+    // Write the display to this file as a PNG image when quitting, to
+    // compare the end of runs with a known image
+    #[clap(long)]
+    dump_screen_on_exit: Option<String>,
+
+    // Draw in the terminal instead of a window, Esc or Ctrl-C to quit
+    #[cfg(feature = "tui")]
+    #[clap(long, conflicts_with = "launcher")]
+    tui: bool,
 }
 
-fn main() {
+fn main() -> ExitCode {
 
     env_logger::init();
 
     let args = Opts::parse();
+    run(&args)
+}
+
+// Runs the emulator as asked on the command line and returns the exit code
+fn run(args: &Opts) -> ExitCode {
+    let mut cpu = match new_cpu(args) {
+        Ok(cpu) => cpu,
+        Err(code) => return code,
+    };
 
-    let mut cpu = Cpu::new();
+    if let Some(path) = &args.trace_file {
+        match File::create(path) {
+            Ok(file) => cpu.set_trace(Box::new(BufWriter::new(file))),
+            Err(e) => {
+                error!("Could not create the trace file {}: {}", path, e);
+                return ExitCode::from(EXIT_FAILURE);
+            }
+        }
+    }
+
+    if let Some(limit) = args.log_opcodes {
+        cpu.set_opcode_log(Box::new(io::stderr()), limit);
+    }
+
+    let keymap = match &args.keymap {
+        Some(path) => match load_keymap(path) {
+            Ok(keymap) => keymap,
+            Err(e) => {
+                error!("Could not load the keymap {}: {}", path, e);
+                return ExitCode::from(EXIT_FAILURE);
+            }
+        },
+        None => default_keymap(),
+    };
 
-    if let Err(e) = cpu.load_rom_file(&args.rom) {
-        error!("{:?}", e);
+    let options = ScreenOptions {
+        block_size: args.scale,
+        fullscreen: args.fullscreen,
+        vsync: args.vsync,
+        keymap,
+        fade_curve: args.fade_curve,
+        crt: args.crt,
+        border: args.border,
+        border_color: args.border_color,
+        grid: args.grid,
+        grid_color: args.grid_color,
+        title: "CHIP-8 EMU".to_string(),
+        draw_diff: args.draw_diff,
+    };
+
+    // In launcher mode the window is needed to pick the ROM
+    let mut screen = None;
+    let rom = if args.launcher {
+        let launcher_screen = screen.insert(Screen::new(options.clone()));
+        match pick_rom(launcher_screen, Path::new(&args.rom)) {
+            Ok(Some(rom)) => rom.to_string_lossy().into_owned(),
+            Ok(None) => return ExitCode::SUCCESS,
+            Err(e) => {
+                error!("Could not list the ROMs in {}: {}", args.rom, e);
+                return ExitCode::from(EXIT_FAILURE);
+            }
+        }
     } else {
-        let mut screen = Screen::new();
+        if args.rom != "-" && !has_rom_extension(Path::new(&args.rom)) {
+            warn!("{} does not have a usual ROM extension ({})", args.rom,
+                ROM_EXTENSIONS.map(|e| format!(".{}", e)).join(", "));
+        }
+        args.rom.clone()
+    };
 
-        // Instructions per frame
-        let ipf = args.ips / FRAME_RATE;
+    if let Err(e) = load_rom(&mut cpu, &rom) {
+        load_error(e)
+    } else {
+        let known = known_quirks(args.quirks_file.as_deref(), cpu.rom_sha1());
+        cpu.set_quirks(quirks(args, known));
 
-        loop {
+        if args.quirks_preset.is_none() && args.machine.is_none() && known.is_none() {
+            let variant = Cpu::detect_variant(cpu.rom());
+            if variant != DetectedVariant::Chip8 {
+                warn!("The ROM looks like a {:?} ROM, try --quirks-preset {}", variant, variant.preset());
+            }
+        }
+        let label = rom_label(&rom, cpu.rom().len());
+
+        if args.disassemble {
+            print!("{}", listing(cpu.rom(), cpu.start_address()));
+            return ExitCode::SUCCESS;
+        }
+
+        if let Some(other) = &args.compare {
+            return compare_roms(args, &mut cpu, other);
+        }
+
+        if let Some(steps) = args.benchmark {
+            return match run_benchmark(&mut cpu, steps) {
+                Ok(report) => {
+                    println!("{}", report);
+                    ExitCode::SUCCESS
+                },
+                Err(e) => cpu_error(e),
+            };
+        }
+
+        if let Some(steps) = args.run_hash {
+            if args.seed.is_none() {
+                cpu.set_seed(0);
+            }
+            let mut screen = HeadlessScreen::new();
+            let code = match run_hash(&mut cpu, steps, &mut screen) {
+                Ok(hash) => {
+                    println!("{}", hash);
+                    ExitCode::SUCCESS
+                },
+                Err(e) => cpu_error(e),
+            };
+            print_hex_dump(args, &cpu);
+            if let Some(path) = &args.dump_screen_on_exit {
+                dump_screen(path, screen.framebuffer());
+            }
+            return code;
+        }
+
+        #[cfg(feature = "tui")]
+        if args.tui {
+            if args.record.is_some() || args.playback.is_some() {
+                warn!("Recording and playback are only supported in the window");
+            }
+            let code = match run_tui(args, &mut cpu) {
+                Ok(Ok(())) => ExitCode::SUCCESS,
+                Ok(Err(e)) => cpu_error(e),
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::from(EXIT_FAILURE)
+                },
+            };
+            print_opcode_stats(&cpu);
+            print_hex_dump(args, &cpu);
+            return code;
+        }
+
+        let mut playback = match &args.playback {
+            Some(path) => match std::fs::read_to_string(path).map_err(|e| e.to_string())
+                .and_then(|contents| Playback::parse(&contents)) {
+                Ok(playback) => Some(playback),
+                Err(e) => {
+                    error!("Could not load the recording {}: {}", path, e);
+                    return ExitCode::from(EXIT_FAILURE);
+                }
+            },
+            None => None,
+        };
+
+        // Playing back at another speed would run the frames out of step,
+        // so the recorded speed wins over --ips and --fps
+        let ips = playback.as_ref().and_then(Playback::ips).unwrap_or(args.ips as u32);
+        let fps = playback.as_ref().and_then(Playback::fps).unwrap_or(args.fps as u32);
+
+        // The seed given on the command line wins over the one recorded, a
+        // new recording needs one to be reproducible
+        let seed = args.seed.or(playback.as_ref().and_then(Playback::seed))
+            .or_else(|| args.record.as_ref().map(|_| rand::random()));
+        if let Some(seed) = seed {
+            cpu.set_seed(seed);
+        }
+
+        let mut recorder = match &args.record {
+            Some(path) => match File::create(path).and_then(|file| Recorder::new(BufWriter::new(file), seed.unwrap(), ips, fps)) {
+                Ok(recorder) => Some(recorder),
+                Err(e) => {
+                    error!("Could not create the recording {}: {}", path, e);
+                    return ExitCode::from(EXIT_FAILURE);
+                }
+            },
+            None => None,
+        };
+
+        // Recorded runs go through frames of a fixed length, so that they
+        // execute the same instructions when played back
+        let fixed_frame = (recorder.is_some() || playback.is_some())
+            .then(|| Duration::from_secs(1) / fps);
+        let mut frame = 0u64;
+
+        let mut ips = ips;
+        let mut screen = match screen {
+            Some(screen) => screen,
+            None => Screen::new(options),
+        };
+        screen.set_title(&window_title(&label, ips, None));
+
+        // Frames and instructions since the achieved speed was last measured
+        let mut stats_start = Instant::now();
+        let mut stats_frames = 0;
+        let mut stats_instructions = cpu.instructions_executed();
+
+        cpu.set_ips(ips);
+        // Recorded runs have to execute all the instructions of each frame
+        if fixed_frame.is_none() {
+            cpu.set_frame_budget(args.frame_budget.map(Duration::from_millis));
+        }
+        let mut limiter = FrameLimiter::new(Duration::from_secs(1) / fps);
+        let mut last_frame = Instant::now();
+        // XO-CHIP audio pattern and pitch the sound is playing
+        let mut audio_pattern = (None, DEFAULT_PITCH);
+        // Whether the turbo key is held, and a frame drawn but not redrawn
+        let mut turbo = false;
+        let mut slow_motion = false;
+        let mut pending_draw = false;
+        let mut code = ExitCode::SUCCESS;
+
+        'running: loop {
 
             let start_frame = Instant::now();
 
-            let mut draw = false;
-            for _ in 0..ipf {
-                cpu.step(Some(&mut screen));
-                draw = draw || cpu.has_drawn();
+            // Run the CPU for the time elapsed since last frame, once halted
+            // only the input and the window are still handled
+            let elapsed = fixed_frame.unwrap_or(start_frame.duration_since(last_frame));
+            let frames = if turbo { args.turbo } else { 1 };
+            let outcome = match run_frames(&mut cpu, elapsed, frames, &mut screen) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    code = cpu_error(e);
+                    break 'running;
+                },
+            };
+            last_frame = start_frame;
+
+            // In turbo the display is only redrawn every few frames
+            pending_draw = pending_draw || outcome.drawn;
+            let draw = pending_draw && (!turbo || frame.is_multiple_of(TURBO_REDRAW_FRAMES));
+            if draw {
+                pending_draw = false;
+            }
+
+            let pattern = (cpu.audio_pattern().copied(), cpu.pitch());
+            if pattern != audio_pattern {
+                screen.set_audio_pattern(pattern.0, pattern.1);
+                audio_pattern = pattern;
+            }
+
+            if outcome.sound_changed {
+                if !args.muted && cpu.get_sound_timer() > 0 {
+                    screen.resume_beep();
+                } else {
+                    screen.pause_beep();
+                }
+            }
+
+            for event in screen.update(draw, &cpu.registers()) {
+                match event {
+                    ScreenEvent::Quit => break 'running,
+                    ScreenEvent::ToggleFullscreen => screen.toggle_fullscreen(),
+                    ScreenEvent::ToggleGrid => screen.toggle_grid(),
+                    ScreenEvent::HexDump => {
+                        let (start, len) = args.hex_dump.unwrap_or((cpu.registers().index, 64));
+                        print!("{}", cpu.hex_dump(start, len));
+                    },
+                    // Changing the speed, turbo and slow motion would run the
+                    // frames of a recording out of step
+                    ScreenEvent::SpeedUp | ScreenEvent::SlowDown if fixed_frame.is_none() => {
+                        ips = match event {
+                            ScreenEvent::SpeedUp => ips.saturating_add(IPS_STEP),
+                            _ => ips.saturating_sub(IPS_STEP),
+                        }.clamp(MIN_IPS, MAX_IPS);
+
+                        info!("Running at {} instructions per second", ips);
+                        cpu.set_ips(ips);
+                        screen.set_title(&window_title(&label, ips, None));
+                    },
+                    ScreenEvent::Turbo(on) if on != turbo && fixed_frame.is_none() => {
+                        info!("Turbo {}", if on { "on" } else { "off" });
+                        turbo = on;
+                    },
+                    ScreenEvent::ToggleSlowMotion if fixed_frame.is_none() => {
+                        slow_motion = !slow_motion;
+                        let factor = if slow_motion { args.slow_motion } else { 1 };
+                        info!("Running {} times slower", factor);
+                        cpu.set_slowdown(factor);
+                    },
+                    // Pausing either one would also desync a recording
+                    ScreenEvent::ToggleCpuPause if fixed_frame.is_none() => {
+                        let paused = !cpu.cpu_paused();
+                        info!("CPU {}", if paused { "paused" } else { "resumed" });
+                        cpu.set_cpu_paused(paused);
+                    },
+                    ScreenEvent::ToggleTimersPause if fixed_frame.is_none() => {
+                        let paused = !cpu.timers_paused();
+                        info!("Timers {}", if paused { "paused" } else { "resumed" });
+                        cpu.set_timers_paused(paused);
+                    },
+                    ScreenEvent::SpeedUp | ScreenEvent::SlowDown |
+                    ScreenEvent::Turbo(_) | ScreenEvent::ToggleSlowMotion |
+                    ScreenEvent::ToggleCpuPause | ScreenEvent::ToggleTimersPause => {},
+                }
+            }
+
+            if let Some(events) = playback.as_mut() {
+                for event in events.events_at(frame) {
+                    // The keys were checked when parsing the recording
+                    let _ = match event.pressed {
+                        true => screen.press_key(event.key),
+                        false => screen.release_key(event.key),
+                    };
+                }
+                if events.is_finished() {
+                    info!("The playback ended at frame {}, the input is live", frame);
+                    playback = None;
+                }
+            }
+
+            if let Some(rec) = recorder.as_mut() {
+                if let Err(e) = rec.record(frame, &screen) {
+                    error!("Could not write the recording, stopping it: {}", e);
+                    recorder = None;
+                }
             }
-            cpu.update_timers();
+            frame += 1;
 
-            let sound_timer =  cpu.get_sound_timer();
-            if !args.muted && sound_timer > 0 {
-                screen.resume_beep();
-            } else {
-                screen.pause_beep();
+            // Show the achieved speed about once per second
+            stats_frames += 1;
+            let stats_time = start_frame.duration_since(stats_start);
+            if stats_time >= Duration::from_secs(1) {
+                let secs = stats_time.as_secs_f64();
+                let executed = cpu.instructions_executed() - stats_instructions;
+                let achieved = (stats_frames as f64 / secs, executed as f64 / secs);
+                screen.set_title(&window_title(&label, ips, Some(achieved)));
+
+                stats_start = start_frame;
+                stats_frames = 0;
+                stats_instructions = cpu.instructions_executed();
             }
 
-            screen.update(draw);
-            
-            let frame_time = Instant::now().duration_since(start_frame);
-            if frame_time < Duration::from_millis(1000 / FRAME_RATE as u64) {
-                std::thread::sleep(Duration::from_millis(1000 / FRAME_RATE as u64) - frame_time);
+            // With vsync presenting the canvas already blocks until the next
+            // refresh, sleeping on top of it would only skip refreshes
+            if !args.vsync {
+                limiter.wait();
             }
         }
+
+        if let Some(Err(e)) = recorder.as_mut().map(Recorder::flush) {
+            error!("Could not write the recording: {}", e);
+        }
+        if let Some(path) = &args.dump_screen_on_exit {
+            dump_screen(path, screen.framebuffer());
+        }
+        print_opcode_stats(&cpu);
+        print_hex_dump(args, &cpu);
+        code
+    }
+}
+
+// Logs why the ROM couldn't be loaded and returns the exit code
+fn load_error(e: CpuError) -> ExitCode {
+    error!("{}", e);
+    ExitCode::from(EXIT_FAILURE)
+}
+
+// Logs the error the ROM stopped with and returns its exit code
+fn cpu_error(e: CpuError) -> ExitCode {
+    error!("{}", e);
+    ExitCode::from(EXIT_CPU_ERROR)
+}
+
+// Runs the CPU for frames frames of the elapsed time each, merging their
+// outcomes, the CPU waiting for the display doesn't hold the later frames back
+fn run_frames(cpu: &mut Cpu, elapsed: Duration, frames: u32, screen: &mut Screen) -> Result<StepOutcome, CpuError> {
+    let sound = cpu.get_sound_timer() > 0;
+    let mut outcome = StepOutcome::default();
+    for _ in 0..frames {
+        let frame = cpu.run_for(elapsed, Some(&mut *screen))?;
+        outcome = StepOutcome { drawn: outcome.drawn || frame.drawn, ..frame };
     }
-}   
+    outcome.sound_changed = sound != (cpu.get_sound_timer() > 0);
+    Ok(outcome)
+}
+
+// Runs the ROM in the terminal until Esc or Ctrl-C is typed
+#[cfg(feature = "tui")]
+fn run_tui(args: &Opts, cpu: &mut Cpu) -> io::Result<Result<(), CpuError>> {
+    let terminal = Terminal::enter()?;
+    let mut screen = TermScreen::new(io::stdout());
+
+    cpu.set_ips(args.ips as u32);
+    cpu.set_frame_budget(args.frame_budget.map(Duration::from_millis));
+    let mut limiter = FrameLimiter::new(Duration::from_secs(1) / args.fps as u32);
+    let mut last_frame = Instant::now();
+
+    let result = loop {
+        let start_frame = Instant::now();
+        if !screen.handle_input(&terminal.read_input()?, start_frame) {
+            break Ok(());
+        }
+
+        let outcome = match cpu.run_for(start_frame.duration_since(last_frame), Some(&mut screen)) {
+            Ok(outcome) => outcome,
+            Err(e) => break Err(e),
+        };
+        last_frame = start_frame;
+
+        if outcome.sound_changed && !args.muted && cpu.get_sound_timer() > 0 {
+            screen.beep()?;
+        }
+
+        screen.draw(terminal.size()?)?;
+        limiter.wait();
+    };
+
+    if let Some(path) = &args.dump_screen_on_exit {
+        dump_screen(path, screen.framebuffer());
+    }
+    Ok(result)
+}
+
+// Writes the display as a PNG image, for --dump-screen-on-exit
+fn dump_screen(path: &str, pixels: &Framebuffer) {
+    if let Err(e) = std::fs::write(path, png::encode(pixels)) {
+        error!("Could not write the screenshot {}: {}", path, e);
+    }
+}
+
+// Prints the region of memory of --hex-dump
+fn print_hex_dump(args: &Opts, cpu: &Cpu) {
+    if let Some((start, len)) = args.hex_dump {
+        print!("{}", cpu.hex_dump(start, len));
+    }
+}
+
+// Prints the opcodes executed, when counted with --opcode-stats
+fn print_opcode_stats(cpu: &Cpu) {
+    if let Some(stats) = cpu.opcode_stats() {
+        println!("Opcodes executed:");
+        print!("{}", stats);
+    }
+}
+
+// Creates a CPU with the start address, halting and seed given on the
+// command line, or the exit code of the options it rejects
+fn new_cpu(args: &Opts) -> Result<Cpu, ExitCode> {
+    let mut cpu = match args.machine {
+        Some(machine) => Cpu::new_for(machine),
+        None => Cpu::new(),
+    };
+
+    if let Some(size) = args.ram_size {
+        if let Err(e) = cpu.set_ram_size(size as usize) {
+            error!("{}", e);
+            return Err(ExitCode::from(EXIT_FAILURE));
+        }
+    }
+
+    // After the RAM size, so that programs can start past the first 4KB
+    if let Some(start_addr) = args.start_addr {
+        if let Err(e) = cpu.set_start_address(start_addr) {
+            error!("{}", e);
+            return Err(ExitCode::from(EXIT_FAILURE));
+        }
+    }
+
+    if let Some(depth) = args.stack_depth {
+        if let Err(e) = cpu.set_stack_depth(depth) {
+            error!("{}", e);
+            return Err(ExitCode::from(EXIT_FAILURE));
+        }
+    }
+
+    if let Some(path) = &args.font {
+        let loaded = std::fs::read(path).map_err(|e| e.to_string())
+            .and_then(|font| cpu.set_font(&font).map_err(|e| e.to_string()));
+        if let Err(e) = loaded {
+            error!("Could not load the font {}: {}", path, e);
+            return Err(ExitCode::from(EXIT_FAILURE));
+        }
+    }
+
+    cpu.set_halt_on_loop(!args.no_halt);
+    cpu.set_strict(args.strict);
+    cpu.set_opcode_stats(args.opcode_stats);
+
+    if let Some(seed) = args.seed {
+        cpu.set_seed(seed);
+    }
+
+    for addr in &args.watch {
+        cpu.watch_memory(*addr);
+    }
+    if !args.watch.is_empty() {
+        cpu.set_watch_callback(Box::new(|write| println!("{}", write)));
+    }
+
+    for (addr, value) in &args.cheat {
+        if let Err(e) = cpu.add_cheat(*addr, *value) {
+            warn!("Ignoring the cheat at 0x{:03X}: {}", addr, e);
+        }
+    }
+
+    Ok(cpu)
+}
+
+// Runs the other ROM in lockstep with the one loaded in cpu and prints where
+// they diverge. Both use the same seed so random numbers don't diverge
+fn compare_roms(args: &Opts, cpu: &mut Cpu, other: &str) -> ExitCode {
+    let mut other_cpu = match new_cpu(args) {
+        Ok(cpu) => cpu,
+        Err(code) => return code,
+    };
+    if let Err(e) = load_rom(&mut other_cpu, other) {
+        return load_error(e);
+    }
+    let known = known_quirks(args.quirks_file.as_deref(), other_cpu.rom_sha1());
+    other_cpu.set_quirks(quirks(args, known));
+
+    if args.seed.is_none() {
+        cpu.set_seed(0);
+        other_cpu.set_seed(0);
+    }
+
+    match compare(cpu, &mut other_cpu, args.compare_steps, args.ips as u32) {
+        Ok(Some(divergence)) => println!("{}", divergence),
+        Ok(None) => println!("No divergence in {} steps", args.compare_steps),
+        Err(e) => return cpu_error(e),
+    }
+    ExitCode::SUCCESS
+}
+
+// Returns the quirks preset given on the command line, or else the known
+// quirks of the ROM, with the single quirks given on top of it
+fn quirks(args: &Opts, known: Option<Quirks>) -> Quirks {
+    let mut quirks = args.quirks_preset.or(known)
+        .or(args.machine.map(Machine::quirks))
+        .unwrap_or_default();
+    let overrides = [
+        (args.vf_reset_quirk, &mut quirks.vf_reset),
+        (args.shift_quirk, &mut quirks.shift),
+        (args.memory_quirk, &mut quirks.memory_increment),
+        (args.jump_quirk, &mut quirks.jump),
+        (args.wrap_quirk, &mut quirks.wrap),
+        (args.display_wait, &mut quirks.display_wait),
+        (args.wrap_memory_quirk, &mut quirks.wrap_memory),
+        (args.clip_collision_quirk, &mut quirks.clip_collision),
+        (args.index_overflow_quirk, &mut quirks.index_overflow_sets_vf),
+    ];
+    for (value, quirk) in overrides {
+        if let Some(value) = value {
+            *quirk = value;
+        }
+    }
+    quirks
+}
+
+// Looks up the quirks of the ROM with the given SHA-1 in the quirks file
+fn known_quirks(path: Option<&str>, sha1: &str) -> Option<Quirks> {
+    // The default file is optional
+    let contents = match path {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| warn!("Could not read the quirks file {}: {}", path, e))
+            .ok()?,
+        None => std::fs::read_to_string(DEFAULT_QUIRKS_FILE).ok()?,
+    };
+
+    match parse_quirks(&contents) {
+        Ok(roms) => {
+            let quirks = roms.get(sha1).copied();
+            if quirks.is_some() {
+                info!("Using the quirks of ROM {} from the quirks file", sha1);
+            }
+            quirks
+        },
+        Err(e) => {
+            warn!("Invalid quirks file: {}", e);
+            None
+        },
+    }
+}
+
+// Formats the window title showing the ROM, the target speed and, once
+// measured, the achieved frames and instructions per second
+fn window_title(rom: &str, ips: u32, achieved: Option<(f64, f64)>) -> String {
+    match achieved {
+        Some((fps, achieved_ips)) =>
+            format!("CHIP-8 EMU - {} - {} IPS ({:.0} FPS, {:.0} IPS)", rom, ips, fps, achieved_ips),
+        None => format!("CHIP-8 EMU - {} - {} IPS", rom, ips),
+    }
+}
+
+// Names the ROM in the window title by its file name and size
+fn rom_label(path: &str, size: usize) -> String {
+    let name = match path {
+        "-" => "stdin".into(),
+        _ => Path::new(path).file_name().map_or(path.into(), |name| name.to_string_lossy()),
+    };
+    format!("{} ({} bytes)", name, size)
+}
+
+// Parses the name of a quirks preset
+fn parse_quirks_preset(name: &str) -> Result<Quirks, String> {
+    Quirks::preset(name)
+        .ok_or(format!("unknown preset {}, expected one of {}", name, QUIRKS_PRESETS.join(", ")))
+}
+
+// Parses the name of a machine
+fn parse_machine(name: &str) -> Result<Machine, String> {
+    Machine::from_name(name)
+        .ok_or(format!("unknown machine {}, expected one of {}", name, MACHINES.join(", ")))
+}
+
+// Parses the name of a fade curve
+fn parse_fade_curve(name: &str) -> Result<FadeCurve, String> {
+    FadeCurve::from_name(name)
+        .ok_or(format!("unknown fade curve {}, expected one of {}", name, FADE_CURVES.join(", ")))
+}
+
+// Parses a color written as RRGGBB in hexadecimal, optionally after a #
+fn parse_color(s: &str) -> Result<Color, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 {
+        return Err(format!("invalid color {}, expected RRGGBB", s));
+    }
+    let rgb = u32::from_str_radix(hex, 16).map_err(|e| format!("invalid color {}: {}", s, e))?;
+    Ok(Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
+
+// Parses a number, either decimal or hexadecimal with a 0x prefix
+fn parse_number(s: &str) -> Result<u16, ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+// Parses a memory address, like parse_number
+fn parse_address(s: &str) -> Result<u16, String> {
+    parse_number(s).map_err(|e| format!("invalid address {}: {}", s, e))
+}
+
+// Parses a cheat, ADDR=VAL with a number for each like parse_number
+fn parse_cheat(s: &str) -> Result<(u16, u8), String> {
+    let (addr, value) = s.split_once('=').ok_or(format!("invalid cheat {}: expected ADDR=VAL", s))?;
+    let value = parse_number(value.trim())
+        .map_err(|e| format!("invalid cheat {}: the value is not a number ({})", s, e))?;
+    let value = u8::try_from(value).map_err(|_| format!("invalid cheat {}: the value is not a byte", s))?;
+    Ok((parse_address(addr.trim())?, value))
+}
+
+// Parses a region of memory written START:LEN, both decimal or hexadecimal
+fn parse_region(s: &str) -> Result<(u16, u16), String> {
+    let (start, len) = s.split_once(':').ok_or(format!("invalid region {}: expected START:LEN", s))?;
+    Ok((parse_address(start.trim())?, parse_address(len.trim())?))
+}
+
+// Shows the ROMs in dir in a menu until one is selected, returns None if the
+// window is closed
+fn pick_rom(screen: &mut Screen, dir: &Path) -> io::Result<Option<PathBuf>> {
+    let mut menu = Menu::new(list_roms(dir)?);
+    if menu.selected_item().is_none() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no ROM files found"));
+    }
+
+    let labels = menu.labels();
+    let registers = Cpu::new().registers();
+    let mut previous = [false; 16];
+
+    loop {
+        if screen.update(false, &registers).contains(&ScreenEvent::Quit) {
+            return Ok(None);
+        }
+
+        // React to the keys as they get pressed
+        let mut pressed = [false; 16];
+        for (key, state) in pressed.iter_mut().enumerate() {
+            *state = screen.is_key_pressed(key as u8) && !previous[key];
+            previous[key] = screen.is_key_pressed(key as u8);
+        }
+
+        if pressed[0x2] {
+            menu.up();
+        }
+        if pressed[0x8] {
+            menu.down();
+        }
+        if pressed[0x5] {
+            return Ok(menu.selected_item().cloned());
+        }
+
+        screen.draw_menu(&labels, menu.selected());
+        std::thread::sleep(Duration::from_millis(1000 / 60));
+    }
+}
+
+// Loads the ROM from the file at path, or from stdin if path is -
+fn load_rom(cpu: &mut Cpu, path: &str) -> Result<(), CpuError> {
+    if path == "-" {
+        cpu.load_rom_from_reader(&mut io::stdin())
+    } else {
+        cpu.load_rom_file(path)
+    }
+}
+
+// Reads and parses a keymap file
+fn load_keymap(path: &str) -> Result<HashMap<Keycode, u8>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    keymap_from_names(&parse_keymap(&contents)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load_error, load_rom, new_cpu, parse_cheat, rom_label, Opts, EXIT_FAILURE};
+    use chip_8::cpu::Cpu;
+    use clap::Parser;
+    use std::process::ExitCode;
+
+    #[test]
+    fn missing_rom_fails() {
+        let loaded = load_rom(&mut Cpu::new(), "roms/missing.ch8").map_err(load_error);
+        assert_eq!(loaded, Err(ExitCode::from(EXIT_FAILURE)));
+    }
+
+    #[test]
+    fn rom_labels() {
+        assert_eq!(rom_label("roms/pong.ch8", 246), "pong.ch8 (246 bytes)");
+        assert_eq!(rom_label("-", 2), "stdin (2 bytes)");
+    }
+
+    #[test]
+    fn cheats() {
+        assert_eq!(parse_cheat("0x2A0=0xFF"), Ok((0x2A0, 0xFF)));
+        assert_eq!(parse_cheat("0x2A0=zz"),
+            Err("invalid cheat 0x2A0=zz: the value is not a number (invalid digit found in string)".to_string()));
+        assert_eq!(parse_cheat("0x2A0=256"), Err("invalid cheat 0x2A0=256: the value is not a byte".to_string()));
+        assert!(parse_cheat("0xZZZ=1").unwrap_err().starts_with("invalid address 0xZZZ"));
+    }
+
+    #[test]
+    fn option_ranges() {
+        let parse = |args: &[&str]| Opts::try_parse_from(["chip-8", "rom.ch8"].iter().chain(args));
+        assert!(parse(&["--benchmark", "0"]).is_err());
+        assert_eq!(parse(&["--benchmark"]).unwrap().benchmark, Some(1000000));
+        assert_eq!(parse(&["--benchmark", "4294967296"]).unwrap().benchmark, Some(1 << 32));
+
+        assert!(parse(&["--ips", "0"]).is_err());
+        assert!(parse(&["--ips", "5001"]).is_err());
+        assert_eq!(parse(&["--ips", "5000"]).unwrap().ips, 5000);
+    }
+
+    #[test]
+    fn rejected_options() {
+        let cpu = |args: &[&str]| new_cpu(&Opts::try_parse_from(["chip-8", "rom.ch8"].iter().chain(args)).unwrap());
+        assert_eq!(cpu(&["--start-addr", "0x10"]).err(), Some(ExitCode::from(EXIT_FAILURE)));
+        assert_eq!(cpu(&["--start-addr", "0x2000"]).err(), Some(ExitCode::from(EXIT_FAILURE)));
+        assert_eq!(cpu(&["--start-addr", "0x2000", "--ram-size", "65536"]).unwrap().start_address(), 0x2000);
+        assert_eq!(cpu(&["--stack-depth", "41"]).err(), Some(ExitCode::from(EXIT_FAILURE)));
+        assert_eq!(cpu(&["--stack-depth", "40"]).unwrap().stack_depth(), 40);
+        assert_eq!(cpu(&["--font", "fonts/missing.bin"]).err(), Some(ExitCode::from(EXIT_FAILURE)));
+        assert_eq!(cpu(&["--font", "Cargo.toml"]).err(), Some(ExitCode::from(EXIT_FAILURE)));
+    }
+}
@@ -0,0 +1,150 @@
+// Counts of the opcodes executed by a run, to see what a program exercises
+use core::fmt;
+
+// The 35 opcodes of the original CHIP-8
+pub const OPCODES: [&str; 35] = [
+    "0NNN", "00E0", "00EE", "1NNN", "2NNN", "3XNN", "4XNN", "5XY0", "6XNN", "7XNN",
+    "8XY0", "8XY1", "8XY2", "8XY3", "8XY4", "8XY5", "8XY6", "8XY7", "8XYE", "9XY0",
+    "ANNN", "BNNN", "CXNN", "DXYN", "EX9E", "EXA1", "FX07", "FX0A", "FX15", "FX18",
+    "FX1E", "FX29", "FX33", "FX55", "FX65",
+];
+
+// Width of the longest bar of the histogram
+const BAR_WIDTH: u64 = 40;
+
+// Returns the index in OPCODES of the opcode, None for the ones of the
+// extensions and the invalid ones
+pub fn opcode_index(opcode: u16) -> Option<usize> {
+    let n = (opcode & 0x000F) as usize;
+    let nn = opcode & 0x00FF;
+
+    let index = match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => 1,
+            0x00EE => 2,
+            _ => 0,
+        },
+        0x5000 if n == 0 => 7,
+        0x5000 => return None,
+        0x8000 => match n {
+            0x0..=0x7 => 10 + n,
+            0xE => 18,
+            _ => return None,
+        },
+        0x9000 if n == 0 => 19,
+        0x9000 => return None,
+        0xE000 => match nn {
+            0x9E => 24,
+            0xA1 => 25,
+            _ => return None,
+        },
+        0xF000 => match nn {
+            0x07 => 26,
+            0x0A => 27,
+            0x15 => 28,
+            0x18 => 29,
+            0x1E => 30,
+            0x29 => 31,
+            0x33 => 32,
+            0x55 => 33,
+            0x65 => 34,
+            _ => return None,
+        },
+        // 1NNN to 4XNN, 6XNN and 7XNN, then ANNN to DXYN
+        high => match high >> 12 {
+            high @ 0x1..=0x7 => 2 + high as usize,
+            high => 10 + high as usize,
+        },
+    };
+    Some(index)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeStats {
+    // Executions of each of OPCODES
+    counts: [u64; 35],
+    // Executions of the other opcodes
+    other: u64,
+}
+
+impl Default for OpcodeStats {
+    fn default() -> OpcodeStats {
+        OpcodeStats::new()
+    }
+}
+
+impl OpcodeStats {
+    pub fn new() -> OpcodeStats {
+        OpcodeStats { counts: [0; 35], other: 0 }
+    }
+
+    // Counts an execution of the opcode
+    pub fn record(&mut self, opcode: u16) {
+        match opcode_index(opcode) {
+            Some(i) => self.counts[i] += 1,
+            None => self.other += 1,
+        }
+    }
+
+    // Returns the executions of the opcode named name, one of OPCODES
+    pub fn count(&self, name: &str) -> Option<u64> {
+        OPCODES.iter().position(|n| *n == name).map(|i| self.counts[i])
+    }
+
+    // Returns the executions of the opcodes not in OPCODES
+    pub fn other(&self) -> u64 {
+        self.other
+    }
+}
+
+impl fmt::Display for OpcodeStats {
+    // Writes a histogram of the opcodes executed at least once
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = OPCODES.iter().zip(self.counts).chain(core::iter::once((&"other", self.other)));
+        let max = self.counts.iter().copied().chain([self.other]).max().unwrap_or(0).max(1);
+        for (name, count) in rows.filter(|(_, count)| *count > 0) {
+            let bar = (count * BAR_WIDTH).div_ceil(max) as usize;
+            writeln!(f, "{:<5} {:>10} {}", name, count, "#".repeat(bar))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{opcode_index, OpcodeStats, OPCODES};
+
+    #[test]
+    fn indexes_match_names() {
+        let examples = [
+            0x0123, 0x00E0, 0x00EE, 0x1234, 0x2345, 0x3456, 0x4567, 0x5670, 0x6789, 0x789A,
+            0x8120, 0x8121, 0x8122, 0x8123, 0x8124, 0x8125, 0x8126, 0x8127, 0x812E, 0x9120,
+            0xA123, 0xB123, 0xC123, 0xD123, 0xE19E, 0xE1A1, 0xF107, 0xF10A, 0xF115, 0xF118,
+            0xF11E, 0xF129, 0xF133, 0xF155, 0xF165,
+        ];
+        for (i, opcode) in examples.iter().enumerate() {
+            assert_eq!(opcode_index(*opcode), Some(i), "{} {:04X}", OPCODES[i], opcode);
+        }
+        for opcode in [0x5121, 0x8128, 0x9121, 0xE1FF, 0xF000, 0xF13A] {
+            assert_eq!(opcode_index(opcode), None);
+        }
+    }
+
+    #[test]
+    fn histogram() {
+        let mut stats = OpcodeStats::new();
+        for opcode in [0x6001, 0x6002, 0x6003, 0x6004, 0x00E0, 0xF000] {
+            stats.record(opcode);
+        }
+        assert_eq!(stats.count("6XNN"), Some(4));
+        assert_eq!(stats.count("00E0"), Some(1));
+        assert_eq!(stats.count("DXYN"), Some(0));
+        assert_eq!(stats.count("XXXX"), None);
+        assert_eq!(stats.other(), 1);
+
+        assert_eq!(stats.to_string(), format!(
+            "00E0           1 {}\n6XNN           4 {}\nother          1 {}\n",
+            "#".repeat(10), "#".repeat(40), "#".repeat(10),
+        ));
+    }
+}
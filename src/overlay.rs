@@ -0,0 +1,148 @@
+use crate::cpu::CpuSnapshot;
+
+// Width and height of a glyph of the overlay font, in font pixels
+pub const GLYPH_WIDTH: u32 = 3;
+pub const GLYPH_HEIGHT: u32 = 5;
+
+// Horizontal and vertical distance between glyphs, in font pixels
+pub const GLYPH_ADVANCE: u32 = GLYPH_WIDTH + 1;
+pub const LINE_ADVANCE: u32 = GLYPH_HEIGHT + 2;
+
+// The keys of the CHIP-8 keypad as laid out on the COSMAC VIP, row by row
+pub const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// Side of a key of the keypad overlay in font pixels, a glyph with a one
+// pixel margin
+pub const KEY_SIZE: u32 = GLYPH_HEIGHT + 2;
+
+// Rows of a 3x5 glyph, the 3 low bits of each row are the pixels from left
+// to right. Unknown characters are drawn as spaces
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b100, 0b100],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b111, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b111, 0b100, 0b100, 0b100, 0b111],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b111, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b111, 0b100, 0b100],
+        'G' => [0b111, 0b100, 0b101, 0b101, 0b111],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b111],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b111, 0b101, 0b111, 0b100, 0b100],
+        'Q' => [0b111, 0b101, 0b101, 0b111, 0b001],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        _ => [0; 5],
+    }
+}
+
+// Returns the coordinates, in font pixels, of the lit pixels of a line of
+// text starting at (0, 0)
+pub fn text_pixels(text: &str) -> Vec<(u32, u32)> {
+    let mut pixels = vec![];
+    for (i, c) in text.chars().enumerate() {
+        for (y, row) in glyph(c).iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if (row >> (GLYPH_WIDTH - 1 - x)) & 1 == 1 {
+                    pixels.push((i as u32 * GLYPH_ADVANCE + x, y as u32));
+                }
+            }
+        }
+    }
+    pixels
+}
+
+// Formats the registers and timers shown by the debug overlay, one line each
+pub fn register_lines(registers: &CpuSnapshot) -> Vec<String> {
+    let mut lines = vec![
+        format!("PC:{:04X} I:{:04X} SP:{:02X}", registers.pc, registers.index, registers.sp),
+        format!("DT:{:02X} ST:{:02X}", registers.delay_timer, registers.sound_timer),
+    ];
+    for (n, values) in registers.v_reg.chunks(4).enumerate() {
+        let line: Vec<String> = values.iter().enumerate()
+            .map(|(i, v)| format!("V{:X}:{:02X}", n * 4 + i, v))
+            .collect();
+        lines.push(line.join(" "));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::{register_lines, text_pixels, GLYPH_ADVANCE, KEYPAD};
+    use crate::cpu::CpuSnapshot;
+
+    #[test]
+    fn text_pixels_layout() {
+        // The 1 glyph has 8 lit pixels
+        let one = text_pixels("1");
+        assert_eq!(one.len(), 8);
+        assert!(one.contains(&(1, 0)));
+        assert!(!one.contains(&(0, 0)));
+
+        // Following glyphs are shifted by the advance
+        let two = text_pixels(" 1");
+        assert!(two.iter().all(|(x, _)| *x >= GLYPH_ADVANCE));
+        assert_eq!(two.len(), 8);
+    }
+
+    #[test]
+    fn keypad_has_every_key() {
+        let mut keys: Vec<u8> = KEYPAD.iter().flatten().copied().collect();
+        keys.sort();
+        assert_eq!(keys, (0..16).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn register_lines_format() {
+        let mut v_reg = [0; 16];
+        v_reg[0xA] = 0x3C;
+        let registers = CpuSnapshot {
+            pc: 0x200,
+            sp: 2,
+            index: 0xABC,
+            v_reg,
+            delay_timer: 0x10,
+            sound_timer: 0,
+        };
+
+        let lines = register_lines(&registers);
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "PC:0200 I:0ABC SP:02");
+        assert_eq!(lines[1], "DT:10 ST:00");
+        assert_eq!(lines[2], "V0:00 V1:00 V2:00 V3:00");
+        assert_eq!(lines[4], "V8:00 V9:00 VA:3C VB:00");
+    }
+}
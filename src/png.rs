@@ -0,0 +1,150 @@
+// Screenshots of the display as PNG images, black and white with one bit per
+// pixel. The image data is stored uncompressed, a CHIP-8 screen only takes a
+// few hundred bytes
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::framebuffer::Framebuffer;
+use crate::gzip::crc32;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// Largest block of data stored without compression by deflate
+const MAX_STORED: usize = 0xFFFF;
+
+// Encodes the pixels as a PNG image, the lit pixels white
+pub fn encode(pixels: &Framebuffer) -> Vec<u8> {
+    let (width, height) = (pixels.width(), pixels.height());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 1 bit greyscale, deflate, no filter, not interlaced
+    header.extend_from_slice(&[1, 0, 0, 0, 0]);
+
+    // Every row starts with its filter type, none, and the pixels of a row
+    // go from the high bit of its first byte
+    let mut rows = Vec::new();
+    for y in 0..height {
+        rows.push(0);
+        let row = rows.len();
+        rows.resize(row + width.div_ceil(8) as usize, 0);
+        for x in 0..width {
+            rows[row + x as usize / 8] |= pixels.get(x, y) << (7 - x % 8);
+        }
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&rows));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// Appends a chunk, its length, type, data and CRC of the type and the data
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Wraps data in a zlib stream of deflate blocks stored without compression
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32KB window, no dictionary and the fastest level
+    let mut out = vec![0x78, 0x01];
+    let blocks = data.chunks(MAX_STORED).collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i + 1 == blocks.len();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    if data.is_empty() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Checksum of the uncompressed data of a zlib stream
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::{adler32, encode, SIGNATURE};
+    use crate::cpu::Cpu;
+    use crate::display::HeadlessScreen;
+    use crate::framebuffer::Framebuffer;
+    use crate::gzip::crc32;
+
+    // Splits a PNG in its chunks, checking their CRC
+    fn chunks(png: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut chunks = vec![];
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + len]));
+            chunks.push((kind, data));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn known_adler() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn headless_screenshot() {
+        // Draw the 0 glyph at (2, 1) and jump to itself
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&[0x60, 0x02, 0x61, 0x01, 0xF2, 0x29, 0xD0, 0x15, 0x12, 0x08]).unwrap();
+        let mut screen = HeadlessScreen::new();
+        cpu.run_steps(100, Some(&mut screen)).unwrap();
+        assert!(cpu.is_halted());
+
+        let png = encode(screen.framebuffer());
+        let chunks = chunks(&png);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 64, 0, 0, 0, 32, 1, 0, 0, 0, 0]);
+
+        // A single stored block of 32 rows of a filter byte and 8 bytes
+        let idat = chunks[1].1;
+        assert_eq!(idat[..7], [0x78, 0x01, 1, 0x20, 0x01, 0xDF, 0xFE]);
+        let rows: Vec<&[u8]> = idat[7..7 + 32 * 9].chunks(9).collect();
+        assert!(rows.iter().all(|row| row[0] == 0));
+        assert_eq!(rows[0][1..], [0; 8]);
+        // 0xF0 0x90 0x90 0x90 0xF0 shifted right by 2
+        let glyph: Vec<u8> = rows[1..6].iter().map(|row| row[1]).collect();
+        assert_eq!(glyph, [0x3C, 0x24, 0x24, 0x24, 0x3C]);
+        assert!(rows[6..].iter().all(|row| row[1..] == [0; 8]));
+    }
+
+    #[test]
+    fn odd_sizes() {
+        let mut pixels = Framebuffer::new(10, 2);
+        pixels.xor(9, 1, 1);
+        let png = encode(&pixels);
+        let idat = chunks(&png)[1].1;
+        // Rows of 2 bytes, the last pixel in the second high bit
+        assert_eq!(idat[7..13], [0, 0, 0, 0, 0, 0x40]);
+    }
+}
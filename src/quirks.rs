@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use crate::cpu::Quirks;
+
+// Parses a quirks file mapping the SHA-1 of known ROMs to the quirks they
+// need, one section per ROM in a subset of TOML, e.g.
+//
+//   # A SUPER-CHIP game
+//   [0123456789abcdef0123456789abcdef01234567]
+//   preset = "schip"
+//   jump = false
+//
+// The preset is one of QUIRKS_PRESETS, the default quirks are used when
+// missing. The other keys are the fields of Quirks and override the preset
+pub fn parse_quirks(contents: &str) -> Result<HashMap<String, Quirks>, String> {
+    let mut roms = HashMap::new();
+
+    let mut section: Option<Section> = None;
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let hash = header.trim().trim_matches('"').to_ascii_lowercase();
+            if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("line {}: {} is not a SHA-1", n + 1, header));
+            }
+            let next = Section { hash, preset: None, overrides: vec![] };
+            if let Some(section) = section.replace(next) {
+                roms.insert(section.hash.clone(), section.quirks());
+            }
+            continue;
+        }
+
+        let (key, value) = line.split_once('=')
+            .ok_or(format!("line {}: expected `key = value` or `[sha1]`", n + 1))?;
+        let (key, value) = (key.trim(), value.trim());
+
+        let section = section.as_mut()
+            .ok_or(format!("line {}: {} is not in a [sha1] section", n + 1, key))?;
+
+        if key == "preset" {
+            let name = value.trim_matches('"');
+            section.preset = Some(Quirks::preset(name)
+                .ok_or(format!("line {}: unknown preset {}", n + 1, name))?);
+            continue;
+        }
+
+        if !QUIRK_NAMES.contains(&key) {
+            return Err(format!("line {}: unknown quirk {}", n + 1, key));
+        }
+        let value = value.parse()
+            .map_err(|_| format!("line {}: {} is not true or false", n + 1, value))?;
+        section.overrides.push((key.to_string(), value));
+    }
+
+    if let Some(section) = section {
+        roms.insert(section.hash.clone(), section.quirks());
+    }
+
+    Ok(roms)
+}
+
+// Names of the fields of Quirks accepted in the quirks file
+const QUIRK_NAMES: [&str; 9] = [
+    "vf_reset", "shift", "memory_increment", "jump", "wrap", "display_wait", "wrap_memory",
+    "clip_collision", "index_overflow_sets_vf",
+];
+
+// A ROM of the quirks file, the preset can come after the overrides
+struct Section {
+    hash: String,
+    preset: Option<Quirks>,
+    overrides: Vec<(String, bool)>,
+}
+
+impl Section {
+    // Returns the preset with the overrides applied
+    fn quirks(self) -> Quirks {
+        let mut quirks = self.preset.unwrap_or_default();
+        for (key, value) in self.overrides {
+            let quirk = match key.as_str() {
+                "vf_reset" => &mut quirks.vf_reset,
+                "shift" => &mut quirks.shift,
+                "memory_increment" => &mut quirks.memory_increment,
+                "jump" => &mut quirks.jump,
+                "wrap" => &mut quirks.wrap,
+                "wrap_memory" => &mut quirks.wrap_memory,
+                "clip_collision" => &mut quirks.clip_collision,
+                "index_overflow_sets_vf" => &mut quirks.index_overflow_sets_vf,
+                _ => &mut quirks.display_wait,
+            };
+            *quirk = value;
+        }
+        quirks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_quirks;
+    use crate::cpu::Quirks;
+
+    const SAMPLE: &str = r#"
+        # A SUPER-CHIP game using the original jump
+        ["0123456789ABCDEF0123456789abcdef01234567"]
+        jump = false
+        preset = "schip"
+
+        [89abcdef0123456789abcdef0123456789abcdef]
+        wrap = true
+        wrap_memory = true
+    "#;
+
+    #[test]
+    fn parse_sample() {
+        let roms = parse_quirks(SAMPLE).unwrap();
+        assert_eq!(roms.len(), 2);
+
+        let quirks = roms["0123456789abcdef0123456789abcdef01234567"];
+        assert_eq!(quirks, Quirks { jump: false, ..Quirks::SCHIP });
+
+        let quirks = roms["89abcdef0123456789abcdef0123456789abcdef"];
+        assert_eq!(quirks, Quirks { wrap: true, wrap_memory: true, ..Quirks::default() });
+    }
+
+    #[test]
+    fn loaded_rom_quirks() {
+        let mut cpu = crate::cpu::Cpu::new();
+        cpu.load_rom(&[0x00, 0xE0, 0x12, 0x02]).unwrap();
+
+        let contents = format!("[{}]\npreset = \"xochip\"", cpu.rom_sha1());
+        let roms = parse_quirks(&contents).unwrap();
+        assert_eq!(roms.get(cpu.rom_sha1()), Some(&Quirks::XOCHIP));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse_quirks("shift = true").is_err());
+        assert!(parse_quirks("[0123]").is_err());
+        assert!(parse_quirks("[89abcdef0123456789abcdef0123456789abcdef]\nshift = yes").is_err());
+        assert!(parse_quirks("[89abcdef0123456789abcdef0123456789abcdef]\nturbo = true").is_err());
+        assert!(parse_quirks("[89abcdef0123456789abcdef0123456789abcdef]\npreset = \"chip48\"").is_err());
+    }
+}
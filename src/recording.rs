@@ -0,0 +1,241 @@
+// Recording of the keypad input frame by frame and its playback, which with
+// the seed of the random numbers makes runs reproducible, for bug reports
+// and tool-assisted runs. A recording is a text file with the seed, the
+// instructions and frames per second it was recorded at and a line for every
+// key pressed or released, e.g.
+//
+//   seed 1234
+//   ips 500
+//   fps 40
+//   120 5 down
+//   131 5 up
+//
+// The events of a frame apply to the keypad after the input of the frame was
+// handled
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::display::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:X} {}", self.frame, self.key, if self.pressed { "down" } else { "up" })
+    }
+}
+
+// Writes the changes of the keypad of every frame to out
+pub struct Recorder<W: Write> {
+    out: W,
+    keys: [bool; 16],
+}
+
+impl<W: Write> Recorder<W> {
+    // Starts a recording of a run whose random numbers come from seed,
+    // running ips instructions and fps frames per second
+    pub fn new(mut out: W, seed: u64, ips: u32, fps: u32) -> io::Result<Recorder<W>> {
+        writeln!(out, "seed {}", seed)?;
+        writeln!(out, "ips {}", ips)?;
+        writeln!(out, "fps {}", fps)?;
+        Ok(Recorder { out, keys: [false; 16] })
+    }
+
+    // Records the keys of the display pressed or released since the last
+    // frame
+    pub fn record(&mut self, frame: u64, display: &dyn Display) -> io::Result<()> {
+        for key in 0..16u8 {
+            let pressed = display.is_key_pressed(key);
+            if pressed != self.keys[key as usize] {
+                self.keys[key as usize] = pressed;
+                writeln!(self.out, "{}", KeyEvent { frame, key, pressed })?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes what is still buffered
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Feeds the events of a recording back frame by frame
+#[derive(Debug)]
+pub struct Playback {
+    seed: Option<u64>,
+    ips: Option<u32>,
+    fps: Option<u32>,
+    events: Vec<KeyEvent>,
+    // Next event to play
+    next: usize,
+}
+
+impl Playback {
+    // Parses a recording, the frames of the events must not decrease
+    pub fn parse(contents: &str) -> Result<Playback, String> {
+        let mut seed = None;
+        let (mut ips, mut fps) = (None, None);
+        let mut events: Vec<KeyEvent> = vec![];
+
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let ["seed", value] = fields[..] {
+                seed = Some(value.parse().map_err(|_| format!("line {}: invalid seed {}", n + 1, value))?);
+                continue;
+            }
+            if let [name @ ("ips" | "fps"), value] = fields[..] {
+                let rate = value.parse().ok().filter(|rate| *rate > 0)
+                    .ok_or(format!("line {}: invalid {} {}", n + 1, name, value))?;
+                match name {
+                    "ips" => ips = Some(rate),
+                    _ => fps = Some(rate),
+                }
+                continue;
+            }
+
+            let [frame, key, state] = fields[..] else {
+                return Err(format!("line {}: expected `frame key down|up`", n + 1));
+            };
+            let frame = frame.parse().map_err(|_| format!("line {}: invalid frame {}", n + 1, frame))?;
+            let key = u8::from_str_radix(key, 16).ok().filter(|key| *key <= 0xF)
+                .ok_or(format!("line {}: invalid CHIP-8 key {}", n + 1, key))?;
+            let pressed = match state {
+                "down" => true,
+                "up" => false,
+                _ => return Err(format!("line {}: expected down or up, not {}", n + 1, state)),
+            };
+
+            if events.last().is_some_and(|last| last.frame > frame) {
+                return Err(format!("line {}: frame {} is before the previous event", n + 1, frame));
+            }
+            events.push(KeyEvent { frame, key, pressed });
+        }
+
+        Ok(Playback { seed, ips, fps, events, next: 0 })
+    }
+
+    // Seed of the random numbers of the recorded run
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    // Instructions per second of the recorded run
+    pub fn ips(&self) -> Option<u32> {
+        self.ips
+    }
+
+    // Frames per second of the recorded run
+    pub fn fps(&self) -> Option<u32> {
+        self.fps
+    }
+
+    // Returns the events of the frame, skipping the ones of earlier frames
+    // not asked for
+    pub fn events_at(&mut self, frame: u64) -> &[KeyEvent] {
+        while self.events.get(self.next).is_some_and(|event| event.frame < frame) {
+            self.next += 1;
+        }
+        let start = self.next;
+        while self.events.get(self.next).is_some_and(|event| event.frame == frame) {
+            self.next += 1;
+        }
+        &self.events[start..self.next]
+    }
+
+    // Whether all the events were played
+    pub fn is_finished(&self) -> bool {
+        self.next == self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyEvent, Playback, Recorder};
+    use crate::cpu::{Cpu, CpuSnapshot};
+    use crate::display::HeadlessScreen;
+    use std::time::Duration;
+
+    // Loop waiting for keys, counted in V3 and summed in V1, with a random
+    // number in V4 after each one
+    const ROM: [u8; 10] = [0xF2, 0x0A, 0x81, 0x24, 0x73, 0x01, 0xC4, 0xFF, 0x12, 0x00];
+
+    // Runs the ROM for 60 frames calling input after each one, which sets
+    // the keys of the next frame
+    fn run(seed: u64, mut input: impl FnMut(u64, &mut HeadlessScreen)) -> CpuSnapshot {
+        let mut cpu = Cpu::with_seed(seed);
+        cpu.load_rom(&ROM).unwrap();
+        let mut screen = HeadlessScreen::new();
+        for frame in 0..60 {
+            cpu.run_for(Duration::from_secs(1) / 60, Some(&mut screen)).unwrap();
+            input(frame, &mut screen);
+        }
+        cpu.registers()
+    }
+
+    #[test]
+    fn record_and_play_back() {
+        let mut recorder = Recorder::new(vec![], 42, 500, 60).unwrap();
+        let typed = [(5, 0x3), (12, 0xA), (20, 0x3), (40, 0xF)];
+        let recorded = run(42, |frame, screen| {
+            for key in 0..16 {
+                let pressed = typed.iter().any(|(start, k)| *k == key && (*start..start + 4).contains(&frame));
+                screen.set_key(key, pressed);
+            }
+            recorder.record(frame, screen).unwrap();
+        });
+        assert_eq!(recorded.v_reg[3], 4);
+        assert_eq!(recorded.v_reg[1], 0x3 + 0xA + 0x3 + 0xF);
+
+        let contents = String::from_utf8(recorder.out).unwrap();
+        assert!(contents.starts_with("seed 42\nips 500\nfps 60\n5 3 down\n9 3 up\n12 A down\n"));
+
+        let mut playback = Playback::parse(&contents).unwrap();
+        assert_eq!((playback.ips(), playback.fps()), (Some(500), Some(60)));
+        let seed = playback.seed().unwrap();
+        let played = run(seed, |frame, screen| {
+            for event in playback.events_at(frame) {
+                screen.set_key(event.key, event.pressed);
+            }
+        });
+        assert!(playback.is_finished());
+        assert_eq!(played, recorded);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let playback = Playback::parse("# comment\n\n3 f down\n3 F up\n").unwrap();
+        assert_eq!((playback.seed(), playback.ips(), playback.fps()), (None, None, None));
+        assert_eq!(playback.events[1], KeyEvent { frame: 3, key: 0xF, pressed: false });
+
+        assert!(Playback::parse("3 10 down").unwrap_err().contains("line 1"));
+        assert!(Playback::parse("3 1 held").is_err());
+        assert!(Playback::parse("seed x").is_err());
+        assert!(Playback::parse("ips 0").unwrap_err().contains("invalid ips"));
+        assert!(Playback::parse("fps x").unwrap_err().contains("invalid fps"));
+        assert!(Playback::parse("3 1 down\n2 1 up").unwrap_err().contains("line 2"));
+    }
+
+    #[test]
+    fn events_by_frame() {
+        let mut playback = Playback::parse("1 1 down\n1 2 down\n4 1 up\n6 2 up").unwrap();
+        assert!(playback.events_at(0).is_empty());
+        assert_eq!(playback.events_at(1).len(), 2);
+        // Frames skipped are not played later
+        assert!(playback.events_at(5).is_empty());
+        assert!(!playback.is_finished());
+        assert_eq!(playback.events_at(6)[0].key, 0x2);
+        assert!(playback.is_finished());
+        assert!(playback.events_at(7).is_empty());
+    }
+}
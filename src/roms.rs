@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// File extensions CHIP-8 programs are usually distributed with
+pub const ROM_EXTENSIONS: [&str; 2] = ["ch8", "rom"];
+
+// Whether the file has one of the usual CHIP-8 ROM extensions, optionally
+// followed by .gz
+pub fn has_rom_extension(path: &Path) -> bool {
+    let path = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("gz") => Path::new(path.file_stem().unwrap_or_default()),
+        _ => path,
+    };
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ROM_EXTENSIONS.iter().any(|r| r.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+// Lists the ROM files in a directory, sorted by name
+pub fn list_roms(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut roms = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && has_rom_extension(&path) {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+// Selection in the list of ROMs of the launcher, wrapping around the ends
+pub struct Menu {
+    items: Vec<PathBuf>,
+    selected: usize,
+}
+
+impl Menu {
+    // Creates a menu selecting the first item
+    pub fn new(items: Vec<PathBuf>) -> Menu {
+        Menu {
+            items,
+            selected: 0,
+        }
+    }
+
+    // Moves the selection to the previous item
+    pub fn up(&mut self) {
+        if !self.items.is_empty() {
+            self.selected = (self.selected + self.items.len() - 1) % self.items.len();
+        }
+    }
+
+    // Moves the selection to the next item
+    pub fn down(&mut self) {
+        if !self.items.is_empty() {
+            self.selected = (self.selected + 1) % self.items.len();
+        }
+    }
+
+    // Returns the index of the selected item
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    // Returns the selected item, if the menu is not empty
+    pub fn selected_item(&self) -> Option<&PathBuf> {
+        self.items.get(self.selected)
+    }
+
+    // Returns the file names of the items, as shown by the launcher
+    pub fn labels(&self) -> Vec<String> {
+        self.items.iter()
+            .map(|p| p.file_name().unwrap_or_default().to_string_lossy().into_owned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{has_rom_extension, list_roms, Menu};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn rom_extensions() {
+        assert!(has_rom_extension(Path::new("roms/pong.ch8")));
+        assert!(has_rom_extension(Path::new("GAME.ROM")));
+        assert!(!has_rom_extension(Path::new("roms/.DS_Store")));
+        assert!(!has_rom_extension(Path::new("notes.txt")));
+        assert!(!has_rom_extension(Path::new("ch8")));
+        assert!(has_rom_extension(Path::new("roms/pong.ch8.gz")));
+        assert!(!has_rom_extension(Path::new("notes.txt.gz")));
+        assert!(!has_rom_extension(Path::new("ch8.gz")));
+    }
+
+    #[test]
+    fn list_rom_directory() {
+        let roms = list_roms(Path::new("roms")).unwrap();
+        let names: Vec<_> = roms.iter().map(|p| p.file_name().unwrap().to_owned()).collect();
+        assert_eq!(names, ["breakout.ch8", "glitchGhost.ch8", "pong.ch8", "snake.ch8"]);
+
+        let dir = std::env::temp_dir().join("chip-8-list-roms");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.rom"), [0x00, 0xE0]).unwrap();
+        fs::write(dir.join("a.ch8"), [0x00, 0xE0]).unwrap();
+        fs::write(dir.join("readme.txt"), "").unwrap();
+        assert_eq!(list_roms(&dir).unwrap(), vec![dir.join("a.ch8"), dir.join("b.rom")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn menu_wraps() {
+        let mut menu = Menu::new(vec![PathBuf::from("a.ch8"), PathBuf::from("dir/b.ch8")]);
+        assert_eq!(menu.labels(), ["a.ch8", "b.ch8"]);
+
+        menu.up();
+        assert_eq!(menu.selected(), 1);
+        menu.down();
+        assert_eq!(menu.selected_item(), Some(&PathBuf::from("a.ch8")));
+
+        let mut empty = Menu::new(vec![]);
+        empty.down();
+        assert_eq!(empty.selected_item(), None);
+    }
+}
@@ -1,60 +1,464 @@
 use sdl2::pixels::Color;
-use sdl2::event::Event;
+use sdl2::rect::Rect;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
 use sdl2::audio::{AudioCallback, AudioSpecDesired, AudioDevice};
-use log::info;
+use sdl2::video::FullscreenType;
+use std::collections::HashMap;
+use log::{info, warn};
+use crate::cpu::{CpuSnapshot, DEFAULT_PITCH};
+use crate::display::{check_key, Display, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::framebuffer::Framebuffer;
+use crate::overlay;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{BlendMode, Canvas, Texture};
+use sdl2::video::Window;
+#[cfg(feature = "gamepad")]
+use sdl2::controller::{Button, GameController};
 
 const PIXEL_SHUTDOWN_FACTOR: u8 = 80;
-const SCREEN_WIDTH: u32 = 64;
-const SCREEN_HEIGHT: u32 = 32;
-const BLOCK_SIZE: u32 = 12;
-const WINDOW_WIDTH: u32 = SCREEN_WIDTH * BLOCK_SIZE + BLOCK_SIZE * 2;
-const WINDOW_HEIGHT: u32 = SCREEN_HEIGHT * BLOCK_SIZE + BLOCK_SIZE * 2;
 
-struct SquareWave {
+// Darkness of the scanlines and of the gaps between the pixels of the CRT
+// filter, and the smallest block size they are drawn at
+const CRT_SHADE: Color = Color::RGBA(0, 0, 0, 128);
+const CRT_MIN_BLOCK_SIZE: u32 = 3;
+
+// Names of the curves accepted by FadeCurve::from_name
+pub const FADE_CURVES: [&str; 3] = ["linear", "exponential", "instant"];
+
+// How the pixels turned off fade out, like the phosphor of a CRT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FadeCurve {
+    // Darker by PIXEL_SHUTDOWN_FACTOR every frame
+    #[default]
+    Linear,
+    // Half as bright every frame, dimming fast and leaving a longer trail
+    Exponential,
+    // Off right away, without ghosting
+    Instant,
+}
+
+impl FadeCurve {
+    // Returns the curve named name, one of FADE_CURVES
+    pub fn from_name(name: &str) -> Option<FadeCurve> {
+        match name.to_ascii_lowercase().as_str() {
+            "linear" => Some(FadeCurve::Linear),
+            "exponential" => Some(FadeCurve::Exponential),
+            "instant" => Some(FadeCurve::Instant),
+            _ => None,
+        }
+    }
+
+    // Brightness a frame after level
+    fn fade(self, level: u8) -> u8 {
+        match self {
+            FadeCurve::Linear => level.saturating_sub(PIXEL_SHUTDOWN_FACTOR),
+            FadeCurve::Exponential => level / 2,
+            FadeCurve::Instant => 0,
+        }
+    }
+}
+
+// Plays a 440Hz square wave, or the XO-CHIP 1-bit audio pattern once a ROM
+// loads one
+struct Beeper {
+    // Sample rate of the device
+    freq: f32,
     phase_inc: f32,
     phase: f32,
     volume: f32,
+    // The 128 bits of the pattern, the bits played per sample and the bit
+    // being played
+    pattern: Option<[u8; 16]>,
+    pattern_inc: f32,
+    position: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl Beeper {
+    fn new(freq: f32) -> Beeper {
+        Beeper {
+            freq,
+            phase_inc: 440.0 / freq,
+            phase: 0.0,
+            volume: 0.25,
+            pattern: None,
+            pattern_inc: pattern_rate(DEFAULT_PITCH) / freq,
+            position: 0.0,
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: Option<[u8; 16]>, pitch: u8) {
+        self.pattern = pattern;
+        self.pattern_inc = pattern_rate(pitch) / self.freq;
+    }
+}
+
+impl AudioCallback for Beeper {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
         for x in out.iter_mut() {
-            *x = if self.phase < 0.5 { self.volume } else { -self.volume };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            let high = match &self.pattern {
+                Some(pattern) => {
+                    let bit = self.position as usize;
+                    self.position = (self.position + self.pattern_inc) % 128.0;
+                    pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                }
+                None => {
+                    let high = self.phase < 0.5;
+                    self.phase = (self.phase + self.phase_inc) % 1.0;
+                    high
+                }
+            };
+            *x = if high { self.volume } else { -self.volume };
+        }
+    }
+}
+
+// Bits of the XO-CHIP audio pattern played per second at pitch
+fn pattern_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+// Computes the largest integer block size such that a display of
+// resolution pixels and its border of border blocks fit in a win_w x win_h
+// window (at least 1)
+fn compute_block_size(win_w: u32, win_h: u32, resolution: (u32, u32), border: u32) -> u32 {
+    let (width, height) = resolution;
+    (win_w / (width + 2 * border)).min(win_h / (height + 2 * border)).max(1)
+}
+
+// Computes the grey level of each pixel, row by row: white when lit, the
+// brightness of the fade when off
+fn frame_levels(pixels: &Framebuffer, shutdown_pixels: &[u8]) -> Vec<u8> {
+    let mut levels = shutdown_pixels.to_vec();
+    for (x, y) in pixels.lit_pixels() {
+        levels[(y * pixels.width() + x) as usize] = 255;
+    }
+    levels
+}
+
+// The pixels of the display in the current resolution, and the brightness
+// of the ones fading out after being turned off
+struct Pixels {
+    lit: Framebuffer,
+    shutdown: Vec<u8>,
+}
+
+impl Pixels {
+    fn new(width: u32, height: u32) -> Pixels {
+        Pixels {
+            lit: Framebuffer::new(width, height),
+            shutdown: vec![0; (width * height) as usize],
+        }
+    }
+
+    fn resolution(&self) -> (u32, u32) {
+        (self.lit.width(), self.lit.height())
+    }
+
+    // XORs the pixel at (x, y) with bit and returns its previous value, a
+    // pixel turned off starts to fade
+    fn draw(&mut self, x: u32, y: u32, bit: u8) -> u8 {
+        let prev = self.lit.xor(x, y, bit);
+        if prev == 1 && bit == 1 {
+            self.shutdown[(y * self.lit.width() + x) as usize] = 255;
+        }
+        prev
+    }
+
+    // Dims the pixels fading out by a frame of the curve
+    fn fade(&mut self, curve: FadeCurve) {
+        self.shutdown.iter_mut().for_each(|x| *x = curve.fade(*x));
+    }
+
+    fn is_fading(&self) -> bool {
+        self.shutdown.iter().any(|x| *x > 0)
+    }
+
+    fn levels(&self) -> Vec<u8> {
+        frame_levels(&self.lit, &self.shutdown)
+    }
+}
+
+// Describes the pixels turned on and off from before to after for
+// --draw-diff, e.g. "on (2, 1) (3, 1) off (5, 6)", empty when none changed
+fn draw_diff(before: &Framebuffer, after: &Framebuffer) -> String {
+    let (mut on, mut off) = (vec![], vec![]);
+    for (x, y) in after.changed_pixels(before) {
+        let list = if after.get(x, y) == 1 { &mut on } else { &mut off };
+        list.push(format!("({}, {})", x, y));
+    }
+    [("on", on), ("off", off)].into_iter()
+        .filter(|(_, pixels)| !pixels.is_empty())
+        .map(|(name, pixels)| format!("{} {}", name, pixels.join(" ")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Creates the streaming texture the pixels of a display of resolution are
+// written to each frame
+fn display_texture(canvas: &Canvas<Window>, resolution: (u32, u32)) -> Texture {
+    let (width, height) = resolution;
+    canvas.create_texture_streaming(PixelFormatEnum::RGBA32, width, height).unwrap()
+}
+
+// Position, resolution and scale of the CHIP-8 display inside the window
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Layout {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    block_size: u32,
+}
+
+impl Layout {
+    // Centers a display of resolution pixels in a win_w x win_h window
+    // using the largest integer block size that fits with the border, so the
+    // 2:1 aspect ratio is preserved
+    fn centered(win_w: u32, win_h: u32, resolution: (u32, u32), border: u32) -> Layout {
+        let (width, height) = resolution;
+        let block_size = compute_block_size(win_w, win_h, resolution, border);
+        Layout {
+            x: (win_w as i32 - (width * block_size) as i32) / 2,
+            y: (win_h as i32 - (height * block_size) as i32) / 2,
+            width,
+            height,
+            block_size,
+        }
+    }
+
+    // Computes the window rect of the whole CHIP-8 display
+    fn display_rect(&self) -> Rect {
+        let first = self.pixel_rect(0, 0);
+        Rect::new(first.x(), first.y(), self.width * self.block_size, self.height * self.block_size)
+    }
+
+    // Computes the one window pixel lines of the grid between the CHIP-8
+    // pixels, the columns then the rows
+    fn grid_rects(&self) -> Vec<Rect> {
+        let display = self.display_rect();
+        let columns = (1..self.width).map(|x| {
+            Rect::new(self.pixel_rect(x, 0).x(), display.y(), 1, display.height())
+        });
+        let rows = (1..self.height).map(|y| {
+            Rect::new(display.x(), self.pixel_rect(0, y).y(), display.width(), 1)
+        });
+        columns.chain(rows).collect()
+    }
+
+    // Computes the window rect of the display surrounded by border blocks
+    fn border_rect(&self, border: u32) -> Rect {
+        let display = self.display_rect();
+        let width = border * self.block_size;
+        Rect::new(display.x() - width as i32, display.y() - width as i32,
+            display.width() + 2 * width, display.height() + 2 * width)
+    }
+
+    // Computes the window rect of the CHIP-8 pixel at (x, y)
+    fn pixel_rect(&self, x: u32, y: u32) -> Rect {
+        let block = self.block_size as i32;
+        Rect::new(
+            (x as i32) * block + self.x,
+            (y as i32) * block + self.y,
+            self.block_size, self.block_size
+        )
+    }
+
+    // Computes the rects darkened by the CRT filter: a scanline over the
+    // bottom quarter of each row of pixels and a one window pixel gap
+    // between the columns. None at block sizes too small to show them
+    fn crt_rects(&self) -> Vec<Rect> {
+        if self.block_size < CRT_MIN_BLOCK_SIZE {
+            return vec![];
+        }
+        let display = self.display_rect();
+        let line = (self.block_size / 4).max(1);
+        let scanlines = (0..self.height).map(|y| {
+            let rect = self.pixel_rect(0, y);
+            Rect::new(rect.x(), rect.bottom() - line as i32, display.width(), line)
+        });
+        let gaps = (1..self.width).map(|x| {
+            Rect::new(self.pixel_rect(x, 0).x(), display.y(), 1, display.height())
+        });
+        scanlines.chain(gaps).collect()
+    }
+}
+
+// Events coming from the window that the main loop should react to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenEvent {
+    Quit,
+    ToggleFullscreen,
+    SpeedUp,
+    SlowDown,
+    // The turbo key was pressed (true) or released
+    Turbo(bool),
+    ToggleSlowMotion,
+    ToggleGrid,
+    HexDump,
+    ToggleCpuPause,
+    ToggleTimersPause,
+}
+
+// Default mapping of the keyboard to the CHIP-8 keypad
+//   1 2 3 C   =>   1 2 3 4
+//   4 5 6 D        Q W E R
+//   7 8 9 E        A S D F
+//   A 0 B F        Z X C V
+pub fn default_keymap() -> HashMap<Keycode, u8> {
+    HashMap::from([
+        (Keycode::Num1, 0x1), (Keycode::Num2, 0x2), (Keycode::Num3, 0x3), (Keycode::Num4, 0xC),
+        (Keycode::Q, 0x4), (Keycode::W, 0x5), (Keycode::E, 0x6), (Keycode::R, 0xD),
+        (Keycode::A, 0x7), (Keycode::S, 0x8), (Keycode::D, 0x9), (Keycode::F, 0xE),
+        (Keycode::Z, 0xA), (Keycode::X, 0x0), (Keycode::C, 0xB), (Keycode::V, 0xF),
+    ])
+}
+
+// Default mapping of the game controller buttons to the CHIP-8 keypad, the
+// D-pad moves with 2/4/6/8 as most games expect
+//   D-pad up/down/left/right => 2/8/4/6
+//   A, B, X, Y               => 5, A, 4, 0
+//   Start, Back              => F, E
+#[cfg(feature = "gamepad")]
+fn controller_key(button: Button) -> Option<u8> {
+    match button {
+        Button::DPadUp => Some(0x2),
+        Button::DPadDown => Some(0x8),
+        Button::DPadLeft => Some(0x4),
+        Button::DPadRight => Some(0x6),
+        Button::A => Some(0x5),
+        Button::B => Some(0xA),
+        Button::X => Some(0x4),
+        Button::Y => Some(0x0),
+        Button::Start => Some(0xF),
+        Button::Back => Some(0xE),
+        _ => None,
+    }
+}
+
+// Converts the key names of a parsed keymap file to SDL keycodes
+pub fn keymap_from_names(entries: &[(String, u8)]) -> Result<HashMap<Keycode, u8>, String> {
+    entries.iter()
+        .map(|(name, key)| match Keycode::from_name(name) {
+            Some(keycode) => Ok((keycode, *key)),
+            None => Err(format!("unknown key name {}", name)),
+        })
+        .collect()
+}
+
+// Options of the SDL screen
+#[derive(Clone)]
+pub struct ScreenOptions {
+    // The size in window pixels of a single CHIP-8 pixel
+    pub block_size: u32,
+    pub fullscreen: bool,
+    pub vsync: bool,
+    // Keyboard keys mapped to the CHIP-8 keys
+    pub keymap: HashMap<Keycode, u8>,
+    pub fade_curve: FadeCurve,
+    // Draw scanlines and gaps between the pixels like a CRT
+    pub crt: bool,
+    // Width in blocks and color of the border around the display
+    pub border: u32,
+    pub border_color: Color,
+    // Show the grid between the pixels from the start, and its color
+    pub grid: bool,
+    pub grid_color: Color,
+    // Title of the window
+    pub title: String,
+    // Print the pixels turned on and off by each frame drawn
+    pub draw_diff: bool,
+}
+
+impl Default for ScreenOptions {
+    fn default() -> ScreenOptions {
+        ScreenOptions {
+            block_size: 12,
+            fullscreen: false,
+            vsync: false,
+            keymap: default_keymap(),
+            fade_curve: FadeCurve::default(),
+            crt: false,
+            border: 1,
+            border_color: Color::BLACK,
+            grid: false,
+            grid_color: Color::RGB(64, 64, 64),
+            title: "CHIP-8 EMU".to_string(),
+            draw_diff: false,
         }
     }
 }
 
 // Represents the CHIP-8 screen
 pub struct Screen {
-    pixels: Vec<u8>,
-    shutdown_pixels: Vec<u8>,
+    pixels: Pixels,
+    fade_curve: FadeCurve,
+    crt: bool,
+    border: u32,
+    border_color: Color,
+    show_grid: bool,
+    grid_color: Color,
+    // Grey levels of the pixels last presented
+    presented: Vec<u8>,
     keypad: Vec<bool>,
     canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    // The pixels of the display, one texture pixel per CHIP-8 pixel
+    texture: Texture,
     event_pump: sdl2::EventPump,
-    device: AudioDevice<SquareWave>,
+    device: AudioDevice<Beeper>,
+    layout: Layout,
+    redraw: bool,
+    keymap: HashMap<Keycode, u8>,
+    show_overlay: bool,
+    show_keypad: bool,
+    // The pixels of the last frame drawn, with --draw-diff
+    last_drawn: Option<Framebuffer>,
+    // The key of the keypad pressed most recently
+    last_pressed: Option<u8>,
+    #[cfg(feature = "gamepad")]
+    controller_subsystem: sdl2::GameControllerSubsystem,
+    #[cfg(feature = "gamepad")]
+    controllers: Vec<GameController>,
 }
 
 impl Screen  {
-    // Creates a new CHIP-8 screen with default values
-    pub fn new() -> Screen {
+    // Creates a new CHIP-8 screen, each CHIP-8 pixel being a square of
+    // block_size x block_size window pixels (plus a border of options.border
+    // blocks)
+    pub fn new(options: ScreenOptions) -> Screen {
+
+        let block_size = options.block_size;
+        let border = options.border;
+
+        assert!(block_size > 0, "The block size must be greater than 0");
+
+        let window_width = SCREEN_WIDTH * block_size + block_size * border * 2;
+        let window_height = SCREEN_HEIGHT * block_size + block_size * border * 2;
 
         // Initialize SDL2
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
-        // Create a window
-        let window = video_subsystem.window("CHIP-8 EMU", WINDOW_WIDTH, WINDOW_HEIGHT)
+        // Create a window, at least large enough for a window pixel per
+        // CHIP-8 pixel and the border
+        let mut window = video_subsystem.window(&options.title, window_width, window_height)
             .position_centered()
+            .resizable()
             .build()
             .unwrap();
+        window.set_minimum_size(SCREEN_WIDTH + border * 2, SCREEN_HEIGHT + border * 2).unwrap();
 
         // Create a canvas from the window
-        let mut canvas = window.into_canvas().build().unwrap();
+        let mut canvas_builder = window.into_canvas();
+        if options.vsync {
+            canvas_builder = canvas_builder.present_vsync();
+        }
+        let mut canvas = canvas_builder.build().unwrap();
 
+        // The debug overlay is drawn semi-transparent over the display
+        canvas.set_blend_mode(BlendMode::Blend);
         canvas.set_draw_color(Color::BLACK);
         canvas.clear();
         canvas.present();
@@ -70,134 +474,422 @@ impl Screen  {
             // Show obtained AudioSpec
             info!("{:?}", spec);
             // initialize the audio callback
-            SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
-            }
+            Beeper::new(spec.freq as f32)
         }).unwrap();
 
-        Screen {
-            pixels: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
-            shutdown_pixels: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
+        let texture = display_texture(&canvas, (SCREEN_WIDTH, SCREEN_HEIGHT));
+
+        let mut screen = Screen {
+            pixels: Pixels::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            fade_curve: options.fade_curve,
+            crt: options.crt,
+            border: options.border,
+            border_color: options.border_color,
+            show_grid: options.grid,
+            grid_color: options.grid_color,
+            presented: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
             keypad: vec![false; 16],
             canvas,
+            texture,
             event_pump: sdl_context.event_pump().unwrap(),
             device,
+            layout: Layout::centered(window_width, window_height, (SCREEN_WIDTH, SCREEN_HEIGHT), border),
+            redraw: false,
+            keymap: options.keymap,
+            show_overlay: false,
+            show_keypad: false,
+            last_drawn: options.draw_diff.then(|| Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT)),
+            last_pressed: None,
+            // Controllers already connected are reported as added as well
+            #[cfg(feature = "gamepad")]
+            controller_subsystem: sdl_context.game_controller().unwrap(),
+            #[cfg(feature = "gamepad")]
+            controllers: vec![],
+        };
+
+        if options.fullscreen {
+            screen.toggle_fullscreen();
         }
-    }
 
-    // Clears the screen
-    pub fn clear(&mut self) {
-        self.pixels.iter_mut().for_each(|x| *x=0);
+        screen
     }
 
-    // Draws pixel buffer to the screen
-    pub fn update(&mut self, draw: bool) {
+    // Draws the grey levels of the pixels, and the overlay when enabled, and
+    // presents the canvas
+    fn present(&mut self, levels: &[u8], registers: &CpuSnapshot) {
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        self.canvas.set_draw_color(self.border_color);
+        self.canvas.fill_rect(self.layout.border_rect(self.border)).unwrap();
 
-        if draw || self.shutdown_pixels.iter().any(|x| *x > 0) {
-            // Decrease the shutdown pixels
-            self.shutdown_pixels.iter_mut().for_each(|x| *x = 
-                x.saturating_sub(PIXEL_SHUTDOWN_FACTOR));
-            // Draw the pixels
-            self.canvas.set_draw_color(Color::BLACK);
-            self.canvas.clear();
-            for y in 0..32 {
-                for x in 0..64 {
-                    let i = y * 64 + x;
-                    let pixel_rect = sdl2::rect::Rect::new(
-                        (x as i32) * BLOCK_SIZE as i32 + BLOCK_SIZE as i32, 
-                        (y as i32) * BLOCK_SIZE as i32 + BLOCK_SIZE as i32, 
-                        BLOCK_SIZE, BLOCK_SIZE
-                    );
-                    if self.pixels[i] == 1 {
-                        // Draw the pixel
-                        self.canvas.set_draw_color(Color::WHITE);
-                        self.canvas.fill_rect(pixel_rect).unwrap();
-                    } else {
-                        // Draw the shutdown pixel
-                        let bright = self.shutdown_pixels[i];
-                        self.canvas.set_draw_color(Color::RGB(bright, bright, bright));
-                        self.canvas.fill_rect(pixel_rect).unwrap();
-                    }
+        // The display is drawn one texture pixel per CHIP-8 pixel and scaled
+        // to the layout by the GPU
+        let width = self.pixels.resolution().0;
+        self.texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            for (y, row) in levels.chunks(width as usize).enumerate() {
+                for (x, level) in row.iter().enumerate() {
+                    let i = y * pitch + x * 4;
+                    buffer[i..i + 4].copy_from_slice(&[*level, *level, *level, 255]);
                 }
             }
-            // Present the canvas
-            self.canvas.present();
+        }).unwrap();
+        self.canvas.copy(&self.texture, None, self.layout.display_rect()).unwrap();
+
+        // Blended over the display in a single batch of rects
+        if self.crt {
+            self.canvas.set_draw_color(CRT_SHADE);
+            self.canvas.fill_rects(&self.layout.crt_rects()).unwrap();
+        }
+
+        if self.show_grid {
+            self.canvas.set_draw_color(self.grid_color);
+            self.canvas.fill_rects(&self.layout.grid_rects()).unwrap();
+        }
+
+        if self.show_overlay {
+            self.draw_overlay(&overlay::register_lines(registers));
+        }
+        if self.show_keypad {
+            self.draw_keypad();
+        }
+
+        self.canvas.present();
+    }
+
+    // Returns the pixels of the display
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.pixels.lit
+    }
+
+    // Returns the pixel at (x, y), 1 when lit and 0 when off or outside of
+    // the display, for bots reacting to the screen
+    pub fn pixel_at(&self, x: u8, y: u8) -> u8 {
+        self.pixels.lit.pixel_at(x as u32, y as u32)
+    }
+
+    // Returns the row y of the display, the pixel x in the bit x, and 0
+    // outside of the display
+    pub fn row_bits(&self, y: u8) -> u64 {
+        self.pixels.lit.row_bits(y as u32)
+    }
+
+    // Presses the key as if it was pressed on the keyboard, for scripted
+    // input. It stays pressed until released, by release_key or a key up
+    pub fn press_key(&mut self, key: u8) -> Result<(), String> {
+        check_key(key)?;
+        self.keypad[key as usize] = true;
+        self.last_pressed = Some(key);
+        Ok(())
+    }
+
+    // Releases the key
+    pub fn release_key(&mut self, key: u8) -> Result<(), String> {
+        check_key(key)?;
+        self.keypad[key as usize] = false;
+        Ok(())
+    }
+
+    // Switches the window between windowed and desktop fullscreen
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let fullscreen = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+
+        if let Err(e) = window.set_fullscreen(fullscreen) {
+            warn!("Could not change the fullscreen state: {}", e);
+            return;
+        }
+
+        self.update_layout();
+    }
+
+    // Sets the title of the window
+    pub fn set_title(&mut self, title: &str) {
+        if let Err(e) = self.canvas.window_mut().set_title(title) {
+            warn!("Could not set the window title: {}", e);
+        }
+    }
+
+    // Draws the ROM launcher menu listing items, highlighting the selected one
+    pub fn draw_menu(&mut self, items: &[String], selected: usize) {
+        let font_pixel = (self.layout.block_size / 3).max(1);
+        let line_height = overlay::LINE_ADVANCE * font_pixel;
+        let x = self.layout.x + self.layout.block_size as i32;
+        let y = self.layout.y + self.layout.block_size as i32;
+
+        // Scroll so that the selected item is always visible
+        let visible = ((self.layout.height - 2) * self.layout.block_size / line_height).max(1) as usize;
+        let first = selected.saturating_sub(visible - 1);
+
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        for (n, item) in items.iter().enumerate().skip(first).take(visible) {
+            let line_y = y + ((n - first) as u32 * line_height) as i32;
+            let rects: Vec<Rect> = overlay::text_pixels(item).into_iter()
+                .map(|(px, py)| Rect::new(
+                    x + (px * font_pixel) as i32,
+                    line_y + (py * font_pixel) as i32,
+                    font_pixel, font_pixel
+                ))
+                .collect();
+            let bright = if n == selected { 255 } else { 96 };
+            self.canvas.set_draw_color(Color::RGB(bright, bright, bright));
+            if !rects.is_empty() {
+                self.canvas.fill_rects(&rects).unwrap();
+            }
+        }
+        self.canvas.present();
+
+        // The display has to replace the menu even if no pixel changes
+        self.redraw = true;
+    }
+
+    // Draws lines of text on a semi-transparent background in the top left
+    // corner of the display
+    fn draw_overlay(&mut self, lines: &[String]) {
+        let font_pixel = (self.layout.block_size / 4).max(1);
+        let x = self.layout.x + self.layout.block_size as i32;
+        let y = self.layout.y + self.layout.block_size as i32;
+
+        let columns = lines.iter().map(|l| l.len()).max().unwrap_or(0) as u32;
+        let background = Rect::new(x, y,
+            (columns * overlay::GLYPH_ADVANCE + 1) * font_pixel,
+            (lines.len() as u32 * overlay::LINE_ADVANCE) * font_pixel);
+        self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+        self.canvas.fill_rect(background).unwrap();
+
+        let rects: Vec<Rect> = lines.iter().enumerate()
+            .flat_map(|(n, line)| {
+                let line_y = (n as u32 * overlay::LINE_ADVANCE + 1) as i32;
+                overlay::text_pixels(line).into_iter().map(move |(px, py)| Rect::new(
+                    x + ((px + 1) * font_pixel) as i32,
+                    y + (line_y + py as i32) * font_pixel as i32,
+                    font_pixel, font_pixel
+                ))
+            })
+            .collect();
+        self.canvas.set_draw_color(Color::RGBA(0, 255, 0, 224));
+        self.canvas.fill_rects(&rects).unwrap();
+    }
+
+    // Draws the keypad in the bottom right corner of the display in the
+    // colors of the overlay, the keys pressed highlighted
+    fn draw_keypad(&mut self) {
+        let font_pixel = (self.layout.block_size / 4).max(1);
+        let key = overlay::KEY_SIZE * font_pixel;
+        let display = self.layout.display_rect();
+        let x = display.right() - (4 * key + self.layout.block_size) as i32;
+        let y = display.bottom() - (4 * key + self.layout.block_size) as i32;
+
+        // Keys and labels of the keys released, then of the pressed ones
+        let mut keys = [vec![], vec![]];
+        let mut labels = [vec![], vec![]];
+        for (row, values) in overlay::KEYPAD.iter().enumerate() {
+            for (column, value) in values.iter().enumerate() {
+                let key_x = x + (column as u32 * key) as i32;
+                let key_y = y + (row as u32 * key) as i32;
+                let pressed = self.keypad[*value as usize] as usize;
+                keys[pressed].push(Rect::new(key_x, key_y, key, key));
+                // Centered in the key
+                labels[pressed].extend(overlay::text_pixels(&format!("{:X}", value)).into_iter()
+                    .map(|(px, py)| Rect::new(
+                        key_x + ((px + 2) * font_pixel) as i32,
+                        key_y + ((py + 1) * font_pixel) as i32,
+                        font_pixel, font_pixel
+                    )));
+            }
         }
-        
+
+        // Green on black for the keys released, black on green when pressed
+        self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+        self.canvas.fill_rects(&keys[0]).unwrap();
+        self.canvas.set_draw_color(Color::RGBA(0, 255, 0, 224));
+        self.canvas.fill_rects(&keys[1]).unwrap();
+        self.canvas.fill_rects(&labels[0]).unwrap();
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.fill_rects(&labels[1]).unwrap();
+    }
+
+    // Recomputes the display layout from the current window size
+    fn update_layout(&mut self) {
+        let (win_w, win_h) = self.canvas.output_size().unwrap();
+        self.layout = Layout::centered(win_w, win_h, self.pixels.resolution(), self.border);
+        self.redraw = true;
+    }
+
+    // Handles the window events, then draws pixel buffer to the screen with
+    // the registers in the debug overlay when enabled, and returns the window
+    // events the caller has to handle
+    pub fn update(&mut self, draw: bool, registers: &CpuSnapshot) -> Vec<ScreenEvent> {
+
+        let mut events = vec![];
+
         // Handle events
+        let mut resized = false;
         for event in self.event_pump.poll_iter() {
+            // The key repeats of the OS while a key is held aren't new
+            // presses, neither for the keypad, whose FX0A waits for a key to
+            // be released, nor for the toggles
+            if let Event::KeyDown { repeat: true, .. } = event {
+                continue;
+            }
             match event {
-                Event::Quit {..} => std::process::exit(0),
+                // SDL also reports Ctrl+C on the terminal as a quit event
+                Event::Quit {..} => events.push(ScreenEvent::Quit),
+                // Resized follows changes by the user or the window manager,
+                // SizeChanged any change including fullscreen toggles. The
+                // contents of the window are lost when it's exposed again
+                Event::Window { win_event: WindowEvent::Resized(..), .. } |
+                Event::Window { win_event: WindowEvent::SizeChanged(..), .. } => {
+                    resized = true;
+                },
+                Event::Window { win_event: WindowEvent::Exposed, .. } => {
+                    self.redraw = true;
+                },
                 Event::KeyDown { keycode: Some(keycode), .. } => {
                     info!("Key pressed: {:?}", keycode);
                     match keycode {
-                        Keycode::Num1 => self.keypad[0x1] = true,
-                        Keycode::Num2 => self.keypad[0x2] = true,
-                        Keycode::Num3 => self.keypad[0x3] = true,
-                        Keycode::Num4 => self.keypad[0xC] = true,
-                        Keycode::Q => self.keypad[0x4] = true,
-                        Keycode::W => self.keypad[0x5] = true,
-                        Keycode::E => self.keypad[0x6] = true,
-                        Keycode::R => self.keypad[0xD] = true,
-                        Keycode::A => self.keypad[0x7] = true,
-                        Keycode::S => self.keypad[0x8] = true,
-                        Keycode::D => self.keypad[0x9] = true,
-                        Keycode::F => self.keypad[0xE] = true,
-                        Keycode::Z => self.keypad[0xA] = true,
-                        Keycode::X => self.keypad[0x0] = true,
-                        Keycode::C => self.keypad[0xB] = true,
-                        Keycode::V => self.keypad[0xF] = true,
-                        _ => {}
+                        Keycode::Tab => events.push(ScreenEvent::Turbo(true)),
+                        Keycode::F3 => {
+                            self.show_overlay = !self.show_overlay;
+                            self.redraw = true;
+                        },
+                        Keycode::F6 => {
+                            self.show_keypad = !self.show_keypad;
+                            self.redraw = true;
+                        },
+                        Keycode::F4 => events.push(ScreenEvent::ToggleGrid),
+                        Keycode::F5 => events.push(ScreenEvent::ToggleSlowMotion),
+                        Keycode::F7 => events.push(ScreenEvent::HexDump),
+                        Keycode::F8 => events.push(ScreenEvent::ToggleCpuPause),
+                        Keycode::F9 => events.push(ScreenEvent::ToggleTimersPause),
+                        Keycode::F11 => events.push(ScreenEvent::ToggleFullscreen),
+                        Keycode::Equals | Keycode::Plus | Keycode::KpPlus |
+                        Keycode::RightBracket => events.push(ScreenEvent::SpeedUp),
+                        Keycode::Minus | Keycode::KpMinus |
+                        Keycode::LeftBracket => events.push(ScreenEvent::SlowDown),
+                        _ => if let Some(key) = self.keymap.get(&keycode) {
+                            self.keypad[*key as usize] = true;
+                            self.last_pressed = Some(*key);
+                        },
                     }
                 },  
                 Event::KeyUp { keycode: Some(keycode), .. } => {
                     info!("Key released: {:?}", keycode);
-                    match keycode {
-                        Keycode::Num1 => self.keypad[0x1] = false,
-                        Keycode::Num2 => self.keypad[0x2] = false,
-                        Keycode::Num3 => self.keypad[0x3] = false,
-                        Keycode::Num4 => self.keypad[0xC] = false,
-                        Keycode::Q => self.keypad[0x4] = false,
-                        Keycode::W => self.keypad[0x5] = false,
-                        Keycode::E => self.keypad[0x6] = false,
-                        Keycode::R => self.keypad[0xD] = false,
-                        Keycode::A => self.keypad[0x7] = false,
-                        Keycode::S => self.keypad[0x8] = false,
-                        Keycode::D => self.keypad[0x9] = false,
-                        Keycode::F => self.keypad[0xE] = false,
-                        Keycode::Z => self.keypad[0xA] = false,
-                        Keycode::X => self.keypad[0x0] = false,
-                        Keycode::C => self.keypad[0xB] = false,
-                        Keycode::V => self.keypad[0xF] = false,
-                        _ => {}
+                    if keycode == Keycode::Tab {
+                        events.push(ScreenEvent::Turbo(false));
+                    } else if let Some(key) = self.keymap.get(&keycode) {
+                        self.keypad[*key as usize] = false;
+                    }
+                },
+                #[cfg(feature = "gamepad")]
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match self.controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            info!("Controller connected: {}", controller.name());
+                            self.controllers.push(controller);
+                        },
+                        Err(e) => warn!("Could not open controller {}: {}", which, e),
+                    }
+                },
+                #[cfg(feature = "gamepad")]
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    info!("Controller {} disconnected", which);
+                    self.controllers.retain(|c| c.instance_id() != which);
+                },
+                #[cfg(feature = "gamepad")]
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(key) = controller_key(button) {
+                        self.keypad[key as usize] = true;
+                        self.last_pressed = Some(key);
+                    }
+                },
+                #[cfg(feature = "gamepad")]
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(key) = controller_key(button) {
+                        self.keypad[key as usize] = false;
                     }
                 },
                 _ => {}
             }
         }
-    }
 
-    // Draws a pixel to the screen
-    pub fn draw_pixel(&mut self, x: u8, y: u8, bit: u8) -> u8 {
-        let i = (y as usize) * 64 + (x as usize);
-        let prev = self.pixels[i];
+        // Fit the display to the new window size before drawing, so no frame
+        // is drawn with the layout of the old size
+        if resized {
+            self.update_layout();
+        }
 
-        if prev == 1 && bit == 1 {
-            self.shutdown_pixels[i] = 255;
+        if let Some(last) = self.last_drawn.as_mut().filter(|_| draw) {
+            let diff = draw_diff(last, &self.pixels.lit);
+            if !diff.is_empty() {
+                eprintln!("Drawn: {}", diff);
+            }
+            last.clone_from(&self.pixels.lit);
         }
 
-        self.pixels[i] ^= bit;
+        // The overlays are redrawn every frame as the registers and the keys
+        // keep changing
+        let overlays = self.show_overlay || self.show_keypad;
+        if draw || self.redraw || overlays || self.pixels.is_fading() {
+            // Decrease the shutdown pixels
+            self.pixels.fade(self.fade_curve);
 
-        prev
+            // Sprites drawn and erased within a frame or redrawn unchanged
+            // leave the presented frame as it is, SDL only keeps the
+            // contents of the canvas until the next present so a changed
+            // frame is drawn from scratch
+            let levels = self.pixels.levels();
+            if levels != self.presented || self.redraw || overlays {
+                self.redraw = false;
+                self.present(&levels, registers);
+                self.presented = levels;
+            }
+        }
+
+        events
+    }
+
+    pub fn resume_beep(&self) {
+        self.device.resume();
+    }
+
+    pub fn pause_beep(&self) {
+        self.device.pause();
+    }
+
+    // Shows or hides the grid between the pixels
+    pub fn toggle_grid(&mut self) {
+        self.show_grid = !self.show_grid;
+        self.redraw = true;
+    }
+
+    // Plays the XO-CHIP audio pattern at pitch instead of the beep, or the
+    // beep again without a pattern
+    pub fn set_audio_pattern(&mut self, pattern: Option<[u8; 16]>, pitch: u8) {
+        self.device.lock().set_pattern(pattern, pitch);
+    }
+
+}
+
+impl Display for Screen {
+    // Clears the screen
+    fn clear(&mut self) {
+        self.pixels.lit.clear();
     }
 
-    pub fn is_key_pressed(&self, key_value: u8) -> bool {
+    // Draws a pixel to the screen
+    fn draw_pixel(&mut self, x: u8, y: u8, bit: u8) -> u8 {
+        self.pixels.draw(x as u32, y as u32, bit)
+    }
+
+    fn is_key_pressed(&self, key_value: u8) -> bool {
         self.keypad[key_value as usize]
     }
 
-    pub fn get_key_pressed(&self) -> Option<u8> {
+    fn get_key_pressed(&self) -> Option<u8> {
         for i in 0..16 {
             if self.keypad[i] {
                 return Some(i as u8);
@@ -206,12 +898,265 @@ impl Screen  {
         None
     }
 
-    pub fn resume_beep(&self) {
-        self.device.resume();
+    fn get_last_key_pressed(&self) -> Option<u8> {
+        self.last_pressed.filter(|key| self.keypad[*key as usize])
+            .or_else(|| self.get_key_pressed())
     }
 
-    pub fn pause_beep(&self) {
-        self.device.pause();
+    // Switches to a cleared display of width x height pixels, fitted to the
+    // window with the largest block size that still fits
+    fn resize(&mut self, width: u32, height: u32) {
+        if self.pixels.resolution() != (width, height) {
+            let texture = std::mem::replace(&mut self.texture, display_texture(&self.canvas, (width, height)));
+            // No longer used, and the canvas that created it is still alive
+            unsafe { texture.destroy() };
+        }
+        self.pixels = Pixels::new(width, height);
+        if let Some(last) = self.last_drawn.as_mut() {
+            *last = Framebuffer::new(width, height);
+        }
+        let border = self.border * 2;
+        if let Err(e) = self.canvas.window_mut().set_minimum_size(width + border, height + border) {
+            warn!("Could not set the minimum window size: {}", e);
+        }
+        self.update_layout();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compute_block_size, default_keymap, draw_diff, frame_levels, pattern_rate, Beeper, FadeCurve, Layout, Pixels, SCREEN_WIDTH, SCREEN_HEIGHT};
+    use sdl2::rect::Rect;
+    use sdl2::audio::AudioCallback;
+    use crate::framebuffer::Framebuffer;
+
+    const LORES: (u32, u32) = (SCREEN_WIDTH, SCREEN_HEIGHT);
+
+    #[cfg(feature = "gamepad")]
+    #[test]
+    fn controller_dpad() {
+        use sdl2::controller::Button;
+        use super::controller_key;
+
+        assert_eq!(controller_key(Button::DPadUp), Some(0x2));
+        assert_eq!(controller_key(Button::DPadLeft), Some(0x4));
+        assert_eq!(controller_key(Button::DPadRight), Some(0x6));
+        assert_eq!(controller_key(Button::DPadDown), Some(0x8));
+        assert_eq!(controller_key(Button::B), Some(0xA));
+        assert_eq!(controller_key(Button::Guide), None);
+    }
+
+    #[test]
+    fn default_keymap_covers_keypad() {
+        let keymap = default_keymap();
+        assert_eq!(keymap.len(), 16);
+        assert!((0..16u8).all(|key| keymap.values().any(|k| *k == key)));
+    }
+
+    #[test]
+    fn block_size_fits_window() {
+        // Initial window sizes map back to their scale
+        assert_eq!(compute_block_size(66 * 12, 34 * 12, LORES, 1), 12);
+        assert_eq!(compute_block_size(66 * 5, 34 * 5, LORES, 1), 5);
+
+        // The smallest side limits the block size
+        assert_eq!(compute_block_size(66 * 12, 34 * 4 + 10, LORES, 1), 4);
+        assert_eq!(compute_block_size(66 * 3 + 65, 34 * 20, LORES, 1), 3);
+
+        // Never smaller than a single window pixel
+        assert_eq!(compute_block_size(10, 10, LORES, 1), 1);
+    }
+
+    #[test]
+    fn windowed_rects_border() {
+        let layout = Layout::centered(66 * 12, 34 * 12, LORES, 1);
+        let rect = layout.pixel_rect(0, 0);
+        assert_eq!((rect.x(), rect.y()), (12, 12));
+        assert_eq!((rect.width(), rect.height()), (12, 12));
+
+        let layout = Layout::centered(66 * 5, 34 * 5, LORES, 1);
+        let rect = layout.pixel_rect(63, 31);
+        assert_eq!((rect.x(), rect.y()), (64 * 5, 32 * 5));
+        assert_eq!((rect.width(), rect.height()), (5, 5));
+
+        let rect = layout.display_rect();
+        assert_eq!((rect.x(), rect.y()), (5, 5));
+        assert_eq!((rect.width(), rect.height()), (64 * 5, 32 * 5));
+    }
+
+    #[test]
+    fn border_sizes() {
+        // Edge to edge, the same block size fits without the border
+        assert_eq!(compute_block_size(64 * 12, 32 * 12, LORES, 0), 12);
+        let layout = Layout::centered(64 * 12, 32 * 12, LORES, 0);
+        assert_eq!(layout, Layout { x: 0, y: 0, width: 64, height: 32, block_size: 12 });
+        assert_eq!(layout.border_rect(0), layout.display_rect());
+
+        // The initial window of a 3 blocks border, with the pixels still on
+        // a grid of blocks
+        let layout = Layout::centered(70 * 5, 38 * 5, LORES, 3);
+        assert_eq!(layout.block_size, 5);
+        assert_eq!(layout.pixel_rect(0, 0), Rect::new(15, 15, 5, 5));
+        assert_eq!(layout.pixel_rect(63, 31), Rect::new(15 + 63 * 5, 15 + 31 * 5, 5, 5));
+        assert_eq!(layout.border_rect(3), Rect::new(0, 0, 70 * 5, 38 * 5));
+    }
+
+    #[test]
+    fn grid_rects() {
+        let layout = Layout::centered(70 * 5, 38 * 5, LORES, 3);
+        let rects = layout.grid_rects();
+        assert_eq!(rects.len(), 63 + 31);
+        // Lines on the edges of the pixel cells, inside the border
+        assert_eq!(rects[0], Rect::new(20, 15, 1, 32 * 5));
+        assert_eq!(rects[0].x(), layout.pixel_rect(1, 0).x());
+        assert_eq!(rects[63], Rect::new(15, 20, 64 * 5, 1));
+        assert_eq!(rects[93].y(), layout.pixel_rect(0, 31).y());
+    }
+
+    #[test]
+    fn crt_rects() {
+        let layout = Layout::centered(66 * 12, 34 * 12, LORES, 1);
+        let rects = layout.crt_rects();
+        assert_eq!(rects.len(), 32 + 63);
+        // The bottom 3 window pixels of the first row
+        assert_eq!(rects[0], Rect::new(12, 21, 64 * 12, 3));
+        assert_eq!(rects[31].bottom(), 12 + 32 * 12);
+        // Between the first two columns, the whole height of the display
+        assert_eq!(rects[32], Rect::new(24, 12, 1, 32 * 12));
+
+        assert!(Layout::centered(66 * 2, 34 * 2, LORES, 1).crt_rects().is_empty());
+    }
+
+    #[test]
+    fn centered_rects_keep_aspect() {
+        // 16:9 display, black bars on the top and the bottom
+        let layout = Layout::centered(1920, 1080, LORES, 1);
+        assert_eq!(layout, Layout { x: 32, y: 76, width: 64, height: 32, block_size: 29 });
+
+        // 4:3 display, black bars on the top and the bottom
+        let layout = Layout::centered(1024, 768, LORES, 1);
+        assert_eq!(layout, Layout { x: 32, y: 144, width: 64, height: 32, block_size: 15 });
+
+        // Wide display, black bars on the sides
+        let layout = Layout::centered(1000, 400, LORES, 1);
+        assert_eq!(layout, Layout { x: 148, y: 24, width: 64, height: 32, block_size: 11 });
+
+        let first = layout.pixel_rect(0, 0);
+        let last = layout.pixel_rect(SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1);
+        let width = last.right() - first.left();
+        let height = last.bottom() - first.top();
+        assert_eq!(width, 2 * height);
+        assert_eq!(first.width(), first.height());
+    }
+
+    #[test]
+    fn hires_layout() {
+        // The same window fits the 128x64 pixels at half the block size
+        assert_eq!(compute_block_size(66 * 12, 34 * 12, (128, 64), 1), 6);
+        let layout = Layout::centered(66 * 12, 34 * 12, (128, 64), 1);
+        assert_eq!(layout, Layout { x: 12, y: 12, width: 128, height: 64, block_size: 6 });
+        assert_eq!(layout.display_rect(), Rect::new(12, 12, 128 * 6, 64 * 6));
+        assert_eq!(layout.grid_rects().len(), 127 + 63);
+        assert_eq!(layout.pixel_rect(127, 63), Rect::new(12 + 127 * 6, 12 + 63 * 6, 6, 6));
+    }
+
+    #[test]
+    fn hires_pixels() {
+        let mut pixels = Pixels::new(128, 64);
+        assert_eq!(pixels.resolution(), (128, 64));
+        assert_eq!(pixels.shutdown.len(), 128 * 64);
+
+        // Rows are 128 pixels wide, the far edge doesn't wrap to the next row
+        assert_eq!(pixels.draw(127, 0, 1), 0);
+        assert_eq!(pixels.draw(0, 1, 1), 0);
+        assert_eq!(pixels.draw(127, 0, 1), 1);
+        assert_eq!(pixels.lit.lit_pixels().collect::<Vec<_>>(), vec![(0, 1)]);
+        let levels = pixels.levels();
+        assert_eq!((levels[127], levels[128]), (255, 255));
+        assert!(pixels.is_fading());
+    }
+
+    #[test]
+    fn audio_pattern() {
+        assert_eq!(pattern_rate(64), 4000.0);
+        assert_eq!(pattern_rate(112), 8000.0);
+        assert_eq!(pattern_rate(16), 2000.0);
+
+        // One bit per sample, looping over the 128 bits
+        let mut beeper = Beeper::new(4000.0);
+        let mut pattern = [0; 16];
+        pattern[0] = 0b1010_0000;
+        beeper.set_pattern(Some(pattern), 64);
+        let mut out = [0.0; 130];
+        beeper.callback(&mut out);
+        assert_eq!(&out[..4], &[0.25, -0.25, 0.25, -0.25]);
+        assert!(out[4..128].iter().all(|x| *x == -0.25));
+        assert_eq!(&out[128..], &[0.25, -0.25]);
+
+        // Half as many bits per second, each bit lasts two samples
+        beeper.set_pattern(Some(pattern), 16);
+        beeper.position = 0.0;
+        beeper.callback(&mut out[..4]);
+        assert_eq!(&out[..4], &[0.25, 0.25, -0.25, -0.25]);
+    }
+
+    // Frames until a pixel turned off fades below level
+    fn frames_below(curve: FadeCurve, level: u8) -> usize {
+        std::iter::successors(Some(255), |x| Some(curve.fade(*x))).position(|x| x < level).unwrap()
+    }
+
+    #[test]
+    fn fade_curves() {
+        assert_eq!(frames_below(FadeCurve::Linear, 1), 4);
+        assert_eq!(frames_below(FadeCurve::Instant, 1), 1);
+        // Under 5% of the brightness after 5 frames, off after 8
+        assert_eq!(frames_below(FadeCurve::Exponential, 13), 5);
+        assert_eq!(frames_below(FadeCurve::Exponential, 1), 8);
+
+        assert_eq!(FadeCurve::from_name("Exponential"), Some(FadeCurve::Exponential));
+        assert_eq!(FadeCurve::from_name("cubic"), None);
     }
 
+    #[test]
+    fn frame_levels_match_pixels() {
+        let mut shutdown_pixels = vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize];
+        shutdown_pixels[1] = 95;
+        shutdown_pixels[2] = 255;
+
+        let mut pixels = Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        for (x, y) in [(0, 0), (2, 0), (63, 31), (10, 20)] {
+            pixels.xor(x, y, 1);
+        }
+
+        // The white pixels are the lit ones, the fading ones keep their
+        // brightness
+        let levels = frame_levels(&pixels, &shutdown_pixels);
+        let white: Vec<(u32, u32)> = levels.iter().enumerate()
+            .filter(|(_, level)| **level == 255)
+            .map(|(i, _)| (i as u32 % SCREEN_WIDTH, i as u32 / SCREEN_WIDTH))
+            .collect();
+        assert_eq!(white, pixels.lit_pixels().collect::<Vec<_>>());
+        assert_eq!(levels[1], 95);
+        assert_eq!(levels.iter().filter(|level| **level > 0).count(), 5);
+    }
+
+    #[test]
+    fn draw_diffs() {
+        // The 0 glyph drawn at (0, 0), then the 1 glyph over it
+        let mut screen = crate::display::HeadlessScreen::new();
+        let mut cpu = crate::cpu::Cpu::new();
+        cpu.load_rom(&[0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x61, 0x01, 0xF1, 0x29, 0xD0, 0x05]).unwrap();
+        let blank = screen.framebuffer().clone();
+        cpu.run_steps(3, Some(&mut screen)).unwrap();
+        let zero = screen.framebuffer().clone();
+        cpu.run_steps(3, Some(&mut screen)).unwrap();
+
+        assert_eq!(draw_diff(&blank, &zero), concat!(
+            "on (0, 0) (1, 0) (2, 0) (3, 0) (0, 1) (3, 1) (0, 2) (3, 2) ",
+            "(0, 3) (3, 3) (0, 4) (1, 4) (2, 4) (3, 4)",
+        ));
+        assert_eq!(draw_diff(&zero, screen.framebuffer()),
+            "on (1, 1) (2, 1) (2, 2) (2, 3) off (2, 0) (1, 4) (2, 4) (3, 4)");
+        assert_eq!(draw_diff(&zero, &zero), "");
+    }
 }
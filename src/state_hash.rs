@@ -0,0 +1,80 @@
+// Hash of the state of a run, to check that two builds or two machines run a
+// ROM the same way, e.g. in bug reports
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::cpu::{Cpu, CpuError, Quirks};
+use crate::display::HeadlessScreen;
+use crate::framebuffer::Framebuffer;
+use crate::sha256::sha256_hex;
+
+// Runs up to steps instructions of the loaded ROM on screen and returns the
+// hash of the state afterwards. The CPU doesn't wait for the display, the
+// timers don't run without time passing, so with a seed set the hash only
+// depends on the ROM, the quirks and steps
+pub fn run_hash(cpu: &mut Cpu, steps: u64, screen: &mut HeadlessScreen) -> Result<String, CpuError> {
+    cpu.set_quirks(Quirks { display_wait: false, ..cpu.quirks() });
+    cpu.run_steps(steps, Some(screen))?;
+    Ok(state_hash(cpu, screen.framebuffer()))
+}
+
+// SHA-256 of the registers, the timers, the memory, and the resolution and
+// the pixels of the display
+pub fn state_hash(cpu: &Cpu, pixels: &Framebuffer) -> String {
+    let registers = cpu.registers();
+    let mut state = Vec::new();
+    for value in [registers.pc, registers.sp, registers.index] {
+        state.extend_from_slice(&value.to_be_bytes());
+    }
+    state.extend_from_slice(&registers.v_reg);
+    state.extend_from_slice(&[registers.delay_timer, registers.sound_timer]);
+    state.extend_from_slice(&cpu.read_range(0, cpu.ram_size()));
+    for value in [pixels.width(), pixels.height()] {
+        state.extend_from_slice(&value.to_be_bytes());
+    }
+    for word in pixels.words() {
+        state.extend_from_slice(&word.to_be_bytes());
+    }
+    sha256_hex(&state)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_hash, state_hash};
+    use crate::cpu::Cpu;
+    use crate::display::HeadlessScreen;
+    use crate::framebuffer::Framebuffer;
+
+    // Draws the glyph of a random digit at a random position and loops
+    const ROM: [u8; 12] = [0xC0, 0x0F, 0xF0, 0x29, 0xC1, 0x3F, 0xC2, 0x1F, 0xD1, 0x25, 0x12, 0x00];
+
+    fn hash(seed: u64, steps: u64) -> String {
+        let mut cpu = Cpu::with_seed(seed);
+        cpu.load_rom(&ROM).unwrap();
+        run_hash(&mut cpu, steps, &mut HeadlessScreen::new()).unwrap()
+    }
+
+    #[test]
+    fn stable_hashes() {
+        let first = hash(42, 1000);
+        assert_eq!(first.len(), 64);
+        assert_eq!(hash(42, 1000), first);
+
+        assert_ne!(hash(43, 1000), first);
+        assert_ne!(hash(42, 999), first);
+
+        // In the high resolution pixels past the first 64 of a row and the resolution make a
+        // difference
+        let cpu = Cpu::new();
+        let mut pixels = Framebuffer::new(128, 64);
+        let blank = state_hash(&cpu, &pixels);
+        assert_ne!(blank, state_hash(&cpu, &Framebuffer::new(64, 32)));
+
+        pixels.xor(100, 0, 1);
+        let at_100 = state_hash(&cpu, &pixels);
+        pixels.xor(100, 0, 1);
+        pixels.xor(108, 0, 1);
+        assert_ne!(at_100, blank);
+        assert_ne!(at_100, state_hash(&cpu, &pixels));
+    }
+}
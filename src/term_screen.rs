@@ -0,0 +1,311 @@
+// Display drawing in the terminal with half block characters, each line of
+// the terminal showing two rows of pixels, to run ROMs without a graphics
+// stack (e.g. over SSH). Terminals only report key presses and not releases,
+// so a key stays pressed for KEY_HOLD after it was typed and the auto repeat
+// of the terminal keeps it pressed while it is held down
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::display::{Display, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::framebuffer::Framebuffer;
+
+pub const KEY_HOLD: Duration = Duration::from_millis(150);
+
+// Keys of the terminal for the CHIP-8 keypad, the same QWERTY layout as the
+// window
+//   1 2 3 4      1 2 3 C
+//   q w e r  =>  4 5 6 D
+//   a s d f      7 8 9 E
+//   z x c v      A 0 B F
+const KEYS: [(u8, u8); 16] = [
+    (b'1', 0x1), (b'2', 0x2), (b'3', 0x3), (b'4', 0xC),
+    (b'q', 0x4), (b'w', 0x5), (b'e', 0x6), (b'r', 0xD),
+    (b'a', 0x7), (b's', 0x8), (b'd', 0x9), (b'f', 0xE),
+    (b'z', 0xA), (b'x', 0x0), (b'c', 0xB), (b'v', 0xF),
+];
+
+const ESC: u8 = 0x1B;
+const CTRL_C: u8 = 0x03;
+
+// Renders the pixels as lines of half blocks, the upper half of each
+// character showing an even row and the lower half the odd row below it
+pub fn render(pixels: &Framebuffer) -> Vec<String> {
+    (0..pixels.height()).step_by(2).map(|y| {
+        (0..pixels.width()).map(|x| {
+            let lower = y + 1 < pixels.height() && pixels.get(x, y + 1) == 1;
+            match (pixels.get(x, y) == 1, lower) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            }
+        }).collect()
+    }).collect()
+}
+
+pub struct TermScreen<W: Write> {
+    out: W,
+    pixels: Framebuffer,
+    // When each key was last typed
+    pressed: [Option<Instant>; 16],
+    // Size of the terminal when last drawn, in columns and lines
+    size: (u16, u16),
+    // Whether the pixels changed since they were last drawn
+    dirty: bool,
+}
+
+impl<W: Write> TermScreen<W> {
+    // Creates a display writing to out, usually the standard output
+    pub fn new(out: W) -> TermScreen<W> {
+        TermScreen {
+            out,
+            pixels: Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            pressed: [None; 16],
+            size: (0, 0),
+            dirty: true,
+        }
+    }
+
+    // Handles the bytes typed at now, returns false when Esc or Ctrl-C asks
+    // to quit. Escape sequences, like the ones of the arrow keys, are skipped
+    pub fn handle_input(&mut self, input: &[u8], now: Instant) -> bool {
+        let mut bytes = input.iter().peekable();
+        while let Some(byte) = bytes.next() {
+            match *byte {
+                CTRL_C => return false,
+                ESC => match bytes.peek() {
+                    Some(b'[') | Some(b'O') => {
+                        // The sequence ends with a letter or ~
+                        bytes.next();
+                        for byte in bytes.by_ref() {
+                            if byte.is_ascii_alphabetic() || *byte == b'~' {
+                                break;
+                            }
+                        }
+                    }
+                    _ => return false,
+                },
+                byte => {
+                    let byte = byte.to_ascii_lowercase();
+                    if let Some((_, key)) = KEYS.iter().find(|(k, _)| *k == byte) {
+                        self.pressed[*key as usize] = Some(now);
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // Whether the key was typed less than KEY_HOLD before now
+    fn is_key_pressed_at(&self, key_value: u8, now: Instant) -> bool {
+        match self.pressed[key_value as usize] {
+            Some(time) => now.saturating_duration_since(time) < KEY_HOLD,
+            None => false,
+        }
+    }
+
+    // Returns the pixels of the display
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.pixels
+    }
+
+    // Rings the bell of the terminal
+    pub fn beep(&mut self) -> io::Result<()> {
+        self.out.write_all(b"\x07")?;
+        self.out.flush()
+    }
+
+    // Draws the display centered in a terminal of size columns x lines. It
+    // is only drawn again when the pixels changed or the terminal was
+    // resized, a terminal too small shows a message instead
+    pub fn draw(&mut self, size: (u16, u16)) -> io::Result<()> {
+        if size != self.size {
+            self.size = size;
+            self.dirty = true;
+            write!(self.out, "\x1b[2J")?;
+        }
+        if !self.dirty {
+            return Ok(());
+        }
+        self.dirty = false;
+
+        // Size of the display in characters
+        let (columns, lines) = size;
+        let (width, height) = (self.pixels.width() as u16, self.pixels.height().div_ceil(2) as u16);
+        if columns < width || lines < height {
+            write!(self.out, "\x1b[H\x1b[2JResize the terminal to at least {}x{}", width, height)?;
+            return self.out.flush();
+        }
+
+        // Cursor positions start from 1
+        let top = (lines - height) / 2 + 1;
+        let left = (columns - width) / 2 + 1;
+        for (i, line) in render(&self.pixels).iter().enumerate() {
+            write!(self.out, "\x1b[{};{}H{}", top + i as u16, left, line)?;
+        }
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Display for TermScreen<W> {
+    fn clear(&mut self) {
+        self.pixels.clear();
+        self.dirty = true;
+    }
+
+    fn draw_pixel(&mut self, x: u8, y: u8, bit: u8) -> u8 {
+        if bit == 1 {
+            self.dirty = true;
+        }
+        self.pixels.xor(x as u32, y as u32, bit)
+    }
+
+    fn is_key_pressed(&self, key_value: u8) -> bool {
+        self.is_key_pressed_at(key_value, Instant::now())
+    }
+
+    fn get_key_pressed(&self) -> Option<u8> {
+        let now = Instant::now();
+        (0..16).find(|key| self.is_key_pressed_at(*key, now))
+    }
+
+    fn get_last_key_pressed(&self) -> Option<u8> {
+        let now = Instant::now();
+        (0..16).filter(|key| self.is_key_pressed_at(*key, now))
+            .max_by_key(|key| self.pressed[*key as usize])
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.pixels = Framebuffer::new(width, height);
+        // The old display may be larger than the new one
+        self.size = (0, 0);
+    }
+}
+
+// The terminal of the standard input and output in raw mode, so that keys
+// are read as they are typed without being echoed, and showing the alternate
+// screen without the cursor. The terminal is restored when dropped, also
+// when unwinding from a panic
+pub struct Terminal {
+    original: libc::termios,
+}
+
+impl Terminal {
+    // Puts the terminal in raw mode
+    pub fn enter() -> io::Result<Terminal> {
+        // Safety: termios is plain data filled by tcgetattr
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Reads return at once, with whatever was typed
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut out = io::stdout();
+        write!(out, "\x1b[?1049h\x1b[?25l")?;
+        out.flush()?;
+
+        Ok(Terminal { original })
+    }
+
+    // Returns the size of the terminal in columns and lines
+    pub fn size(&self) -> io::Result<(u16, u16)> {
+        // Safety: winsize is plain data filled by the ioctl
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((size.ws_col, size.ws_row))
+    }
+
+    // Returns the bytes typed since the last call, without waiting
+    pub fn read_input(&self) -> io::Result<Vec<u8>> {
+        let mut input = vec![];
+        let mut buf = [0u8; 64];
+        loop {
+            let read = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+            if read < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if read == 0 {
+                return Ok(input);
+            }
+            input.extend_from_slice(&buf[..read as usize]);
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        let _ = write!(out, "\x1b[?25h\x1b[?1049l");
+        let _ = out.flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, TermScreen, KEY_HOLD};
+    use crate::display::Display;
+    use crate::framebuffer::Framebuffer;
+    use std::time::Instant;
+
+    #[test]
+    fn half_blocks() {
+        let mut pixels = Framebuffer::new(4, 4);
+        for (x, y) in [(0, 0), (0, 1), (1, 0), (2, 1), (3, 3)] {
+            pixels.xor(x, y, 1);
+        }
+        assert_eq!(render(&pixels), vec!["█▀▄ ", "   ▄"]);
+    }
+
+    #[test]
+    fn draw_frame() {
+        let mut screen = TermScreen::new(vec![]);
+        screen.draw_pixel(0, 0, 1);
+        screen.draw_pixel(63, 31, 1);
+        screen.draw((64, 16)).unwrap();
+
+        let out = String::from_utf8(std::mem::take(&mut screen.out)).unwrap();
+        assert!(out.starts_with("\x1b[2J\x1b[1;1H▀ "));
+        assert!(out.ends_with(&("\x1b[16;1H".to_string() + &" ".repeat(63) + "▄")));
+
+        // Unchanged frames are not drawn again
+        screen.draw((64, 16)).unwrap();
+        assert!(screen.out.is_empty());
+
+        // Centered in a larger terminal, a smaller one shows a message
+        screen.draw((80, 20)).unwrap();
+        let out = String::from_utf8(std::mem::take(&mut screen.out)).unwrap();
+        assert!(out.contains("\x1b[3;9H▀ "));
+        screen.draw((40, 10)).unwrap();
+        let out = String::from_utf8(std::mem::take(&mut screen.out)).unwrap();
+        assert!(out.contains("Resize the terminal to at least 64x16"));
+    }
+
+    #[test]
+    fn typed_keys() {
+        let mut screen = TermScreen::new(vec![]);
+        let now = Instant::now();
+        assert!(screen.handle_input(b"x\x1b[AV", now));
+        assert!(screen.is_key_pressed_at(0x0, now));
+        assert!(screen.is_key_pressed_at(0xF, now));
+        assert!(!screen.is_key_pressed_at(0x5, now));
+        assert!(!screen.is_key_pressed_at(0x0, now + KEY_HOLD));
+
+        assert!(!screen.handle_input(b"\x1b", now));
+        assert!(!screen.handle_input(b"\x03", now));
+    }
+}
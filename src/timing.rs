@@ -0,0 +1,257 @@
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+// Source of the time the timers of the CPU run on, replaceable to test them
+// without sleeping
+pub trait Clock {
+    // Time elapsed since a fixed point, like the creation of the clock
+    fn now(&self) -> Duration;
+}
+
+// Clock reading the monotonic time of the system
+#[cfg(feature = "std")]
+pub struct SystemClock {
+    start: Instant,
+}
+
+#[cfg(feature = "std")]
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl SystemClock {
+    // Creates a clock counting from now
+    pub fn new() -> SystemClock {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+// Converts elapsed time into a number of instructions to execute, carrying
+// the fractional instructions over to the next call so that the long-run
+// instruction rate matches the requested one exactly
+pub struct StepAccumulator {
+    ips: u32,
+    // The rate is divided by it, for slow motion
+    slowdown: u32,
+    // Leftover instructions, in units of 1 / (NANOS_PER_SEC * slowdown)
+    // instructions
+    carry: u128,
+    // Whole instructions given back by defer, returned by the next call
+    deferred: u32,
+}
+
+impl StepAccumulator {
+    // Creates a new accumulator executing ips instructions per second
+    pub fn new(ips: u32) -> StepAccumulator {
+        StepAccumulator {
+            ips,
+            slowdown: 1,
+            carry: 0,
+            deferred: 0,
+        }
+    }
+
+    // Changes the number of instructions executed per second
+    pub fn set_ips(&mut self, ips: u32) {
+        self.ips = ips;
+    }
+
+    // Divides the instructions per second by factor (at least 1), keeping
+    // the fraction of instruction carried over
+    pub fn set_slowdown(&mut self, factor: u32) {
+        let factor = factor.max(1);
+        self.carry = self.carry * factor as u128 / self.slowdown as u128;
+        self.slowdown = factor;
+    }
+
+    // Returns the number of instructions to execute for the elapsed time
+    pub fn steps(&mut self, elapsed: Duration) -> u32 {
+        let unit = NANOS_PER_SEC * self.slowdown as u128;
+        let total = self.carry + elapsed.as_nanos() * self.ips as u128;
+        self.carry = total % unit;
+
+        let deferred = core::mem::take(&mut self.deferred);
+        (total / unit) as u32 + deferred
+    }
+
+    // Gives back steps returned but not executed, e.g. at the end of the
+    // time of a frame, so that the next call returns them again. At most a
+    // second of instructions is kept, a slower host can't catch up anyway
+    pub fn defer(&mut self, steps: u32) {
+        self.deferred = (self.deferred + steps).min(self.ips / self.slowdown);
+    }
+}
+
+// Time before the end of a frame spent busy waiting instead of sleeping, as
+// sleeps can overshoot by about this much
+#[cfg(feature = "std")]
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+// Paces a loop to a fixed frame duration. The frames end on a fixed
+// schedule rather than a frame after each wait, so time lost to a late wake
+// up is made up by the next frame
+#[cfg(feature = "std")]
+pub struct FrameLimiter {
+    frame: Duration,
+    // End of the current frame
+    deadline: Instant,
+}
+
+#[cfg(feature = "std")]
+impl FrameLimiter {
+    // Creates a new limiter whose first frame starts now
+    pub fn new(frame: Duration) -> FrameLimiter {
+        FrameLimiter {
+            frame,
+            deadline: Instant::now() + frame,
+        }
+    }
+
+    // Returns how long to wait at now for the end of the frame and moves on
+    // to the next frame
+    fn schedule(&mut self, now: Instant) -> Duration {
+        let wait = self.deadline.saturating_duration_since(now);
+
+        // After a frame later than a whole frame start the schedule over,
+        // rushing through the missed frames would only make things worse
+        if now > self.deadline + self.frame {
+            self.deadline = now + self.frame;
+        } else {
+            self.deadline += self.frame;
+        }
+
+        wait
+    }
+
+    // Waits for the end of the current frame, sleeping most of the time and
+    // busy waiting the last moments
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let end = now + self.schedule(now);
+
+        if let Some(sleep) = end.checked_duration_since(now + SPIN_TIME) {
+            std::thread::sleep(sleep);
+        }
+        while Instant::now() < end {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StepAccumulator;
+    #[cfg(feature = "std")]
+    use super::FrameLimiter;
+    use std::time::Duration;
+    #[cfg(feature = "std")]
+    use std::time::Instant;
+
+    // Runs the accumulator for one second split in fps frames and returns
+    // the steps executed for each frame
+    fn run_second(ips: u32, fps: u32) -> Vec<u32> {
+        let mut acc = StepAccumulator::new(ips);
+        (0..fps as u64)
+            .map(|i| {
+                let start = i * 1_000_000_000 / fps as u64;
+                let end = (i + 1) * 1_000_000_000 / fps as u64;
+                acc.steps(Duration::from_nanos(end - start))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exact_division() {
+        let steps = run_second(400, 40);
+        assert!(steps.iter().all(|s| *s == 10));
+    }
+
+    #[test]
+    fn carries_remainder() {
+        // 12.5 instructions per frame
+        let steps = run_second(500, 40);
+        assert_eq!(steps.iter().sum::<u32>(), 500);
+        assert!(steps.iter().all(|s| *s == 12 || *s == 13));
+
+        // 11.66 instructions per frame
+        let steps = run_second(700, 60);
+        assert_eq!(steps.iter().sum::<u32>(), 700);
+        assert!(steps.iter().all(|s| *s == 11 || *s == 12));
+    }
+
+    #[test]
+    fn less_than_one_per_frame() {
+        let steps = run_second(30, 60);
+        assert_eq!(steps.iter().sum::<u32>(), 30);
+        assert!(steps.iter().all(|s| *s <= 1));
+    }
+
+    #[test]
+    fn slow_motion() {
+        // 10 instructions per frame slowed down 40 times, one every 4 frames
+        let mut acc = StepAccumulator::new(400);
+        acc.set_slowdown(40);
+        let frame = Duration::from_millis(25);
+        let steps: Vec<u32> = (0..12).map(|_| acc.steps(frame)).collect();
+        assert_eq!(steps, [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1]);
+
+        // Back to full speed with half an instruction carried over
+        acc.steps(frame * 2);
+        acc.set_slowdown(1);
+        assert_eq!(acc.steps(frame / 20), 1);
+        assert_eq!(acc.steps(frame), 10);
+    }
+
+    #[test]
+    fn deferred_steps() {
+        // A frame overran after 4 of its 10 instructions
+        let mut acc = StepAccumulator::new(400);
+        let frame = Duration::from_millis(25);
+        assert_eq!(acc.steps(frame), 10);
+        acc.defer(6);
+        assert_eq!(acc.steps(frame), 16);
+        assert_eq!(acc.steps(frame), 10);
+
+        // Frames overrunning for longer keep at most a second of them
+        for _ in 0..100 {
+            let steps = acc.steps(frame);
+            acc.defer(steps);
+        }
+        assert_eq!(acc.steps(frame), 410);
+        assert_eq!(acc.steps(frame), 10);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn limiter_compensates_overshoot() {
+        let start = Instant::now();
+        let frame = Duration::from_millis(25);
+        let mut limiter = FrameLimiter { frame, deadline: start + frame };
+
+        // 10ms of work leave 15ms to wait
+        assert_eq!(limiter.schedule(start + Duration::from_millis(10)), Duration::from_millis(15));
+
+        // The wait overshot by 2ms, the next frame is shortened by as much
+        assert_eq!(limiter.schedule(start + Duration::from_millis(27)), Duration::from_millis(23));
+
+        // A late frame doesn't wait
+        assert_eq!(limiter.schedule(start + Duration::from_millis(80)), Duration::ZERO);
+
+        // Far behind the schedule starts over
+        assert_eq!(limiter.schedule(start + Duration::from_millis(200)), Duration::ZERO);
+        assert_eq!(limiter.schedule(start + Duration::from_millis(210)), Duration::from_millis(15));
+    }
+}
@@ -0,0 +1,84 @@
+// Runs the test ROMs in roms/ headless and compares the SHA-1 of the final
+// display with the expected one for each quirks preset. To add a ROM, add a
+// line to CASES with an empty hash and run
+//
+//   CONFORMANCE_DUMP=1 cargo test --test conformance -- --nocapture
+//
+// which prints the display and the hash of every case, check that the
+// display shows the expected result before copying the hash
+use std::time::Duration;
+
+use chip_8::cpu::{Cpu, Quirks};
+use chip_8::display::{HeadlessScreen, SCREEN_HEIGHT, SCREEN_WIDTH};
+use chip_8::sha1::sha1_hex;
+
+// Frames a pressed key is held for
+const PRESS_FRAMES: u32 = 5;
+
+struct Case {
+    rom: &'static str,
+    preset: &'static str,
+    // Frames of 1/60 s to run at 1000 instructions per second
+    frames: u32,
+    // Frames at which a key is pressed, e.g. to pick an option in a menu
+    keys: &'static [(u32, u8)],
+    sha1: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case { rom: "roms/demo/ibm.ch8", preset: "chip8", frames: 60, keys: &[], sha1: "d4598c296d5884a621d3fb2bc9461a308710fcfa" },
+    Case { rom: "roms/test/opcodes.ch8", preset: "chip8", frames: 120, keys: &[], sha1: "ef6097414d6a8e471a821048e5c03d891cd57dbd" },
+    Case { rom: "roms/test/flags.ch8", preset: "chip8", frames: 120, keys: &[], sha1: "4c8cf412bf7eea181f8e2fc2e7e100f30a9f1484" },
+    Case { rom: "roms/test/quirks.ch8", preset: "chip8", frames: 600, keys: &[(60, 0x1)], sha1: "2d41abda77de6fb10a73cde1ffd4ca032a93097a" },
+    Case { rom: "roms/test/quirks.ch8", preset: "schip", frames: 600, keys: &[(60, 0x2), (120, 0x1)], sha1: "255de78c5db8db60cff14f857b10aeb11ba5fe61" },
+    Case { rom: "roms/test/quirks.ch8", preset: "xochip", frames: 600, keys: &[(60, 0x3)], sha1: "984cecb88db63d0f7f26f92c22ff8abe512e4f3c" },
+];
+
+// Runs the case and returns the final display, one byte per pixel
+fn run(case: &Case) -> Vec<u8> {
+    let mut cpu = Cpu::with_seed(0);
+    cpu.set_quirks(Quirks::preset(case.preset).unwrap());
+    cpu.set_ips(1000);
+    cpu.load_rom_file(case.rom).unwrap();
+
+    let mut screen = HeadlessScreen::new();
+    for frame in 0..case.frames {
+        for (start, key) in case.keys {
+            screen.set_key(*key, (*start..start + PRESS_FRAMES).contains(&frame));
+        }
+        cpu.run_for(Duration::from_secs(1) / 60, Some(&mut screen)).unwrap();
+    }
+
+    let mut pixels = vec![];
+    for y in 0..SCREEN_HEIGHT as u8 {
+        for x in 0..SCREEN_WIDTH as u8 {
+            pixels.push(screen.pixel(x, y));
+        }
+    }
+    pixels
+}
+
+#[test]
+fn conformance() {
+    let dump = std::env::var_os("CONFORMANCE_DUMP").is_some();
+
+    let mut failed = vec![];
+    for case in CASES {
+        let pixels = run(case);
+        let sha1 = sha1_hex(&pixels);
+
+        if dump {
+            println!("{} ({}): {}", case.rom, case.preset, sha1);
+            for row in pixels.chunks(SCREEN_WIDTH as usize) {
+                let row: String = row.iter().map(|p| if *p == 1 { '#' } else { '.' }).collect();
+                println!("{}", row);
+            }
+        }
+
+        if sha1 != case.sha1 {
+            failed.push(format!("{} ({})", case.rom, case.preset));
+        }
+    }
+
+    assert!(failed.is_empty(), "Unexpected display for {}", failed.join(", "));
+}